@@ -1,9 +1,41 @@
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
-use kvs::{engines::{kvs::KvStore, kvs_engine::KvsEngine, sled::SledKvsEngine}, thread_pool::shared_queue};
+use kvs::{engines::{kvs::{CompactionOptions, CompactionStrategy, Durability, KvStore}, kvs_engine::KvsEngine, sled::SledKvsEngine}, thread_pool::shared_queue};
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
 use kvs::thread_pool::{rayon::RayonThreadPool, shared_queue::SharedQueueThreadPool, ThreadPool};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// CountingAlloc counts the bytes allocated, so benches can report the allocations of an operation
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// bytes allocated while running f
+fn allocated(mut f: impl FnMut()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    f();
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
 
 // generate 100 bytes of random length
 fn generate_data(seed: u64, size: u64) -> Vec<String> {
@@ -31,8 +63,8 @@ fn write(c: &mut Criterion) {
     let keys: Vec<String> = generate_data(100, 200);
     let values: Vec<String> = generate_data(100, 200);
     // open KvsEngine for KvStore
-    let mut kvs = KvStore::open("./").unwrap();
-    let mut sled = SledKvsEngine::open("./db").unwrap();
+    let kvs = KvStore::open("./").unwrap();
+    let sled = SledKvsEngine::open("./db").unwrap();
     // create a benchmark group, to bench over an iterator of inputs
     let mut group = c.benchmark_group("kvs_write");
     // find throughput for each bench
@@ -47,7 +79,7 @@ fn write(c: &mut Criterion) {
         BenchmarkId::from_parameter("kvs_write"),
         &vec![&keys, &values],
         |b, data| {
-            let mut i = 0;
+            let i = 0;
             b.iter_batched(
                 || {
                     let i = rng.gen_range(0..keys.len());
@@ -67,7 +99,7 @@ fn write(c: &mut Criterion) {
         BenchmarkId::from_parameter("sled_write"),
         &vec![&keys, &values],
         |b, data| {
-            let mut i = 0;
+            let i = 0;
             b.iter_batched(
                 || {
                     let i = rng.gen_range(0..keys.len());
@@ -90,8 +122,8 @@ fn read(c: &mut Criterion) {
     let keys: Vec<String> = generate_data(100, 100);
     let values: Vec<String> = generate_data(100, 100);
     // open Engines, and make writes in preparation of benches
-    let mut kvs = KvStore::open("./").unwrap();
-    let mut sled = SledKvsEngine::open("./db").unwrap();
+    let kvs = KvStore::open("./").unwrap();
+    let sled = SledKvsEngine::open("./db").unwrap();
     // set values at keys for both engines
     let mut dataSize: u64 = 0;
     for i in 0..100 {
@@ -161,10 +193,10 @@ fn shared_thread_kvs_read(c: &mut Criterion) {
     let keys: Vec<String> = generate_data(100, 100);
     let values: Vec<String> = generate_data(100, 100);
     // open Engines, and make writes in preparation of benches
-    let mut kvs = KvStore::open("./").unwrap();
+    let kvs = KvStore::open("./").unwrap();
     // create thread pools, set to 10 threads so that work-stealing is implemented
-    let mut shared_queue = SharedQueueThreadPool::new(10);
-    let mut rayon_queue = RayonThreadPool::new(10);
+    let shared_queue = SharedQueueThreadPool::new(10);
+    let rayon_queue = RayonThreadPool::new(10);
     // set values at keys for both engines
     let mut dataSize: u64 = 0;
     for i in 0..100 {
@@ -179,5 +211,240 @@ fn shared_thread_kvs_read(c: &mut Criterion) {
     });
 }   
 
-criterion_group!(benches, write, read, shared_thread_kvs_read);
+// large_value_read, compare get, which clones the value out of the store, with get_into, which
+// copies the value from the log into the writer, on a single 1 MiB value
+fn large_value_read(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = KvStore::open(temp_dir.path()).unwrap();
+    let value = "v".repeat(1024 * 1024);
+    kvs.set("key".to_owned(), value.clone()).unwrap();
+    // the first read loads the index from the log
+    kvs.get("key".to_owned()).unwrap();
+    // report the bytes allocated by a single read through each path
+    println!(
+        "kvs get allocates {} bytes, kvs get_into allocates {} bytes",
+        allocated(|| {
+            kvs.get("key".to_owned()).unwrap();
+        }),
+        allocated(|| {
+            kvs.get_into("key".to_owned(), &mut io::sink()).unwrap();
+        })
+    );
+    let mut group = c.benchmark_group("large_value_read");
+    group.throughput(Throughput::Bytes(value.len() as u64));
+    group.bench_function("kvs_get", |b| {
+        b.iter(|| kvs.get("key".to_owned()).unwrap())
+    });
+    group.bench_function("kvs_get_into", |b| {
+        b.iter(|| kvs.get_into("key".to_owned(), &mut io::sink()).unwrap())
+    });
+    group.finish();
+}
+
+// overwrite 100 keys with 100 byte values count times, on a store compacted with strategy, returns
+// the store's write amplification, i.e the bytes written to disk per byte of key and value set
+fn overwrite(strategy: &CompactionStrategy, count: usize) -> f64 {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs = KvStore::open(temp_dir.path()).unwrap();
+    kvs.set_compaction_options(CompactionOptions { strategy: strategy.clone() }).unwrap();
+    let mut data_size = 0;
+    for i in 0..count {
+        let key = format!("key{:03}", i % 100);
+        let value = format!("{:0100}", i);
+        data_size += key.len() + value.len();
+        kvs.set(key, value).unwrap();
+    }
+    kvs.bytes_written() as f64 / data_size as f64
+}
+
+// compare the write amplification, and the time taken, of each compaction strategy on a workload
+// of repeated overwrites
+fn write_amplification(c: &mut Criterion) {
+    let strategies = [
+        ("full_rewrite", CompactionStrategy::FullRewrite),
+        ("size_tiered", CompactionStrategy::SizeTiered { fanout: 4 }),
+    ];
+    for (name, strategy) in strategies.iter() {
+        println!("{} write amplification: {:.2}", name, overwrite(strategy, 20000));
+    }
+    let mut group = c.benchmark_group("write_amplification");
+    group.sample_size(10);
+    for (name, strategy) in strategies.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), strategy, |b, strategy| {
+            b.iter(|| overwrite(strategy, 2000))
+        });
+    }
+    group.finish();
+}
+
+// compare the throughput of sets fsynced on every write, to sets fsynced in batches by a group
+// commit
+fn durability(c: &mut Criterion) {
+    let modes = [
+        ("every_write", Durability::EveryWrite),
+        (
+            "group_commit",
+            Durability::GroupCommit { interval: Duration::from_millis(10), max_bytes: 1 << 16 },
+        ),
+    ];
+    // a burst of small writes, where the cost of each fsync dominates
+    let keys: Vec<String> = (0..200).map(|i| format!("key{:03}", i)).collect();
+    let value = format!("{:0100}", 0);
+    let mut group = c.benchmark_group("durability");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    for (name, mode) in modes.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), mode, |b, mode| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let kvs = KvStore::open(temp_dir.path()).unwrap();
+                    kvs.set_durability(mode.clone()).unwrap();
+                    (temp_dir, kvs)
+                },
+                |(_temp_dir, kvs)| {
+                    for key in keys.iter() {
+                        kvs.set(key.clone(), value.clone()).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+// read every key of store into a sink, the way kvs-server serves a get
+fn read_all(store: &KvStore, keys: &[String]) {
+    for key in keys {
+        assert!(store.get_into(key.clone(), &mut io::sink()).unwrap());
+    }
+}
+
+// small values, where the cost of a read is dominated by per-record overhead rather than by the
+// value, compare reads of values held inline with reads of the same values from the log
+fn small_values(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{:04}", i)).collect();
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let values: Vec<String> = (0..keys.len())
+        .map(|_| {
+            let len = rng.gen_range(1..256);
+            rng.clone().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+        })
+        .collect();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        store.set(key.clone(), value.clone()).unwrap();
+    }
+    let thresholds = [("read_inline", 256), ("read_from_log", 0)];
+    // report the mean latency of a read under each threshold
+    let latency: Vec<_> = thresholds
+        .iter()
+        .map(|(_, threshold)| {
+            store.set_inline_threshold(*threshold);
+            read_all(&store, &keys);
+            let start = Instant::now();
+            for _ in 0..10 {
+                read_all(&store, &keys);
+            }
+            start.elapsed() / (10 * keys.len() as u32)
+        })
+        .collect();
+    println!(
+        "small value read latency: inline {:?}, from log {:?}, {:.1}x faster inline",
+        latency[0],
+        latency[1],
+        latency[1].as_secs_f64() / latency[0].as_secs_f64()
+    );
+
+    let mut group = c.benchmark_group("small_values");
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("write", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    store.set(key.clone(), value.clone()).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    for (name, threshold) in thresholds.iter() {
+        store.set_inline_threshold(*threshold);
+        group.bench_function(*name, |b| b.iter(|| read_all(&store, &keys)));
+    }
+    group.finish();
+}
+
+// the page of limit keys from start on, found the way KvStore did while its index was a HashMap,
+// by selecting the page from every key, and sorting only the page
+fn select_page<'a>(index: &'a HashMap<String, String>, start: &str, limit: usize) -> Vec<&'a String> {
+    let mut keys: Vec<&String> = index.keys().filter(|key| key.as_str() >= start).collect();
+    if keys.len() > limit + 1 {
+        keys.select_nth_unstable(limit);
+        keys.truncate(limit + 1);
+    }
+    keys.sort_unstable();
+    keys.truncate(limit);
+    keys
+}
+
+// range_scan, compare the latency of a page of a scan over a store of 100000 keys, read from the
+// ordered index, with the page selected from an unordered index as KvStore did before, gets are
+// benched alongside to show the cost of a point lookup in the ordered index
+fn range_scan(c: &mut Criterion) {
+    const LIMIT: usize = 100;
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let index: HashMap<String, String> = (0..100000)
+        .map(|i| (format!("key{:06}", i), format!("value{}", i)))
+        .collect();
+    for (key, value) in index.iter() {
+        store.set(key.clone(), value.clone()).unwrap();
+    }
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let mut group = c.benchmark_group("range_scan");
+    group.throughput(Throughput::Elements(LIMIT as u64));
+    group.bench_function("ordered_index", |b| {
+        b.iter_batched(
+            || Some(format!("key{:06}", rng.gen_range(0..index.len()))),
+            |start| store.scan_page(start, LIMIT).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("unordered_index", |b| {
+        b.iter_batched(
+            || format!("key{:06}", rng.gen_range(0..index.len())),
+            |start| select_page(&index, &start, LIMIT).len(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("get", |b| {
+        b.iter_batched(
+            || format!("key{:06}", rng.gen_range(0..index.len())),
+            |key| store.get(key).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    write,
+    read,
+    shared_thread_kvs_read,
+    large_value_read,
+    write_amplification,
+    durability,
+    small_values,
+    range_scan
+);
 criterion_main!(benches);
@@ -0,0 +1,204 @@
+use crate::engines::kvs::CommandData;
+use crate::engines::kvs_engine::Result;
+use crate::kvs_client::KvsClient;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the number of distinct keys a bench reads and writes, writes fill the keys reads look up
+const KEYS: usize = 1000;
+
+/// Ratio is the share of a bench's requests that are reads and writes, written `reads:writes`,
+/// 80:20 makes 4 of every 5 requests a get, and the 5th a set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ratio {
+    /// parts of the requests that are gets
+    pub reads: u32,
+    /// parts of the requests that are sets
+    pub writes: u32,
+}
+
+impl FromStr for Ratio {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Ratio, String> {
+        let invalid = || {
+            format!(
+                "invalid ratio '{}', expected <reads>:<writes>, e.g 80:20",
+                s
+            )
+        };
+        let (reads, writes) = s.split_once(':').ok_or_else(invalid)?;
+        let ratio = Ratio {
+            reads: reads.parse().map_err(|_| invalid())?,
+            writes: writes.parse().map_err(|_| invalid())?,
+        };
+        if ratio.reads == 0 && ratio.writes == 0 {
+            return Err(invalid());
+        }
+        Ok(ratio)
+    }
+}
+
+impl Ratio {
+    /// is_read returns whether the nth request is a get, the writes are spread evenly between the
+    /// reads, so with 80:20 every 5th request is a set
+    fn is_read(&self, n: usize) -> bool {
+        let (writes, total) = (self.writes as usize, (self.reads + self.writes) as usize);
+        // the nth request is a write if the writes due by it are more than the writes due before it
+        (n + 1) * writes / total == n * writes / total
+    }
+}
+
+/// BenchConfig is the load a bench puts on kvs-server
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// the number of connections, each sending its requests from a thread of its own
+    pub threads: usize,
+    /// the number of requests sent, across every connection
+    pub requests: usize,
+    /// the length of each value set, in bytes
+    pub value_size: usize,
+    /// the share of requests that are gets and sets
+    pub ratio: Ratio,
+}
+
+/// BenchReport is what a bench measured
+#[derive(Debug)]
+pub struct BenchReport {
+    /// gets sent
+    pub reads: usize,
+    /// sets sent
+    pub writes: usize,
+    /// requests that failed, counted in reads / writes
+    pub errors: usize,
+    /// the time from the first request being sent to the last response
+    pub elapsed: Duration,
+    // the latency of every request, sorted
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// throughput is the requests answered per second
+    pub fn throughput(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// percentile returns the latency that p percent of requests were answered within
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "requests: {} ({} gets, {} sets)",
+            self.reads + self.writes,
+            self.reads,
+            self.writes
+        )?;
+        writeln!(f, "errors: {}", self.errors)?;
+        writeln!(f, "elapsed: {:.3}s", self.elapsed.as_secs_f64())?;
+        writeln!(f, "throughput: {:.1} req/s", self.throughput())?;
+        for p in [50.0, 90.0, 99.0] {
+            let latency = self.percentile(p).as_secs_f64() * 1000.0;
+            writeln!(f, "latency p{}: {:.3}ms", p, latency)?;
+        }
+        let max = self.percentile(100.0).as_secs_f64() * 1000.0;
+        write!(f, "latency max: {:.3}ms", max)
+    }
+}
+
+/// run sends config.requests gets and sets to kvs-server, split across config.threads connections
+/// opened by connect, each connection is kept open for all of its requests. Every connection is
+/// opened before the first request is sent, so connecting is not measured
+/// # Errors
+/// config.threads is 0, or a connection could not be opened. A request that fails is counted in
+/// BenchReport::errors, and the bench carries on
+pub fn run<F>(config: &BenchConfig, connect: F) -> Result<BenchReport>
+where
+    F: Fn() -> Result<KvsClient>,
+{
+    if config.threads == 0 {
+        return Err("a bench needs at least 1 thread".into());
+    }
+    let clients = (0..config.threads)
+        .map(|_| connect())
+        .collect::<Result<Vec<KvsClient>>>()?;
+    let value = "x".repeat(config.value_size);
+
+    let start = Instant::now();
+    let results: Vec<(Vec<Duration>, usize)> = thread::scope(|scope| {
+        let handles: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(thread, client)| {
+                let value = &value;
+                // request n is sent by thread n % threads
+                let requests = (thread..config.requests).step_by(config.threads);
+                scope.spawn(move || send_all(client, requests, config.ratio, value))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("bench thread panicked"))
+            .collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = Vec::with_capacity(config.requests);
+    let mut errors = 0;
+    for (thread_latencies, thread_errors) in results {
+        latencies.extend(thread_latencies);
+        errors += thread_errors;
+    }
+    latencies.sort_unstable();
+    let reads = (0..config.requests)
+        .filter(|&n| config.ratio.is_read(n))
+        .count();
+    Ok(BenchReport {
+        reads,
+        writes: config.requests - reads,
+        errors,
+        elapsed,
+        latencies,
+    })
+}
+
+/// send_all sends the requests numbered by requests over client, one at a time, returns the
+/// latency of each, and the number that failed
+fn send_all<I>(
+    mut client: KvsClient,
+    requests: I,
+    ratio: Ratio,
+    value: &str,
+) -> (Vec<Duration>, usize)
+where
+    I: Iterator<Item = usize>,
+{
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for n in requests {
+        let key = format!("key{}", n % KEYS);
+        let cmd = match ratio.is_read(n) {
+            true => CommandData::Get { key },
+            false => CommandData::Set {
+                key,
+                value: value.to_owned(),
+            },
+        };
+        let start = Instant::now();
+        if client.send(&cmd).is_err() {
+            errors += 1;
+        }
+        latencies.push(start.elapsed());
+    }
+    (latencies, errors)
+}
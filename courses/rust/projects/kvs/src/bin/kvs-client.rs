@@ -1,13 +1,27 @@
 use clap::Parser;
-use kvs::cli::{Client, Commands};
+use kvs::bench;
+use kvs::cli::{Client, Commands, StatsAction};
 use kvs::engines::{
     kvs::CommandData,
-    kvs_engine::{ErrKeyNotFound, Result},
+    kvs_engine::{Result, ValueFormat},
 };
 use kvs::kvs_client::KvsClient;
 use std::error::Error;
+use std::io::{self, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
-fn main() -> Result<()> {
+use std::process;
+
+// every failure, whether from the server or the client, is printed to stderr, and exits non-zero
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// values are printed to stdout, a get of a missing key prints "Key not found" and succeeds, a
+// rm / rename of a missing key fails
+fn run() -> Result<()> {
     // parse arguments / command passed to the cli
     let cli = Client::parse();
     // fail if the addr is formatted incorrectly
@@ -19,7 +33,30 @@ fn main() -> Result<()> {
         // panic here, as it should never be the case this is is a nil value
         .unwrap();
 
-    let mut client = KvsClient::init::<SocketAddr>(addr)?;
+    // fail if any replica is formatted incorrectly
+    let replicas = cli
+        .replicas
+        .iter()
+        .map(|replica| {
+            replica.to_socket_addrs()?.next().ok_or_else(|| {
+                let msg = format!("replica {} resolved to no address", replica);
+                Box::from(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            })
+        })
+        .collect::<Result<Vec<SocketAddr>>>()?;
+
+    let tls = cli.tls_config()?;
+    let connect = || match &tls {
+        Some(config) => KvsClient::init_tls(addr, cli.server_name(), config.clone()),
+        None if !replicas.is_empty() => KvsClient::init_cluster(addr, &replicas),
+        None => KvsClient::init::<SocketAddr>(addr),
+    };
+    // a bench opens connections of its own
+    if let Commands::bench(args) = &cli.command {
+        println!("{}", bench::run(&args.config(), connect)?);
+        return Ok(());
+    }
+    let mut client = connect()?;
     let cmd: CommandData;
 
     match &cli.command {
@@ -27,16 +64,30 @@ fn main() -> Result<()> {
             // must have key
             cmd = CommandData::Set {
                 key: args.key.as_ref().unwrap().to_owned(),
-                value: args.value.as_ref().unwrap().to_owned(),
+                value: args.read_value()?,
             };
             // commands initialized, now send the request to server
         }
+        Commands::get(args) if args.pretty => {
+            // the whole value is needed to lay it out, so it is not streamed
+            let mut buf = Vec::new();
+            if client.get_to(args.key.as_ref().unwrap().to_owned(), &mut buf)? {
+                let val = String::from_utf8(buf)?;
+                println!("{}", ValueFormat::Json.pretty(val)?);
+            } else {
+                println!("Key not found");
+            }
+            return Ok(());
+        }
         Commands::get(args) => {
-            // must have key
-            cmd = CommandData::Get {
-                key: args.key.as_ref().unwrap().to_owned(),
-            };
-            // commands initialized, now send the request to server
+            // must have key, the value is streamed to stdout as it arrives
+            let mut stdout = io::stdout().lock();
+            if client.get_to(args.key.as_ref().unwrap().to_owned(), &mut stdout)? {
+                writeln!(stdout)?;
+            } else {
+                writeln!(stdout, "Key not found")?;
+            }
+            return Ok(());
         }
         Commands::rm(args) => {
             // must have key
@@ -45,17 +96,101 @@ fn main() -> Result<()> {
             };
             // commands initialized, now send the request to server
         }
+        Commands::sync => {
+            // returns once the server has made prior writes durable
+            cmd = CommandData::Sync;
+        }
+        Commands::clear(args) => {
+            // must be confirmed
+            args.confirm()?;
+            cmd = CommandData::Clear;
+        }
+        Commands::len => {
+            // prints the number of keys
+            cmd = CommandData::Len;
+        }
+        Commands::pause => {
+            // returns once the writes the server is handling have finished
+            cmd = CommandData::Pause;
+        }
+        Commands::resume => {
+            cmd = CommandData::Resume;
+        }
+        Commands::append(args) => {
+            // prints the new length
+            cmd = CommandData::Append {
+                key: args.key.to_owned(),
+                value: args.value.to_owned(),
+            };
+        }
+        Commands::prepend(args) => {
+            // prints the new length
+            cmd = CommandData::Prepend {
+                key: args.key.to_owned(),
+                value: args.value.to_owned(),
+            };
+        }
+        Commands::rename(args) => {
+            // the source must exist
+            cmd = CommandData::Rename {
+                from: args.from.to_owned(),
+                to: args.to.to_owned(),
+            };
+        }
+        Commands::compact(_) => {
+            // compaction is run by kvs, against a store on disk
+            return Err("compact is only run by kvs".into());
+        }
+        Commands::info(args) => {
+            // prints the server's build and configuration
+            let info = client.info()?;
+            match &args.format[..] {
+                "json" => println!("{}", serde_json::to_string_pretty(&info)?),
+                _ => println!("{}", info),
+            }
+            return Ok(());
+        }
+        Commands::scan(args) => {
+            // prints the page, and where the next one starts
+            args.print(client.scan_page(args.start.to_owned(), args.limit)?);
+            return Ok(());
+        }
+        Commands::touch(args) => {
+            // the key must exist
+            cmd = CommandData::Touch {
+                key: args.key.to_owned(),
+            };
+        }
+        Commands::accessed(args) => {
+            // prints the last access, or "Key not found"
+            cmd = CommandData::Accessed {
+                key: args.key.to_owned(),
+            };
+        }
+        Commands::history(args) => {
+            // prints the values, newest first
+            args.print(client.get_versions(args.key.to_owned(), args.limit)?);
+            return Ok(());
+        }
+        Commands::bench(_) => unreachable!("bench is run before connecting"),
+        Commands::stats(args) => {
+            // prints the counters, or zeroes them
+            cmd = match args.action {
+                Some(StatsAction::reset) => CommandData::StatsReset,
+                None => CommandData::Stats {
+                    window: args.window,
+                },
+            };
+        }
     }
     // commands initialized, now send the request to server
     let data = client.send(&cmd)?;
     if let Some(res) = data {
-        println!("{}", res);
-        // if this was an Rm fail out
-        if let Commands::rm(key) = &cli.command {
-            // return Err(Box::new(ErrKeyNotFound{key: key.key.clone().unwrap()}));
-            return Err(Box::from(ErrKeyNotFound {
-                key: key.key.to_owned().unwrap(),
-            }));
+        match &cli.command {
+            // the only response to a rm / rename / touch with a message is "Key not found", fail
+            // out
+            Commands::rm(_) | Commands::rename(_) | Commands::touch(_) => return Err(res.into()),
+            _ => println!("{}", res),
         }
     }
     Ok(())
@@ -1,34 +1,84 @@
-use clap::Parser;
-use kvs::cli::Server;
-use kvs::engines::kvs_engine::Result;
+use clap::{CommandFactory, FromArgMatches};
+use kvs::cli::{Engine, Server};
+use kvs::engines::kvs_engine::{Result, SharedKvsEngine};
+use kvs::engines::kvs::KvStore;
+use kvs::engines::sled::SledKvsEngine;
+#[cfg(unix)]
+use kvs::daemon;
 use kvs::kvs_server::KvsServer;
-use kvs::thread_pool::{shared_queue::SharedQueueThreadPool, ThreadPool, naive::NaiveThreadPool};
-use std::error::Error;
-use std::net::{SocketAddr, ToSocketAddrs};
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 fn main() -> Result<()> {
-    let cli = Server::parse();
+    let matches = Server::command().get_matches();
+    let mut cli = Server::from_arg_matches(&matches)?;
+    // fill in any settings not given as flags from the config file
+    cli.merge_config(&matches)?;
+
+    let sled_options = cli.sled_options()?;
+    let track_access = cli.track_access()?;
+    let hash_keys = cli.hash_keys()?;
+    let compaction = cli.compaction()?;
+    let tls = cli.tls_config()?;
 
     // receive addr to serve on
-    let addr: SocketAddr = cli
-        .addr
-        .to_socket_addrs()
-        .map_err(|err| Into::<Box<dyn Error>>::into(err))?
-        .next()
-        .unwrap();
+    let addr = cli.listen_addr()?;
+
+    // fork into the background before the engine spawns any threads
+    #[cfg(unix)]
+    let daemon = match cli.daemon {
+        true => Some(daemon::daemonize(
+            cli.log_file.as_deref().unwrap_or_else(|| Path::new("kvs-server.log")),
+        )?),
+        false => None,
+    };
+    #[cfg(not(unix))]
+    if cli.daemon {
+        return Err("--daemon is only supported on unix".into());
+    }
 
     let mut server: KvsServer;
-    // unwrap engine
-    match &cli.engine[..] {
-        "kvs" => {
-            // initialize server with kvs engine
-            server = KvsServer::init::<SocketAddr>(addr, false)?;
+    match cli.engine {
+        Engine::Kvs => {
+            // initialize server with kvs engine, tracking access times / hashing keys / keeping
+            // every record if asked to
+            let engine = KvStore::open("./")?;
+            engine.set_access_tracking(track_access)?;
+            engine.set_key_hashing(hash_keys)?;
+            engine.set_compaction_options(compaction)?;
+            server = KvsServer::with_engine(addr, SharedKvsEngine::from(engine))?;
         }
-        "sled" => {
-            // initialize server with sled engine
-            server = KvsServer::init::<SocketAddr>(addr, true)?;
+        Engine::Sled => {
+            // initialize server with sled engine, tuned by the sled flags
+            let engine = SledKvsEngine::open_with_config("./db", sled_options)?;
+            server = KvsServer::with_engine(addr, SharedKvsEngine::from(engine))?;
         }
-        _ => panic!(),
+    }
+    server.set_idle_timeout(cli.idle_timeout.map(Duration::from_secs));
+    server.set_max_rps(cli.max_rps);
+    server.set_key_policy(cli.key_policy());
+    server.set_value_format(cli.value_format);
+    server.set_allow_pause(cli.allow_pause);
+    server.set_slow_log_threshold(cli.slow_log_threshold.map(Duration::from_millis));
+    server.set_tls(tls);
+    // resolve the primary to replicate from
+    let primary = match &cli.replicate_from {
+        Some(primary) => primary.to_socket_addrs()?.next(),
+        None => None,
+    };
+    server.set_replicate_from(primary);
+    // report the bound address, this is the port chosen by the OS when serving on port 0
+    println!("listening on {}", server.local_addr()?);
+    // the server is listening, let the process that started the daemon exit
+    #[cfg(unix)]
+    if let Some(daemon) = daemon {
+        daemon.ready(
+            server.local_addr()?,
+            cli.pid_file.unwrap_or_else(|| PathBuf::from("kvs-server.pid")),
+            server.engine(),
+        )?;
     }
     // now serve requests
-    server.serve(*(SharedQueueThreadPool::new(4)?))
+    server.serve(*(SharedQueueThreadPool::new_with_capacity(4, cli.max_queue_depth)?))
 }
@@ -1,8 +1,8 @@
 use clap::Parser;
 use kvs::cli::{Cli, Commands};
 use kvs::engines::{
-    kvs::KvStore,
-    kvs_engine::{KvsEngine, Result},
+    kvs::{CompactOpts, KvStore},
+    kvs_engine::{KvsEngine, Result, ValueFormat},
 };
 fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -11,16 +11,17 @@ fn main() -> Result<()> {
         // set command
         Commands::set(args) => {
             // open store at the current log directory
-            let mut store = KvStore::open("./")?;
-            store.set(
-                args.key.as_ref().unwrap().to_owned(),
-                args.value.as_ref().unwrap().to_owned(),
-            )
+            let store = KvStore::open("./")?;
+            store.set(args.key.as_ref().unwrap().to_owned(), args.read_value()?)
         }
         Commands::get(args) => {
-            let mut store: KvStore = KvStore::open("./")?;
+            let store: KvStore = KvStore::open("./")?;
             match store.get(args.key.as_ref().unwrap().to_owned()) {
                 Ok(data) => match data {
+                    Some(val) if args.pretty => {
+                        println!("{}", ValueFormat::Json.pretty(val)?);
+                        Ok(())
+                    }
                     Some(val) => {
                         println!("{}", val);
                         Ok(())
@@ -34,8 +35,67 @@ fn main() -> Result<()> {
             }
         }
         Commands::rm(args) => {
-            let mut store = KvStore::open("./")?;
+            let store = KvStore::open("./")?;
             store.remove(args.key.as_ref().unwrap().to_owned())
         }
+        Commands::sync => {
+            let store = KvStore::open("./")?;
+            store.sync()
+        }
+        Commands::clear(args) => {
+            args.confirm()?;
+            let store = KvStore::open("./")?;
+            store.clear()
+        }
+        Commands::len => {
+            let store = KvStore::open("./")?;
+            println!("{}", store.len()?);
+            Ok(())
+        }
+        Commands::append(args) => {
+            let store = KvStore::open("./")?;
+            println!(
+                "{}",
+                store.append(args.key.to_owned(), args.value.to_owned())?
+            );
+            Ok(())
+        }
+        Commands::prepend(args) => {
+            let store = KvStore::open("./")?;
+            println!(
+                "{}",
+                store.prepend(args.key.to_owned(), args.value.to_owned())?
+            );
+            Ok(())
+        }
+        Commands::rename(args) => {
+            let store = KvStore::open("./")?;
+            store.rename(args.from.to_owned(), args.to.to_owned())
+        }
+        Commands::stats(_) => Err("stats are only kept by kvs-server".into()),
+        Commands::info(_) => Err("info is only answered by kvs-server".into()),
+        Commands::bench(_) => Err("bench is only run by kvs-client".into()),
+        Commands::pause | Commands::resume => Err("writes are only paused by kvs-server".into()),
+        Commands::touch(_) | Commands::accessed(_) => {
+            Err("access times are only tracked by kvs-server".into())
+        }
+        Commands::history(args) => {
+            let store = KvStore::open("./")?;
+            args.print(store.get_versions(args.key.to_owned(), args.limit)?);
+            Ok(())
+        }
+        Commands::scan(args) => {
+            let store = KvStore::open("./")?;
+            args.print(store.scan_page(args.start.to_owned(), args.limit)?);
+            Ok(())
+        }
+        Commands::compact(args) => {
+            let store = KvStore::open("./")?;
+            let report = store.compact_with(CompactOpts {
+                dry_run: args.dry_run,
+            })?;
+            println!("{}", report);
+            Ok(())
+        }
     }
 }
@@ -1,4 +1,16 @@
-use clap::{Args, Parser, Subcommand};
+use crate::bench::{BenchConfig, Ratio};
+use crate::engines::kvs::{CompactionOptions, CompactionStrategy};
+use crate::engines::kvs_engine::{KeyPolicy, Page, Result, ValueFormat};
+use crate::engines::sled::SledOptions;
+use crate::transport::{client_tls_config, server_tls_config};
+use clap::{ArgGroup, ArgMatches, Args, Parser, Subcommand, ValueEnum, ValueSource};
+use rustls::{ClientConfig, ServerConfig as TlsServerConfig};
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 /// Cli object used for kvs Cli
 /// # SubCommands
 /// get <key> - get value for key
@@ -19,6 +31,8 @@ pub struct Cli {
 /// rm  <key> - remove (key, value) pair from cache and log
 /// # Flags
 /// addr <address:port> - ip address / port on which kvs-server is serving
+/// tls - connect with TLS, ca-cert <path> - trust this CA rather than the system's
+/// replica <address:port> - send reads to this replica of the server at addr, may be repeated
 #[derive(Parser)]
 #[clap(author, version, infer_subcommands = true)]
 pub struct Client {
@@ -28,33 +42,402 @@ pub struct Client {
     /// optional argument, ipaddr / port that kvs-server is serving on
     #[clap(long, value_parser, action, default_value = "127.0.0.1:4000")]
     pub addr: String,
+    /// encrypt the connection with TLS, kvs-server must be serving TLS
+    #[clap(long, action)]
+    pub tls: bool,
+    /// PEM file of the certificates trusted to sign kvs-server's certificate, defaults to the
+    /// system's root certificates
+    #[clap(long, value_parser, requires = "tls")]
+    pub ca_cert: Option<PathBuf>,
+    /// ipaddr / port of a replica of the server at addr, reads are spread across the replicas
+    /// round-robin, and writes sent to addr, may be given more than once
+    #[clap(long = "replica", value_parser, conflicts_with = "tls")]
+    pub replicas: Vec<String>,
+}
+
+impl Client {
+    /// tls_config returns the TLS config described by the tls flags, None for plaintext
+    /// # Errors
+    /// see transport::client_tls_config
+    pub fn tls_config(&self) -> Result<Option<Arc<ClientConfig>>> {
+        if !self.tls {
+            return Ok(None);
+        }
+        client_tls_config(self.ca_cert.as_deref()).map(Some)
+    }
+
+    /// server_name returns the host in addr, kvs-server's TLS certificate must be valid for it
+    pub fn server_name(&self) -> &str {
+        let host = self
+            .addr
+            .rsplit_once(':')
+            .map_or(&self.addr[..], |(host, _)| host);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
 }
 
 /// Cli interface for kvs-server
 /// # Flags
 /// addr <address:port> - ip address / port on which kvs-server is serving
 /// engine <engine> - the kvs backend to be used, sled / kvs
+/// idle-timeout <seconds> - close connections that have been idle for this long
+/// max-rps <n> - reject requests over n a second on a connection
+/// sled-cache-mb <MB> / sled-flush-ms <ms> - tune the sled engine, see SledOptions
+/// track-access - track when each key was last accessed, for touch / accessed
+/// hash-keys - index a hash of each key rather than the key, see KvStore::set_key_hashing
+/// disable-compaction - keep every record in the log, for kvs-client history
+/// allow-pause - let kvs-client pause / resume writes, for maintenance
+/// tls-cert <path> / tls-key <path> - serve TLS with this certificate chain and private key
+/// config <path> - read any setting not given as a flag from this TOML file, see ServerConfig
 
 #[derive(Parser)]
 #[clap(author, version)]
 pub struct Server {
-    /// <address>:<port> to serve on
+    /// <address>:<port> to serve on, port 0 serves on a free port chosen by the OS
     #[clap(long, value_parser, action, default_value = "127.0.0.1:4000")]
     pub addr: String,
     /// kvs engine to be used
-    #[clap(long, value_parser, action, default_value = "kvs")]
-    pub engine: String,
+    #[clap(long, value_enum, action, default_value_t = Engine::Kvs)]
+    pub engine: Engine,
+    /// close connections that send no command for this many seconds
+    #[clap(long, value_parser, action)]
+    pub idle_timeout: Option<u64>,
+    /// reject requests over this many a second on a connection, with bursts of up to as many
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_rps: Option<u32>,
+    /// accept no more connections while this many are waiting for a worker thread
+    #[clap(long, value_parser, action)]
+    pub max_queue_depth: Option<usize>,
+    /// reject keys longer than this many bytes
+    #[clap(long, value_parser)]
+    pub max_key_len: Option<usize>,
+    /// reject the empty key
+    #[clap(long, action)]
+    pub deny_empty_keys: bool,
+    /// reject keys containing control characters
+    #[clap(long, action)]
+    pub deny_control_chars: bool,
+    /// reject keys containing any of these characters
+    #[clap(long, value_parser, default_value = "")]
+    pub forbidden_key_chars: String,
+    /// reject values not in this format, raw accepts any value, json only a JSON document
+    #[clap(long, value_enum, default_value_t = ValueFormat::Raw)]
+    pub value_format: ValueFormat,
+    /// fork into the background, returning once the server is listening
+    #[clap(long, action)]
+    pub daemon: bool,
+    /// file the daemon writes its PID to, defaults to ./kvs-server.pid
+    #[clap(long, value_parser, requires = "daemon")]
+    pub pid_file: Option<PathBuf>,
+    /// file the daemon appends its logs to, defaults to ./kvs-server.log
+    #[clap(long, value_parser, requires = "daemon")]
+    pub log_file: Option<PathBuf>,
+    /// follow the primary kvs-server at this address, applying its writes locally
+    #[clap(long, value_parser)]
+    pub replicate_from: Option<String>,
+    /// log a warning for commands taking longer than this many milliseconds
+    #[clap(long, value_parser)]
+    pub slow_log_threshold: Option<u64>,
+    /// size of the sled engine's page cache in megabytes
+    #[clap(long, value_parser)]
+    pub sled_cache_mb: Option<u64>,
+    /// how often the sled engine flushes in the background in milliseconds, 0 only flushes on sync
+    #[clap(long, value_parser)]
+    pub sled_flush_ms: Option<u64>,
+    /// track when each key was last accessed, for kvs-client touch / accessed, requires
+    /// --engine kvs
+    #[clap(long, action)]
+    pub track_access: bool,
+    /// index a hash of each key rather than the key itself, bounding the memory held per key,
+    /// requires --engine kvs, scans and --track-access are not supported
+    #[clap(long, action)]
+    pub hash_keys: bool,
+    /// never compact the log, keeping every value written for kvs-client history, requires
+    /// --engine kvs
+    #[clap(long, action)]
+    pub disable_compaction: bool,
+    /// let clients pause writes with kvs-client pause, and resume them with kvs-client resume
+    #[clap(long, action)]
+    pub allow_pause: bool,
+    /// PEM file of the certificate chain to serve TLS with, requires --tls-key
+    #[clap(long, value_parser)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of --tls-cert
+    #[clap(long, value_parser)]
+    pub tls_key: Option<PathBuf>,
+    /// TOML file to read settings from, flags given on the command line take precedence
+    #[clap(long, value_parser)]
+    pub config: Option<PathBuf>,
+}
+
+/// Engine is the storage engine kvs-server serves, named the same on the command line and in the
+/// config file
+#[derive(ValueEnum, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// KvStore, the log-structured store of this crate
+    Kvs,
+    /// SledKvsEngine, backed by sled
+    Sled,
+}
+
+/// ServerConfig is the TOML file read by kvs-server --config, every setting is optional, and is
+/// named after the matching flag, with '-' replaced by '_'. Unknown settings are an error
+/// ```toml
+/// addr = "127.0.0.1:4001"
+/// engine = "sled"
+/// idle_timeout = 30
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// see Server::addr
+    pub addr: Option<String>,
+    /// see Server::engine
+    pub engine: Option<Engine>,
+    /// see Server::idle_timeout
+    pub idle_timeout: Option<u64>,
+    /// see Server::max_rps
+    pub max_rps: Option<u32>,
+    /// see Server::max_queue_depth
+    pub max_queue_depth: Option<usize>,
+    /// see Server::max_key_len
+    pub max_key_len: Option<usize>,
+    /// see Server::deny_empty_keys
+    pub deny_empty_keys: Option<bool>,
+    /// see Server::deny_control_chars
+    pub deny_control_chars: Option<bool>,
+    /// see Server::forbidden_key_chars
+    pub forbidden_key_chars: Option<String>,
+    /// see Server::value_format
+    pub value_format: Option<ValueFormat>,
+    /// see Server::daemon
+    pub daemon: Option<bool>,
+    /// see Server::pid_file
+    pub pid_file: Option<PathBuf>,
+    /// see Server::log_file
+    pub log_file: Option<PathBuf>,
+    /// see Server::replicate_from
+    pub replicate_from: Option<String>,
+    /// see Server::slow_log_threshold
+    pub slow_log_threshold: Option<u64>,
+    /// see Server::sled_cache_mb
+    pub sled_cache_mb: Option<u64>,
+    /// see Server::sled_flush_ms
+    pub sled_flush_ms: Option<u64>,
+    /// see Server::track_access
+    pub track_access: Option<bool>,
+    /// see Server::hash_keys
+    pub hash_keys: Option<bool>,
+    /// see Server::disable_compaction
+    pub disable_compaction: Option<bool>,
+    /// see Server::allow_pause
+    pub allow_pause: Option<bool>,
+    /// see Server::tls_cert
+    pub tls_cert: Option<PathBuf>,
+    /// see Server::tls_key
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// load reads the ServerConfig at path, settings are held to the same bounds as their flags
+    /// # Errors
+    /// the file cannot be read, is not valid TOML, contains an unknown setting, or a setting out
+    /// of bounds
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        let config = fs::read_to_string(path)
+            .map_err(|e| format!("reading config {}: {}", path.display(), e))?;
+        let config: ServerConfig = toml::from_str(&config)
+            .map_err(|e| format!("parsing config {}: {}", path.display(), e))?;
+        if config.max_rps == Some(0) {
+            return Err(format!(
+                "invalid config {}: max_rps must be at least 1",
+                path.display()
+            )
+            .into());
+        }
+        Ok(config)
+    }
+}
+
+impl Server {
+    /// merge_config fills every setting that was not given on the command line from the file at
+    /// --config, matches are the matches Server was parsed from, so flags left at their default
+    /// are told apart from flags given explicitly
+    /// # Errors
+    /// see ServerConfig::load
+    pub fn merge_config(&mut self, matches: &ArgMatches) -> Result<()> {
+        let config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => return Ok(()),
+        };
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let (false, Some(addr)) = (given("addr"), config.addr) {
+            self.addr = addr;
+        }
+        if let (false, Some(engine)) = (given("engine"), config.engine) {
+            self.engine = engine;
+        }
+        if let (false, Some(chars)) = (given("forbidden-key-chars"), config.forbidden_key_chars) {
+            self.forbidden_key_chars = chars;
+        }
+        if let (false, Some(format)) = (given("value-format"), config.value_format) {
+            self.value_format = format;
+        }
+        self.idle_timeout = self.idle_timeout.or(config.idle_timeout);
+        self.max_rps = self.max_rps.or(config.max_rps);
+        self.max_queue_depth = self.max_queue_depth.or(config.max_queue_depth);
+        self.max_key_len = self.max_key_len.or(config.max_key_len);
+        // boolean flags can only be switched on from the command line
+        self.deny_empty_keys |= config.deny_empty_keys.unwrap_or_default();
+        self.deny_control_chars |= config.deny_control_chars.unwrap_or_default();
+        self.daemon |= config.daemon.unwrap_or_default();
+        self.track_access |= config.track_access.unwrap_or_default();
+        self.hash_keys |= config.hash_keys.unwrap_or_default();
+        self.disable_compaction |= config.disable_compaction.unwrap_or_default();
+        self.allow_pause |= config.allow_pause.unwrap_or_default();
+        self.pid_file = self.pid_file.take().or(config.pid_file);
+        self.log_file = self.log_file.take().or(config.log_file);
+        self.replicate_from = self.replicate_from.take().or(config.replicate_from);
+        self.slow_log_threshold = self.slow_log_threshold.or(config.slow_log_threshold);
+        self.sled_cache_mb = self.sled_cache_mb.or(config.sled_cache_mb);
+        self.sled_flush_ms = self.sled_flush_ms.or(config.sled_flush_ms);
+        self.tls_cert = self.tls_cert.take().or(config.tls_cert);
+        self.tls_key = self.tls_key.take().or(config.tls_key);
+        Ok(())
+    }
+
+    /// listen_addr resolves --addr to the address to serve on
+    /// # Errors
+    /// --addr is not an <address>:<port>, or resolves to no address
+    pub fn listen_addr(&self) -> Result<SocketAddr> {
+        let mut addrs = self.addr.to_socket_addrs().map_err(|e| {
+            format!(
+                "invalid --addr '{}', expected <address>:<port>: {}",
+                self.addr, e
+            )
+        })?;
+        Ok(addrs
+            .next()
+            .ok_or_else(|| format!("--addr '{}' resolves to no address", self.addr))?)
+    }
+
+    /// key_policy returns the KeyPolicy described by the key flags
+    pub fn key_policy(&self) -> KeyPolicy {
+        KeyPolicy {
+            max_len: self.max_key_len,
+            allow_empty: !self.deny_empty_keys,
+            allow_control: !self.deny_control_chars,
+            forbidden: self.forbidden_key_chars.chars().collect(),
+        }
+    }
+
+    /// sled_options returns the SledOptions described by the sled flags
+    /// # Errors
+    /// a sled flag is given, but the engine is not sled
+    pub fn sled_options(&self) -> Result<SledOptions> {
+        let given = self.sled_cache_mb.is_some() || self.sled_flush_ms.is_some();
+        if given && self.engine != Engine::Sled {
+            return Err("--sled-cache-mb and --sled-flush-ms require --engine sled".into());
+        }
+        Ok(SledOptions {
+            cache_capacity: self.sled_cache_mb.map(|mb| mb * 1024 * 1024),
+            flush_every_ms: self.sled_flush_ms,
+            ..SledOptions::default()
+        })
+    }
+
+    /// track_access returns whether the kvs engine tracks access times
+    /// # Errors
+    /// --track-access is given, but the engine is not kvs
+    pub fn track_access(&self) -> Result<bool> {
+        if self.track_access && self.engine != Engine::Kvs {
+            return Err("--track-access requires --engine kvs".into());
+        }
+        Ok(self.track_access)
+    }
+
+    /// compaction returns the CompactionOptions of the kvs engine
+    /// # Errors
+    /// --disable-compaction is given, but the engine is not kvs
+    pub fn compaction(&self) -> Result<CompactionOptions> {
+        if !self.disable_compaction {
+            return Ok(CompactionOptions::default());
+        }
+        if self.engine != Engine::Kvs {
+            return Err("--disable-compaction requires --engine kvs".into());
+        }
+        Ok(CompactionOptions {
+            strategy: CompactionStrategy::Disabled,
+        })
+    }
+
+    /// hash_keys returns whether the kvs engine indexes a hash of each key
+    /// # Errors
+    /// --hash-keys is given, but the engine is not kvs, or --track-access is given too
+    pub fn hash_keys(&self) -> Result<bool> {
+        if self.hash_keys && self.engine != Engine::Kvs {
+            return Err("--hash-keys requires --engine kvs".into());
+        }
+        if self.hash_keys && self.track_access {
+            return Err("--hash-keys and --track-access cannot be used together".into());
+        }
+        Ok(self.hash_keys)
+    }
+
+    /// tls_config returns the TLS config described by the tls flags, None to serve plaintext
+    /// # Errors
+    /// only one of --tls-cert and --tls-key is given, see transport::server_tls_config
+    pub fn tls_config(&self) -> Result<Option<Arc<TlsServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => server_tls_config(cert, key).map(Some),
+            (None, None) => Ok(None),
+            _ => Err("--tls-cert and --tls-key must be given together".into()),
+        }
+    }
 }
 
 /// Available commands for kvs / kvs-client
 #[derive(Subcommand)]
 pub enum Commands {
-    // set value at key in state
+    /// set value at key in state
     set(Set),
-    //  get value at key from state
+    /// get value at key from state
     get(Get),
-    // remove value at key in state
+    /// remove value at key in state
     rm(Rm),
+    /// flush all prior writes to disk
+    sync,
+    /// remove every (key, value) pair from state
+    clear(Clear),
+    /// append to the value at key in state
+    append(Append),
+    /// prepend to the value at key in state
+    prepend(Prepend),
+    /// number of keys in state
+    len,
+    /// move the value at one key to another
+    rename(Rename),
+    /// counters of the commands handled by kvs-server
+    stats(Stats),
+    /// rewrite the log with only its live records
+    compact(Compact),
+    /// list (key, value) pairs in key order, a page at a time
+    scan(Scan),
+    /// the build and configuration of kvs-server
+    info(Info),
+    /// mark the value at key as accessed, without reading it
+    touch(Touch),
+    /// when the value at key was last accessed
+    accessed(Accessed),
+    /// the values key has been set to, newest first
+    history(History),
+    /// reject writes until resume, reads are still served, kvs-server must allow it
+    pause,
+    /// handle writes again after a pause
+    resume,
+    /// put load on kvs-server, and report the throughput and latency it was served with
+    bench(Bench),
 }
 
 #[derive(Args)]
@@ -64,14 +447,19 @@ pub struct Get {
     /// key passed key from which to get value
     #[clap(value_parser)]
     pub key: Option<String>,
+    /// print the value as indented JSON, failing if the value is not JSON
+    #[clap(long, action)]
+    pub pretty: bool,
 }
 
 /// standard set command,
 /// key: key for which to set value to
-/// value: value that will be set with `key`
+/// value: value that will be set with `key`, exactly one of value, --value-file, or --value-stdin
+/// must be given
 /// # Behavior
 /// If key already exists, overwrites key
 #[derive(Args)]
+#[clap(group(ArgGroup::new("input").required(true).args(&["value", "value-file", "value-stdin"])))]
 pub struct Set {
     /// key to set value to
     #[clap(value_parser)]
@@ -79,6 +467,250 @@ pub struct Set {
     /// value that will be set with key
     #[clap(value_parser)]
     pub value: Option<String>,
+    /// read the value to set from the file at this path
+    #[clap(long, value_parser)]
+    pub value_file: Option<PathBuf>,
+    /// read the value to set from stdin
+    #[clap(long, action)]
+    pub value_stdin: bool,
+}
+
+impl Set {
+    /// read_value returns the value to set, from the positional value, --value-file, or --value-stdin,
+    /// the bytes read are set as is
+    /// # Errors
+    /// the file / stdin could not be read, or the value is not valid UTF-8
+    pub fn read_value(&self) -> Result<String> {
+        let bytes = if let Some(value) = &self.value {
+            return Ok(value.to_owned());
+        } else if let Some(path) = &self.value_file {
+            fs::read(path).map_err(|err| format!("unable to read {}: {}", path.display(), err))?
+        } else {
+            let mut buf = Vec::new();
+            io::stdin().read_to_end(&mut buf)?;
+            buf
+        };
+        String::from_utf8(bytes).map_err(|_| "value is not valid UTF-8".into())
+    }
+}
+
+/// Clear Command
+/// # Behavior
+/// Removes every (key, value) pair from the store
+/// # Errors
+/// refuses to clear the store unless --yes is passed
+#[derive(Args)]
+pub struct Clear {
+    /// confirm that every (key, value) pair should be removed
+    #[clap(long, action)]
+    pub yes: bool,
+}
+
+impl Clear {
+    /// confirm returns an error unless --yes was passed
+    pub fn confirm(&self) -> Result<()> {
+        if !self.yes {
+            return Err("refusing to clear the store without --yes".into());
+        }
+        Ok(())
+    }
+}
+
+/// Append Command
+/// # Behavior
+/// Appends value to the value at key, creating it if key does not exist, prints the new length
+#[derive(Args)]
+pub struct Append {
+    /// key of the value to append to
+    #[clap(value_parser)]
+    pub key: String,
+    /// text appended to the value
+    #[clap(value_parser)]
+    pub value: String,
+}
+
+/// Prepend Command
+/// # Behavior
+/// Prepends value to the value at key, creating it if key does not exist, prints the new length
+#[derive(Args)]
+pub struct Prepend {
+    /// key of the value to prepend to
+    #[clap(value_parser)]
+    pub key: String,
+    /// text prepended to the value
+    #[clap(value_parser)]
+    pub value: String,
+}
+
+/// Rename Command
+/// # Behavior
+/// Moves the value at from to to, replacing any value at to, and removes from
+/// # Errors
+/// ErrNotFound - from has no value
+#[derive(Args)]
+pub struct Rename {
+    /// key of the value to move
+    #[clap(value_parser)]
+    pub from: String,
+    /// key the value is moved to
+    #[clap(value_parser)]
+    pub to: String,
+}
+
+/// Compact Command
+/// # Behavior
+/// Rewrites the log of the store in the current directory with only its live records, and prints
+/// the space reclaimed, --dry-run only prints the space that would be reclaimed
+#[derive(Args)]
+pub struct Compact {
+    /// report the space compaction would reclaim without rewriting the log
+    #[clap(long, action)]
+    pub dry_run: bool,
+}
+
+/// Scan Command
+/// # Behavior
+/// Prints up to --limit (key, value) pairs in key order, one `key\tvalue` per line, starting at
+/// the key --start, or the first key. If there are more keys the line `next: <key>` follows, pass
+/// that key as --start to print the next page
+#[derive(Args)]
+pub struct Scan {
+    /// key the page starts at, the first key if not given
+    #[clap(long, value_parser)]
+    pub start: Option<String>,
+    /// the most pairs printed
+    #[clap(long, value_parser, default_value_t = 100)]
+    pub limit: usize,
+}
+
+impl Scan {
+    /// print writes a page returned by scan_page to stdout, as described above
+    pub fn print(&self, page: Page) {
+        let (entries, next) = page;
+        for (key, value) in entries {
+            println!("{}\t{}", key, value);
+        }
+        if let Some(next) = next {
+            println!("next: {}", next);
+        }
+    }
+}
+
+/// Info Command
+/// # Behavior
+/// Prints kvs-server's version, engine, compaction, thread pool, and address, one `name: value`
+/// per line, or as a JSON object with --format json
+#[derive(Args)]
+pub struct Info {
+    /// text or json
+    #[clap(long, value_parser = ["text", "json"], default_value = "text")]
+    pub format: String,
+}
+
+/// Touch Command
+/// # Behavior
+/// Marks key as accessed now, without reading its value, kvs-server must be tracking access
+/// times, see Server::track_access
+/// # Errors
+/// ErrNotFound - key has no value
+#[derive(Args)]
+pub struct Touch {
+    /// key of the value to mark as accessed
+    #[clap(value_parser)]
+    pub key: String,
+}
+
+/// Accessed Command
+/// # Behavior
+/// Prints when key was last set, read, or touched, in milliseconds since the unix epoch, or
+/// `never` if it has not been accessed since kvs-server began tracking access times
+#[derive(Args)]
+pub struct Accessed {
+    /// key of the value to look up
+    #[clap(value_parser)]
+    pub key: String,
+}
+
+/// History Command
+/// # Behavior
+/// Prints up to --limit of the values key has been set to, one per line, newest first, or
+/// `Key not found` if it has never been set, nothing is printed for a --limit of 0. Values are read from the log, so only the values
+/// written since it was last compacted are printed, see CompactionStrategy::Disabled
+#[derive(Args)]
+pub struct History {
+    /// key of the values to print
+    #[clap(value_parser)]
+    pub key: String,
+    /// the most values printed
+    #[clap(long, value_parser, default_value_t = 10)]
+    pub limit: usize,
+}
+
+impl History {
+    /// print writes the values returned by get_versions to stdout, as described above
+    pub fn print(&self, versions: Vec<String>) {
+        // a key that has been set has at least one version, unless none were asked for
+        if versions.is_empty() && self.limit > 0 {
+            println!("Key not found");
+        }
+        for value in versions {
+            println!("{}", value);
+        }
+    }
+}
+
+/// Bench Command
+/// # Behavior
+/// Sends --requests gets and sets, in the proportion given by --ratio, across --threads
+/// connections, and prints the throughput and latency percentiles they were answered with. Sets
+/// write values of --value-size bytes, and overwrite the keys key0 to key999
+#[derive(Args)]
+pub struct Bench {
+    /// the number of connections, each sending from a thread of its own
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 4)]
+    pub threads: u32,
+    /// the number of requests sent, across every connection
+    #[clap(long, value_parser, default_value_t = 10000)]
+    pub requests: usize,
+    /// the length of each value set, in bytes
+    #[clap(long, value_parser, default_value_t = 100)]
+    pub value_size: usize,
+    /// <reads>:<writes>, the share of requests that are gets and sets
+    #[clap(long, value_parser, default_value = "80:20")]
+    pub ratio: Ratio,
+}
+
+impl Bench {
+    /// config returns the BenchConfig described by the bench flags
+    pub fn config(&self) -> BenchConfig {
+        BenchConfig {
+            threads: self.threads as usize,
+            requests: self.requests,
+            value_size: self.value_size,
+            ratio: self.ratio,
+        }
+    }
+}
+
+/// Stats Command
+/// # Behavior
+/// Prints the commands kvs-server has handled, and the rate it handled them at over the last
+/// --window seconds, `stats reset` zeroes the counters instead
+#[derive(Args)]
+pub struct Stats {
+    /// seconds the rate of commands is measured over, at most 60
+    #[clap(long, value_parser, default_value_t = 60)]
+    pub window: u64,
+    /// zero the counters
+    #[clap(subcommand)]
+    pub action: Option<StatsAction>,
+}
+
+/// Subcommands of stats
+#[derive(Subcommand)]
+pub enum StatsAction {
+    /// zero the counters kept by kvs-server
+    reset,
 }
 
 /// Standard Rm Command
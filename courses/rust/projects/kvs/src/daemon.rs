@@ -0,0 +1,129 @@
+use crate::engines::kvs_engine::{Result, SharedKvsEngine};
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+/// Daemon is held by the background kvs-server process, until the server is ready to accept
+/// connections, the process that started the daemon waits on it
+pub struct Daemon {
+    // write end of the pipe the starting process is waiting on
+    ready: File,
+}
+
+/// daemonize forks the current process into the background, this must be called before any
+/// threads are spawned
+/// The calling process waits until the background process reports it is ready with
+/// Daemon::ready, prints the address it is listening on, and exits. The background process is
+/// detached from the terminal, its stdout / stderr are appended to log_file, and SIGTERM /
+/// SIGINT are blocked so they may be handled once the server is ready
+/// # Errors
+/// OS errors resulting from opening log_file, or forking
+pub fn daemonize(log_file: &Path) -> Result<Daemon> {
+    let log = File::options().create(true).append(true).open(log_file)?;
+    let null = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(Box::from(io::Error::last_os_error()));
+    }
+    let (mut wait, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(Box::from(io::Error::last_os_error())),
+        0 => {
+            // the background process, only the parent waits on the pipe
+            drop(wait);
+            if unsafe { libc::setsid() } == -1 {
+                return Err(Box::from(io::Error::last_os_error()));
+            }
+            redirect(&null, libc::STDIN_FILENO)?;
+            redirect(&log, libc::STDOUT_FILENO)?;
+            redirect(&log, libc::STDERR_FILENO)?;
+            block_signals()?;
+            Ok(Daemon { ready })
+        }
+        _ => {
+            // the starting process, wait for the background process to report its address, if it
+            // exits before then the pipe is closed with nothing written
+            drop(ready);
+            let mut addr = String::new();
+            wait.read_to_string(&mut addr)?;
+            if addr.is_empty() {
+                eprintln!(
+                    "kvs-server failed to start, see {} for details",
+                    log_file.display()
+                );
+                process::exit(1);
+            }
+            print!("{}", addr);
+            process::exit(0);
+        }
+    }
+}
+
+impl Daemon {
+    /// ready writes the PID of the background process to pid_file, and reports addr to the
+    /// process waiting on the daemon. Once a SIGTERM / SIGINT is received, engine is synced,
+    /// pid_file is removed, and the process exits
+    pub fn ready(
+        mut self,
+        addr: SocketAddr,
+        pid_file: PathBuf,
+        engine: SharedKvsEngine,
+    ) -> Result<()> {
+        fs::write(&pid_file, format!("{}\n", process::id()))?;
+        thread::spawn(move || {
+            let signal = wait_for_signal();
+            info!("received signal {}, shutting down", signal);
+            if let Err(e) = engine.sync() {
+                error!("error syncing engine: {}", e);
+            }
+            let _ = fs::remove_file(&pid_file);
+            process::exit(0);
+        });
+        writeln!(self.ready, "listening on {}", addr)?;
+        Ok(())
+    }
+}
+
+// point fd at file
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        return Err(Box::from(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// the set of signals that shut the daemon down
+fn shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+        libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+        set.assume_init()
+    }
+}
+
+// block the shutdown signals in this thread, threads spawned afterwards inherit the mask, so the
+// signals stay pending until wait_for_signal takes them
+fn block_signals() -> Result<()> {
+    let set = shutdown_signals();
+    let errno = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+    if errno != 0 {
+        return Err(Box::from(io::Error::from_raw_os_error(errno)));
+    }
+    Ok(())
+}
+
+// wait until one of the shutdown signals is received, returns the signal
+fn wait_for_signal() -> libc::c_int {
+    let set = shutdown_signals();
+    let mut signal = 0;
+    unsafe { libc::sigwait(&set, &mut signal) };
+    signal
+}
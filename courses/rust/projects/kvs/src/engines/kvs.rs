@@ -1,22 +1,27 @@
-use crate::engines::kvs_engine::{ErrKeyNotFound, KvsEngine, Result};
-use parking_lot::Mutex;
+use crate::engines::kvs_engine::{
+    ErrKeyNotFound, KeyPolicy, KvsEngine, KvsError, Page, Result, ValueFormat, ValueStream,
+};
+use crate::engines::log_storage::{FileStorage, GroupCommitStorage, LogStorage, StreamStorage};
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::cmp::Ordering;
+use std::collections::hash_map::{self, RandomState};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-    sync::Arc,
-};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::iter;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{self, AtomicU64};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{path::PathBuf, sync::Arc};
 /// Example
 /// ```rust
 /// use kvs::engines::{kvs::KvStore, kvs_engine::KvsEngine};
 /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
-/// # let mut store = KvStore::open("./")?;
+/// # let store = KvStore::open("./")?;
 /// # store.set("hello".to_string(), "world".to_string());
 /// # assert_eq!(store.get("hello".to_owned())?, Some("world".to_string()));
 /// # store.remove("hello".to_string());
@@ -24,31 +29,415 @@ use std::{
 /// # Ok(())
 /// # }
 /// ```
-/// KvStore object contains a HashMap taking Keys to Values
+/// KvStore object contains a BTreeMap taking Keys to Values, kept in key order so ranges of keys
+/// are read without sorting, or a map from a hash of each key, see KvStore::set_key_hashing
 /// The KvStore implements the following methods
-/// fn set(&mut self, key: String, value: String)
-/// fn get(&mut self, key: String) -> Option<String>
-/// fn rm(&mut self, key: String)
-/// This is an in-memory kv-store, it does not persist state to disk
+/// fn set(&self, key: String, value: String)
+/// fn get(&self, key: String) -> Option<String>
+/// fn rm(&self, key: String)
+/// Reads share a read lock on the store, and are not recorded in the log, writes take the lock
+/// exclusively
 pub struct KvStore {
-    // map containing sha256(command, key, value?) -> file_offset
-    map: HashMap<String, String>,
-    // file to be used during sets, gets, rm
-    file: PathBuf,
+    // the log and the state cached from it, held in an Arc so a stream of changes may hold a
+    // read lock, keeping the log from being compacted until the stream is dropped
+    state: Arc<RwLock<LogState>>,
+}
+
+/// LogState is the log of a KvStore, and the state cached from it
+struct LogState {
+    // the live values, and the records of their sets, values of at most inline_threshold bytes
+    // are held inline
+    index: Index,
+    // the largest value held inline
+    inline_threshold: usize,
+    // storage holding the log, used during sets, gets, rm
+    storage: Storage,
     // the log has been modified since last read
     dirty: bool,
-    // set log pointers
-    log_pointers: HashMap<String, Bound>,
     // number of actions made on log
     actions: u64,
+    // size of the log once it was last rewritten, with every sealed segment merged into it
+    compacted: u64,
+    // bytes of the log held by the records the index points at
+    live: u64,
+    // keys accepted by the store
+    key_policy: KeyPolicy,
+    // values accepted by the store
+    value_format: ValueFormat,
+    // ids of the sealed segments, oldest first, the log is newer than all of them
+    segments: Vec<u64>,
+    // how the log is compacted
+    compaction: CompactionOptions,
+    // bytes written to the log and its segments since the store was opened
+    written: u64,
+    // when writes are made durable
+    durability: Durability,
+    // when each live key was last accessed, in milliseconds since the unix epoch, 0 if it has
+    // not been accessed since tracking began, None unless access tracking is enabled. The times
+    // are atomics, so gets update them under a read lock
+    accessed: Option<HashMap<String, AtomicU64>>,
+}
+
+/// CompactionOptions configures how a KvStore reclaims the space held by stale records
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionOptions {
+    /// the strategy used to compact the log
+    pub strategy: CompactionStrategy,
+}
+
+/// CompactionStrategy is the way a KvStore compacts its log
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// once the log has doubled since it was last rewritten, rewrite the whole log with only its
+    /// live records, so each rewrite is paid for by the writes that grew the log
+    #[default]
+    FullRewrite,
+    /// once the log reaches COMPACTION_SIZE it is sealed into an immutable segment, and a new
+    /// log is started. Segments are tiered by size, a segment under fanout times COMPACTION_SIZE
+    /// is of tier 0, under fanout times that of tier 1, and so on. Once there are fanout adjacent
+    /// segments of a tier, the oldest such run is merged into one, of about the next tier, so the
+    /// cost of a compaction is bounded by the size of the segments merged, rather than the size
+    /// of the store, and each record is rewritten at most once per tier
+    SizeTiered {
+        /// the number of sealed segments merged at a time, at least 2
+        fanout: usize,
+    },
+    /// the log is never compacted automatically, it keeps every record written, so every value
+    /// a key has been set to is returned by get_versions, compact still compacts the log
+    Disabled,
+}
+
+impl fmt::Display for CompactionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompactionStrategy::FullRewrite => write!(f, "full rewrite"),
+            CompactionStrategy::SizeTiered { fanout } => {
+                write!(f, "size tiered, fanout {}", fanout)
+            }
+            CompactionStrategy::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+/// CompactOpts configures a single compaction, see KvStore::compact_with
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactOpts {
+    /// only report the space compaction would reclaim, nothing is rewritten
+    pub dry_run: bool,
+}
+
+/// CompactionReport is the space held by a KvStore's log and sealed segments, as found before a
+/// compaction, and how much of it the compaction reclaims
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// bytes of the log and every sealed segment
+    pub total_bytes: u64,
+    /// bytes held by the records of live keys, this is all that remains after compaction
+    pub live_bytes: u64,
+    /// bytes held by overwritten / removed records, and by records that do not affect the state
+    pub reclaimable_bytes: u64,
+    /// number of live records, i.e keys
+    pub entries: usize,
+}
+
+impl fmt::Display for CompactionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "total bytes: {}", self.total_bytes)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "reclaimable bytes: {}", self.reclaimable_bytes)?;
+        write!(f, "entries: {}", self.entries)
+    }
+}
+
+/// Durability is when a KvStore makes its writes durable, i.e fsyncs them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// every write is handed to the storage before it returns, and only made durable by sync,
+    /// a crash of the process loses nothing, a crash of the machine may lose any unsynced write
+    #[default]
+    OnSync,
+    /// every write is fsynced before it returns
+    EveryWrite,
+    /// writes are buffered in memory, and return once buffered, a background thread writes and
+    /// fsyncs the buffer every interval, or once it holds max_bytes. A crash loses at most the
+    /// writes of the last interval, reads see buffered writes immediately
+    GroupCommit {
+        /// the longest a write is buffered before it is flushed
+        interval: Duration,
+        /// the size of the buffer that triggers a flush before the interval is up
+        max_bytes: usize,
+    },
+}
+
+/// Value is a live value as held by a KvStore, a value of at most the inline threshold is held
+/// inline, so it is read without touching the log, larger values are read from their record
+enum Value {
+    Inline(String),
+    // the length of a value only held in the log
+    InLog(u64),
+}
+
+/// Entry is what the index holds for a live key, its value, and the bound of its Set record
+struct Entry {
+    value: Value,
+    bound: Bound,
+}
+
+/// KeyHasher hashes the keys of a store whose keys are hashed
+type KeyHasher = Box<dyn Fn(&str) -> u64 + Send + Sync>;
+
+/// Index maps each live key of a KvStore to its Entry
+enum Index {
+    /// every key is held in full, in key order
+    Keys(BTreeMap<String, Entry>),
+    /// only a hash of each key is held, the full key is only held in the log, keys with the same
+    /// hash share a slot, and are told apart by reading the key from their records
+    Hashed {
+        hasher: KeyHasher,
+        slots: HashMap<u64, Slot>,
+        // the number of entries across every slot
+        len: usize,
+    },
+}
+
+/// Slot holds the entries of the keys with one hash, only colliding keys need more than one
+enum Slot {
+    One(Entry),
+    Colliding(Vec<Entry>),
+}
+
+impl Slot {
+    fn entries(&self) -> &[Entry] {
+        match self {
+            Slot::One(entry) => std::slice::from_ref(entry),
+            Slot::Colliding(entries) => entries,
+        }
+    }
+
+    fn entries_mut(&mut self) -> &mut [Entry] {
+        match self {
+            Slot::One(entry) => std::slice::from_mut(entry),
+            Slot::Colliding(entries) => entries,
+        }
+    }
+
+    /// add the entry of a key colliding with the keys already in the slot
+    fn push(&mut self, entry: Entry) {
+        match std::mem::replace(self, Slot::Colliding(Vec::with_capacity(2))) {
+            Slot::One(first) => *self = Slot::Colliding(vec![first, entry]),
+            Slot::Colliding(mut entries) => {
+                entries.push(entry);
+                *self = Slot::Colliding(entries);
+            }
+        }
+    }
+
+    /// the position of the entry of key in the slot, see Records::is_of
+    fn position(&self, key: &str, records: &Records) -> Result<Option<usize>> {
+        let serialized = serde_json::to_string(key)?;
+        for (i, entry) in self.entries().iter().enumerate() {
+            if records.is_of(&entry.bound, &serialized)? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Index {
+    /// an empty index, hashing keys with hasher if given
+    fn new(hasher: Option<KeyHasher>) -> Index {
+        match hasher {
+            Some(hasher) => Index::Hashed {
+                hasher,
+                slots: HashMap::new(),
+                len: 0,
+            },
+            None => Index::Keys(BTreeMap::new()),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Index::Keys(keys) => keys.clear(),
+            Index::Hashed { slots, len, .. } => {
+                slots.clear();
+                *len = 0;
+            }
+        }
+    }
+
+    /// the number of live keys
+    fn len(&self) -> usize {
+        match self {
+            Index::Keys(keys) => keys.len(),
+            Index::Hashed { len, .. } => *len,
+        }
+    }
+
+    /// the entry of key, records are only read if keys are hashed, and more than one key has the
+    /// hash of key
+    fn get(&self, key: &str, records: &Records) -> Result<Option<&Entry>> {
+        match self {
+            Index::Keys(keys) => Ok(keys.get(key)),
+            Index::Hashed { hasher, slots, .. } => {
+                let slot = match slots.get(&hasher(key)) {
+                    Some(slot) => slot,
+                    None => return Ok(None),
+                };
+                Ok(slot.position(key, records)?.map(|i| &slot.entries()[i]))
+            }
+        }
+    }
+
+    /// insert the entry of key, returning the entry it replaces
+    fn insert(&mut self, key: String, entry: Entry, records: &Records) -> Result<Option<Entry>> {
+        let (hasher, slots, len) = match self {
+            Index::Keys(keys) => return Ok(keys.insert(key, entry)),
+            Index::Hashed { hasher, slots, len } => (hasher, slots, len),
+        };
+        match slots.entry(hasher(&key)) {
+            hash_map::Entry::Vacant(vacant) => {
+                vacant.insert(Slot::One(entry));
+            }
+            hash_map::Entry::Occupied(mut occupied) => {
+                let slot = occupied.get_mut();
+                if let Some(i) = slot.position(&key, records)? {
+                    return Ok(Some(std::mem::replace(&mut slot.entries_mut()[i], entry)));
+                }
+                slot.push(entry);
+            }
+        }
+        *len += 1;
+        Ok(None)
+    }
+
+    /// remove the entry of key, returning it
+    fn remove(&mut self, key: &str, records: &Records) -> Result<Option<Entry>> {
+        let (hasher, slots, len) = match self {
+            Index::Keys(keys) => return Ok(keys.remove(key)),
+            Index::Hashed { hasher, slots, len } => (hasher, slots, len),
+        };
+        let mut occupied = match slots.entry(hasher(key)) {
+            hash_map::Entry::Occupied(occupied) => occupied,
+            hash_map::Entry::Vacant(_) => return Ok(None),
+        };
+        let i = match occupied.get().position(key, records)? {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        *len -= 1;
+        let removed = match occupied.get_mut() {
+            Slot::Colliding(entries) if entries.len() > 1 => entries.swap_remove(i),
+            _ => match occupied.remove() {
+                Slot::One(entry) => entry,
+                Slot::Colliding(mut entries) => entries.swap_remove(i),
+            },
+        };
+        Ok(Some(removed))
+    }
+
+    /// every entry, in no particular order
+    fn entries(&self) -> Box<dyn Iterator<Item = &Entry> + '_> {
+        match self {
+            Index::Keys(keys) => Box::new(keys.values()),
+            Index::Hashed { slots, .. } => Box::new(slots.values().flat_map(Slot::entries)),
+        }
+    }
+
+    /// every entry, in no particular order
+    fn entries_mut(&mut self) -> Box<dyn Iterator<Item = &mut Entry> + '_> {
+        match self {
+            Index::Keys(keys) => Box::new(keys.values_mut()),
+            Index::Hashed { slots, .. } => Box::new(slots.values_mut().flat_map(Slot::entries_mut)),
+        }
+    }
+}
+
+/// Records reads the keys of Set records, to tell apart keys with the same hash
+struct Records<'a> {
+    storage: &'a dyn LogStorage,
+    // the segment being replayed, and its bytes, its records are read from memory
+    replaying: Option<(Option<u64>, &'a [u8])>,
+}
+
+impl Records<'_> {
+    /// whether the Set record within bound is of the key serialized as serialized. The serialized
+    /// key is a JSON string, and ends at its closing quote, so a record of a longer key sharing
+    /// its prefix never matches
+    fn is_of(&self, bound: &Bound, serialized: &str) -> Result<bool> {
+        let begin = bound.begin + SET_KEY_PREFIX.len();
+        let end = begin + serialized.len();
+        if end > bound.end {
+            return Ok(false);
+        }
+        match self.replaying {
+            Some((segment, buf)) if segment == bound.segment => {
+                Ok(&buf[begin..end] == serialized.as_bytes())
+            }
+            _ => {
+                let mut key = Vec::with_capacity(serialized.len());
+                self.storage
+                    .reader(bound.segment, begin as u64, serialized.len() as u64)?
+                    .read_to_end(&mut key)?;
+                Ok(key == serialized.as_bytes())
+            }
+        }
+    }
+}
+
+/// Storage is the storage a KvStore writes through, either directly, or buffered by a group
+/// commit
+enum Storage {
+    Direct(Box<dyn LogStorage>),
+    GroupCommit(GroupCommitStorage),
+}
+
+impl Storage {
+    /// the storage written through, flushing any buffered writes
+    fn into_inner(self) -> Result<Box<dyn LogStorage>> {
+        match self {
+            Storage::Direct(storage) => Ok(storage),
+            Storage::GroupCommit(storage) => storage.into_inner(),
+        }
+    }
+}
+
+impl Deref for Storage {
+    type Target = dyn LogStorage;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Storage::Direct(storage) => storage.as_ref(),
+            Storage::GroupCommit(storage) => storage,
+        }
+    }
+}
+
+impl DerefMut for Storage {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Storage::Direct(storage) => storage.as_mut(),
+            Storage::GroupCommit(storage) => storage,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 struct Bound {
+    // the sealed segment holding the record, None for the log
+    segment: Option<u64>,
     begin: usize,
     end: usize,
 }
 
+impl Bound {
+    /// bytes of the log within the bound, including the trailing newline
+    fn len(&self) -> u64 {
+        (self.end + 1 - self.begin) as u64
+    }
+}
+
 /// Total Order over (usize, usize), used to prepare buffer for
 /// draining
 impl Ord for Bound {
@@ -66,16 +455,240 @@ impl PartialOrd for Bound {
 /// maximum number of actions needed before log compaction
 const COMPACTION_SIZE: u64 = 10000;
 
+/// values of at most this many bytes are held inline by default, see KvStore::set_inline_threshold
+const INLINE_THRESHOLD: usize = 256;
+
+/// maximum number of changes returned by a single call to changes_since
+const CHANGES_BATCH: usize = 1024;
+
+/// serialized `{"Set":{"key":` preceding the key of a Set record in the log
+const SET_KEY_PREFIX: &str = "{\"Set\":{\"key\":";
+/// serialized `,"value":"` between the key and the value of a Set record in the log
+const SET_VALUE_PREFIX: &str = ",\"value\":\"";
+/// serialized `"}}` closing a Set record in the log
+const SET_SUFFIX: &str = "\"}}";
+
 /// CommandData is an enum representing the data that will ultimately
 /// be serialized and written to the logfile, the enum contains
-/// (rm, key, value)
+/// (rm, key, value), a tombstone, kept apart from a set of the empty value
 /// (set, key, value)
-/// (get, key, value)
-#[derive(Deserialize, Serialize, Debug)]
+/// (get, key, value), gets are no longer written, but are skipped in logs that hold them
+/// (access time, key, at), written by compaction for each key whose access is tracked
+/// every other command, i.e (sync), (clear), (append), (prepend), (len), (replicate), (rename),
+/// (stats), (stats reset), (scan), (info), (touch), (accessed), (commit offset), (history), is only
+/// sent from kvs-client to kvs-server and is never written to the log
+/// (pause), (resume) are admin commands, pausing and resuming the server's writes
+/// (idempotent, request id, command) wraps a command kvs-client may retry, kvs-server answers a
+/// retry with the response to the first attempt, rather than handling the command again
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum CommandData {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    /// set key to value
+    Set {
+        /// the key set
+        key: String,
+        /// the value key is set to
+        value: String,
+    },
+    /// get the value of key
+    Get {
+        /// the key read
+        key: String,
+    },
+    /// remove key, the tombstone of a removal in the log
+    Rm {
+        /// the key removed
+        key: String,
+    },
+    /// make every write before it durable
+    Sync,
+    /// remove every key
+    Clear,
+    /// append value to the value of key
+    Append {
+        /// the key appended to
+        key: String,
+        /// the suffix appended
+        value: String,
+    },
+    /// prepend value to the value of key
+    Prepend {
+        /// the key prepended to
+        key: String,
+        /// the prefix prepended
+        value: String,
+    },
+    /// the number of keys
+    Len,
+    /// stream the changes made from from_offset on, as a replica follows its primary
+    Replicate {
+        /// the log offset the changes are streamed from
+        from_offset: u64,
+    },
+    /// move the value of from to to
+    Rename {
+        /// the key the value is moved from
+        from: String,
+        /// the key the value is moved to
+        to: String,
+    },
+    /// the counters of the commands handled by kvs-server
+    Stats {
+        /// seconds the rate of commands is measured over
+        window: u64,
+    },
+    /// zero the counters of kvs-server
+    StatsReset,
+    /// a page of (key, value) pairs in key order
+    Scan {
+        /// the key the page starts at, None for the first page
+        start: Option<String>,
+        /// the most pairs in the page
+        limit: usize,
+    },
+    /// the build and configuration of kvs-server
+    Info,
+    /// mark key as accessed, without reading it
+    Touch {
+        /// the key marked
+        key: String,
+    },
+    /// when key was last accessed
+    Accessed {
+        /// the key looked up
+        key: String,
+    },
+    /// when key was last accessed, as written to the log by compaction
+    AccessTime {
+        /// the key accessed
+        key: String,
+        /// milliseconds since the unix epoch
+        at: u64,
+    },
+    /// the log offset every write before which is durable
+    CommitOffset,
+    /// the values key has been set to, newest first
+    History {
+        /// the key looked up
+        key: String,
+        /// the most values returned
+        limit: usize,
+    },
+    /// cmd, sent with request_id so a retry of it is not handled twice
+    Idempotent {
+        /// identifies the command across its retries
+        request_id: String,
+        /// the command wrapped
+        cmd: Box<CommandData>,
+    },
+    /// reject writes until Resume
+    Pause,
+    /// handle writes again after a Pause
+    Resume,
+}
+
+impl CommandData {
+    /// the tags of every variant, as serialized, a command whose tag is not one of these is not
+    /// known to this version, see protocol::read_command. A variant must be added here as it is
+    /// added to CommandData, along with a bump of protocol::PROTOCOL_VERSION
+    pub const TAGS: &'static [&'static str] = &[
+        "Set",
+        "Get",
+        "Rm",
+        "Sync",
+        "Clear",
+        "Append",
+        "Prepend",
+        "Len",
+        "Replicate",
+        "Rename",
+        "Stats",
+        "StatsReset",
+        "Scan",
+        "Info",
+        "Touch",
+        "Accessed",
+        "AccessTime",
+        "CommitOffset",
+        "History",
+        "Idempotent",
+        "Pause",
+        "Resume",
+    ];
+
+    /// the name of the command, as given to kvs-client
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandData::Set { .. } => "set",
+            CommandData::Get { .. } => "get",
+            CommandData::Rm { .. } => "rm",
+            CommandData::Sync => "sync",
+            CommandData::Clear => "clear",
+            CommandData::Append { .. } => "append",
+            CommandData::Prepend { .. } => "prepend",
+            CommandData::Len => "len",
+            CommandData::Replicate { .. } => "replicate",
+            CommandData::Rename { .. } => "rename",
+            CommandData::Stats { .. } => "stats",
+            CommandData::StatsReset => "stats reset",
+            CommandData::Scan { .. } => "scan",
+            CommandData::Info => "info",
+            CommandData::Touch { .. } => "touch",
+            CommandData::Accessed { .. } => "accessed",
+            CommandData::AccessTime { .. } => "access time",
+            CommandData::CommitOffset => "commit offset",
+            CommandData::History { .. } => "history",
+            // an idempotent command is described by the command it wraps
+            CommandData::Idempotent { cmd, .. } => cmd.name(),
+            CommandData::Pause => "pause",
+            CommandData::Resume => "resume",
+        }
+    }
+
+    /// whether the command changes the (key, value) pairs in the store
+    pub fn is_write(&self) -> bool {
+        match self {
+            CommandData::Idempotent { cmd, .. } => cmd.is_write(),
+            cmd => matches!(
+                cmd,
+                CommandData::Set { .. }
+                    | CommandData::Rm { .. }
+                    | CommandData::Clear
+                    | CommandData::Append { .. }
+                    | CommandData::Prepend { .. }
+                    | CommandData::Rename { .. }
+            ),
+        }
+    }
+
+    /// whether the command only reads the (key, value) pairs in the store, so it may be answered
+    /// by a replica
+    pub fn is_read(&self) -> bool {
+        match self {
+            CommandData::Idempotent { cmd, .. } => cmd.is_read(),
+            cmd => matches!(
+                cmd,
+                CommandData::Get { .. } | CommandData::Len | CommandData::Scan { .. }
+            ),
+        }
+    }
+
+    /// the key the command operates on, the source of a rename, None for commands without a key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CommandData::Set { key, .. }
+            | CommandData::Get { key }
+            | CommandData::Rm { key }
+            | CommandData::Append { key, .. }
+            | CommandData::Prepend { key, .. }
+            | CommandData::Touch { key }
+            | CommandData::Accessed { key }
+            | CommandData::AccessTime { key, .. }
+            | CommandData::History { key, .. }
+            | CommandData::Rename { from: key, .. } => Some(key),
+            CommandData::Idempotent { cmd, .. } => cmd.key(),
+            _ => None,
+        }
+    }
 }
 
 impl KvStore {
@@ -83,20 +696,266 @@ impl KvStore {
     /// with the given path passed as argument
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         // create log file, in given dir
-        let log_path = Path::new(&path.into()).join("log");
-        // open file with given path, (write permissions must be given if creating file)
-        File::options().create(true).write(true).open(&log_path)?;
-        // return a KvStore at the path provided
-        Ok(KvStore {
-            map: HashMap::new(),
-            file: log_path,
+        Self::with_storage(FileStorage::open(path)?)
+    }
+
+    /// Instantiate a KvStore over the given storage, i.e a StreamStorage over a stream in memory,
+    /// the log and any sealed segments already in storage are read
+    pub fn with_storage(storage: impl LogStorage) -> Result<KvStore> {
+        // find the segments sealed by a size-tiered store
+        let mut segments = storage.segments()?;
+        segments.sort_unstable();
+        // return a KvStore over the storage provided
+        let state = LogState {
+            index: Index::new(None),
+            inline_threshold: INLINE_THRESHOLD,
+            storage: Storage::Direct(Box::new(storage)),
             dirty: true,
             actions: 0,
-            log_pointers: HashMap::new(),
+            compacted: 0,
+            live: 0,
+            key_policy: KeyPolicy::default(),
+            value_format: ValueFormat::default(),
+            segments,
+            compaction: CompactionOptions::default(),
+            written: 0,
+            durability: Durability::default(),
+            accessed: None,
+        };
+        Ok(KvStore {
+            state: Arc::new(RwLock::new(state)),
         })
     }
 
-    /// read_log reads the current log file, and updates the key to log pointer indices
+    /// set_durability sets when writes are made durable from now on, see Durability, writes
+    /// buffered under a previous GroupCommit are flushed first
+    /// # Errors
+    /// a GroupCommit with a zero interval, or an error flushing the buffered writes
+    pub fn set_durability(&self, durability: Durability) -> Result<()> {
+        if let Durability::GroupCommit { interval, .. } = durability {
+            if interval.is_zero() {
+                return Err("group commit interval must be greater than 0".into());
+            }
+        }
+        let mut state = self.state.write();
+        // flush the buffered writes while the storage is still in place, so a failed flush leaves
+        // it as it was, and taking it out below has nothing left to flush
+        if let Storage::GroupCommit(storage) = &mut state.storage {
+            storage.sync()?;
+        }
+        // the storage is moved out to be rewrapped, leave an empty one in its place meanwhile
+        let placeholder = Storage::Direct(Box::new(StreamStorage::in_memory()));
+        let storage = std::mem::replace(&mut state.storage, placeholder).into_inner()?;
+        state.storage = match durability {
+            Durability::GroupCommit {
+                interval,
+                max_bytes,
+            } => Storage::GroupCommit(GroupCommitStorage::new(storage, interval, max_bytes)),
+            _ => Storage::Direct(storage),
+        };
+        state.durability = durability;
+        Ok(())
+    }
+
+    /// set_compaction_options sets how the log is compacted from now on, see CompactionOptions,
+    /// segments sealed under a previous strategy are still read, and are merged into the log by
+    /// a FullRewrite
+    /// # Errors
+    /// a SizeTiered strategy with a fanout under 2, a run of one segment would only be merged into
+    /// itself
+    pub fn set_compaction_options(&self, options: CompactionOptions) -> Result<()> {
+        if let CompactionStrategy::SizeTiered { fanout: 0..=1 } = options.strategy {
+            return Err("compaction fanout must be at least 2".into());
+        }
+        self.state.write().compaction = options;
+        Ok(())
+    }
+
+    /// set_inline_threshold sets the largest value held inline, in bytes, larger values are only
+    /// held in the log, and are read from it by every get. The values are re-indexed on the next
+    /// read
+    pub fn set_inline_threshold(&self, threshold: usize) {
+        let mut state = self.state.write();
+        state.inline_threshold = threshold;
+        state.dirty = true;
+    }
+
+    /// set_access_tracking sets whether the store tracks when each key was last accessed, by a
+    /// set, get, or touch, see KvsEngine::last_accessed. The access times are held in memory, and
+    /// only written to the log by compaction, so reads add no writes to the log. Access times
+    /// written by a previous compaction are read on the next read of the log, keys not accessed
+    /// since then have no access time. Tracking is disabled by default, disabling it discards
+    /// the access times, and the next compaction drops them from the log
+    /// # Errors
+    /// KvsError::Unsupported - tracking is enabled while keys are hashed, the access times are
+    /// held by key, which would defeat the hashing, see KvStore::set_key_hashing
+    pub fn set_access_tracking(&self, enabled: bool) -> Result<()> {
+        let mut state = self.state.write();
+        if enabled == state.accessed.is_some() {
+            return Ok(());
+        }
+        if enabled && matches!(state.index, Index::Hashed { .. }) {
+            return Err(Box::from(KvsError::Unsupported {
+                operation: "access tracking with hashed keys".to_owned(),
+            }));
+        }
+        state.accessed = enabled.then(HashMap::new);
+        state.dirty = true;
+        Ok(())
+    }
+
+    /// set_key_hashing sets whether the index holds a 64 bit hash of each key rather than the key
+    /// itself, the full key is then only held in the log. This bounds the memory held per key,
+    /// whatever the length of the keys, at the cost of CPU and reads of the log:
+    /// - every lookup hashes the key
+    /// - keys whose hashes collide share a slot, a lookup of one of them reads the key from the
+    ///   record of each key in the slot until one matches, so a collision costs a read of the log,
+    ///   not a wrong value. With a 64 bit hash collisions are rare until there are billions of
+    ///   keys
+    /// - writes to a key whose hash is already held read the key of the held record, to tell an
+    ///   overwrite from a collision, so overwrites and replaying the log read the log
+    /// - scans are not supported, the keys are not held in order
+    /// - access tracking is not supported, it holds every key
+    ///
+    /// Values of at most the inline threshold are still held in memory, see
+    /// KvStore::set_inline_threshold. Keys are hashed with a randomly seeded SipHash, hashes are
+    /// never written to the log, so the log is the same whether keys are hashed or not. Hashing is
+    /// disabled by default, changing it re-indexes the store on the next read
+    /// # Errors
+    /// KvsError::Unsupported - hashing is enabled while access tracking is enabled
+    pub fn set_key_hashing(&self, enabled: bool) -> Result<()> {
+        match enabled {
+            true => self.set_key_hasher(RandomState::new()),
+            false => self.set_index(None),
+        }
+    }
+
+    /// set_key_hasher enables key hashing as set_key_hashing does, hashing keys with hasher
+    /// # Errors
+    /// see KvStore::set_key_hashing
+    pub fn set_key_hasher<S>(&self, hasher: S) -> Result<()>
+    where
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        self.set_index(Some(Box::new(move |key: &str| hasher.hash_one(key))))
+    }
+
+    /// replace the index with an empty one, hashing keys with hasher if given, the log is
+    /// re-indexed on the next read
+    fn set_index(&self, hasher: Option<KeyHasher>) -> Result<()> {
+        let mut state = self.state.write();
+        if hasher.is_some() && state.accessed.is_some() {
+            return Err(Box::from(KvsError::Unsupported {
+                operation: "key hashing with access tracking".to_owned(),
+            }));
+        }
+        state.index = Index::new(hasher);
+        state.dirty = true;
+        Ok(())
+    }
+
+    /// bytes written to the log and its segments since the store was opened, by writes and by
+    /// compaction, comparing this to the bytes of the records written gives the write
+    /// amplification of the compaction strategy
+    pub fn bytes_written(&self) -> u64 {
+        self.state.read().written
+    }
+
+    /// stream_changes_since reads the log from offset, yielding every mutation (set / rm) recorded
+    /// at or after offset, along with the offset of the record that follows it, so a reader may
+    /// resume from there. Reads are not yielded, and the stream ends at the current end of the log
+    /// Offsets are positions in the log, compaction and clear rewrite the log, and a size-tiered
+    /// store seals the log into a segment, so an offset taken before any of these no longer points
+    /// at a record
+    /// The stream holds a read lock on the store, writes wait until it is dropped
+    /// # Errors
+    /// offset is past the end of the log
+    pub fn stream_changes_since(&self, offset: u64) -> Result<ChangeStream> {
+        let state = self.state.read_arc();
+        let len = state.storage.len(None)?;
+        if offset > len {
+            return Err(Box::from(format!(
+                "offset {} is past the end of the {} byte log",
+                offset, len
+            )));
+        }
+        let reader = state.storage.reader(None, offset, len - offset)?;
+        Ok(ChangeStream {
+            reader: BufReader::new(Box::new(LockedReader {
+                reader,
+                _state: state,
+            })),
+            offset,
+        })
+    }
+
+    /// compact, updates the log file, to only contain gets / sets from previous state
+    /// This form of compaction, retains the latest state for reads / writes, every sealed
+    /// segment is merged into the log
+    /// Compaction is run automatically as the log grows, this forces it regardless of log size
+    pub fn compact(&self) -> Result<()> {
+        self.compact_with(CompactOpts::default()).map(|_| ())
+    }
+
+    /// compact_with compacts the store as compact does, and reports the space the compaction
+    /// reclaimed, with dry_run nothing is rewritten, the space a compaction would reclaim is
+    /// reported, and readers are not blocked
+    pub fn compact_with(&self, opts: CompactOpts) -> Result<CompactionReport> {
+        if opts.dry_run {
+            return self.read_state()?.compaction_report();
+        }
+        let mut state = self.write_state()?;
+        let report = state.compaction_report()?;
+        state.compact()?;
+        Ok(report)
+    }
+
+    /// a read lock on the state, the log is read first if the state is dirty
+    fn read_state(&self) -> Result<ArcRwLockReadGuard<RawRwLock, LogState>> {
+        let state = self.state.read_arc();
+        if !state.dirty {
+            return Ok(state);
+        }
+        drop(state);
+        let mut state = self.state.write_arc();
+        state.read_log()?;
+        Ok(ArcRwLockWriteGuard::downgrade(state))
+    }
+
+    /// a write lock on the state, the log is read first if the state is dirty
+    fn write_state(&self) -> Result<RwLockWriteGuard<'_, LogState>> {
+        let mut state = self.state.write();
+        state.read_log()?;
+        Ok(state)
+    }
+}
+
+/// LockedReader holds a read lock on a KvStore while reading from its log, so the log is not
+/// compacted under it
+struct LockedReader<R> {
+    reader: R,
+    _state: ArcRwLockReadGuard<RawRwLock, LogState>,
+}
+
+impl<R: Read> Read for LockedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl LogState {
+    /// every sealed segment, oldest first, followed by the log
+    fn all_segments(&self) -> Vec<Option<u64>> {
+        self.segments
+            .iter()
+            .copied()
+            .map(Some)
+            .chain(iter::once(None))
+            .collect()
+    }
+
+    /// read_log reads the sealed segments, oldest first, then the current log file, and updates
+    /// the index
     /// this is only called when the state is dirty, i.e, the cache does not reflect the
     /// log
     /// The dirtiness of the state is set to false after this read
@@ -108,16 +967,32 @@ impl KvStore {
         if !self.dirty {
             return Ok(());
         }
-        // create buffer to hold file contents
-        let mut vec = Vec::<u8>::new();
-        // open file
-        File::options()
-            .read(true)
-            .open(&self.file)
-            // Box err if it exists
-            .map_err(|err| Into::<Box<dyn Error>>::into(err))?
-            // read log contents to buffer, return Boxed error if needed
-            .read_to_end(&mut vec)?;
+        // the log is replayed from the start, discard the stale state
+        self.index.clear();
+        self.live = 0;
+        for segment in self.all_segments() {
+            self.replay(segment)?;
+        }
+        // access times held in memory outlive the replay, only the keys that are gone are
+        // dropped, keys without an access time in the log or in memory have not been accessed
+        // keys are never hashed while access is tracked
+        if let (Some(accessed), Index::Keys(keys)) = (&mut self.accessed, &self.index) {
+            accessed.retain(|key, _| keys.contains_key(key));
+            for key in keys.keys() {
+                if !accessed.contains_key(key) {
+                    accessed.insert(key.clone(), AtomicU64::new(0));
+                }
+            }
+        }
+        // state is not dirty any more
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// replay the records of segment into the index
+    fn replay(&mut self, segment: Option<u64>) -> Result<()> {
+        // read log contents to buffer, return Boxed error if needed
+        let vec = self.storage.read(segment)?;
         // now data from buffer and return log pointer of most recent recording
         let (mut begin, mut end) = (0, 0);
         vec.iter()
@@ -130,22 +1005,32 @@ impl KvStore {
                     match cmd {
                         // update key from set
                         CommandData::Set { key, value: val } => {
-                            // set cached state
-                            self.map.insert(key.clone(), val);
-                            // write to log_pointers for result
-                            self.log_pointers.insert(
-                                key,
-                                Bound {
+                            // set cached state, pointing at the record
+                            let entry = Entry {
+                                value: self.index_value(val),
+                                bound: Bound {
+                                    segment,
                                     begin,
                                     end: end - 1,
                                 },
-                            );
+                            };
+                            self.insert_entry(key, entry, Some((segment, &vec)))?;
                         }
-                        // remove key from map in Rm
+                        // remove key from the index in Rm
                         CommandData::Rm { key, .. } => {
-                            self.map.remove(&key);
-                            // remove key from log_pointers
-                            self.log_pointers.remove(&key);
+                            self.remove_entry(&key, Some((segment, &vec)))?;
+                        }
+                        // access times of live keys are kept, and rewritten by compaction, so
+                        // they count as live, a time in memory is never older than the log's
+                        CommandData::AccessTime { key, at } => {
+                            let live = self.accessed.is_some() && self.entry(&key)?.is_some();
+                            if let (Some(accessed), true) = (&mut self.accessed, live) {
+                                self.live += (end - begin) as u64;
+                                accessed
+                                    .entry(key)
+                                    .or_insert_with(|| AtomicU64::new(0))
+                                    .fetch_max(at, atomic::Ordering::Relaxed);
+                            }
                         }
                         // reads do not affect state
                         _ => (),
@@ -153,96 +1038,448 @@ impl KvStore {
                     // update begin to end +1
                     begin = end;
                 }
-                // state is not dirty any more
-                self.dirty = false;
                 Ok(())
             })
             .collect()
     }
 
-    /// compact, updates the log file, to only contain gets / sets from previous state
-    /// This form of compaction, retains the latest state for reads / writes
+    /// compact_log compacts the log once it has reached COMPACTION_SIZE, according to the
+    /// compaction strategy
+    /// FullRewrite - the log is rewritten once it is at least twice its size after the last
+    /// rewrite, every sealed segment is merged into it
+    /// SizeTiered - the log is sealed, and the oldest run of fanout segments of a tier is merged
+    /// Disabled - the log is left as it is
     fn compact_log(&mut self) -> Result<()> {
         // only compact state once the log has reached comaption size
         if self.actions < COMPACTION_SIZE {
             return Ok(());
         }
+        match self.compaction.strategy {
+            // otherwise every write to a store holding over COMPACTION_SIZE of live records would
+            // rewrite all of them
+            CompactionStrategy::FullRewrite if self.actions < 2 * self.compacted => Ok(()),
+            CompactionStrategy::FullRewrite => self.compact(),
+            CompactionStrategy::SizeTiered { fanout } => {
+                self.seal()?;
+                match self.tier_run(fanout)? {
+                    Some(run) => self.merge(&run),
+                    None => Ok(()),
+                }
+            }
+            CompactionStrategy::Disabled => Ok(()),
+        }
+    }
+
+    /// the oldest run of fanout adjacent sealed segments of the same tier, see
+    /// CompactionStrategy::SizeTiered, None if no tier has fanout adjacent segments
+    fn tier_run(&self, fanout: usize) -> Result<Option<Vec<Option<u64>>>> {
+        let tiers = self
+            .segments
+            .iter()
+            .map(|&id| {
+                let len = self.storage.len(Some(id))?;
+                let mut tier = 0;
+                let mut bound = COMPACTION_SIZE.saturating_mul(fanout as u64);
+                while len >= bound {
+                    tier += 1;
+                    bound = bound.saturating_mul(fanout as u64);
+                }
+                Ok(tier)
+            })
+            .collect::<Result<Vec<u32>>>()?;
+        let start = tiers
+            .windows(fanout)
+            .position(|run| run.iter().all(|&tier| tier == run[0]));
+        Ok(start.map(|start| {
+            self.segments[start..start + fanout]
+                .iter()
+                .copied()
+                .map(Some)
+                .collect()
+        }))
+    }
+
+    /// seal renames the log into a new segment, newer than every other segment, and starts an
+    /// empty log, the records do not move so the index only needs their segment updated
+    fn seal(&mut self) -> Result<()> {
+        let id = self.segments.last().map_or(1, |id| id + 1);
+        self.storage.seal(id)?;
+        self.segments.push(id);
+        for entry in self.index.entries_mut() {
+            if entry.bound.segment.is_none() {
+                entry.bound.segment = Some(id);
+            }
+        }
+        self.actions = 0;
+        Ok(())
+    }
+
+    /// value as held by the index, inline if it is at most inline_threshold bytes
+    fn index_value(&self, value: String) -> Value {
+        if value.len() <= self.inline_threshold {
+            Value::Inline(value)
+        } else {
+            Value::InLog(value.len() as u64)
+        }
+    }
+
+    /// mark key as accessed now, if access tracking is enabled
+    fn touch(&self, key: &str) {
+        if let Some(at) = self
+            .accessed
+            .as_ref()
+            .and_then(|accessed| accessed.get(key))
+        {
+            at.store(now_millis(), atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// the records of the log, read from storage
+    fn records(&self) -> Records<'_> {
+        Records {
+            storage: &*self.storage,
+            replaying: None,
+        }
+    }
+
+    /// the index entry of key
+    fn entry(&self, key: &str) -> Result<Option<&Entry>> {
+        self.index.get(key, &self.records())
+    }
+
+    /// the value of key, read from its record in the log unless it is held inline
+    fn value(&self, key: &str) -> Result<Option<String>> {
+        match self.entry(key)? {
+            Some(entry) => self.entry_value(key, entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// the value of entry, the entry of key
+    fn entry_value(&self, key: &str, entry: &Entry) -> Result<String> {
+        match &entry.value {
+            Value::Inline(val) => Ok(val.clone()),
+            Value::InLog(len) => {
+                let mut val = String::with_capacity(*len as usize);
+                self.value_reader(key, &entry.bound)?
+                    .read_to_string(&mut val)?;
+                Ok(val)
+            }
+        }
+    }
+
+    /// a reader decoding the value of key directly from its Set record in the log, within bound
+    fn value_reader(&self, key: &str, bound: &Bound) -> Result<Box<dyn Read + Send>> {
+        // the escaped value sits between the serialized key and the end of the record
+        let begin = bound.begin
+            + SET_KEY_PREFIX.len()
+            + serde_json::to_string(key)?.len()
+            + SET_VALUE_PREFIX.len();
+        let end = bound.end - SET_SUFFIX.len();
+        let reader = self
+            .storage
+            .reader(bound.segment, begin as u64, (end - begin) as u64)?;
+        Ok(Box::new(JsonStrReader::new(BufReader::new(reader))))
+    }
+
+    /// compaction_report describes the space a compaction of every segment would reclaim, the
+    /// log must have been read
+    fn compaction_report(&self) -> Result<CompactionReport> {
+        let total_bytes = self
+            .all_segments()
+            .into_iter()
+            .map(|segment| self.storage.len(segment))
+            .sum::<Result<u64>>()?;
+        Ok(CompactionReport {
+            total_bytes,
+            live_bytes: self.live,
+            reclaimable_bytes: total_bytes.saturating_sub(self.live),
+            entries: self.index.len(),
+        })
+    }
+
+    /// index key at entry, keeping the count of live bytes, replaying is the segment being
+    /// replayed and its bytes, if any, see Records
+    fn insert_entry(
+        &mut self,
+        key: String,
+        entry: Entry,
+        replaying: Option<(Option<u64>, &[u8])>,
+    ) -> Result<()> {
+        let records = Records {
+            storage: &*self.storage,
+            replaying,
+        };
+        self.live += entry.bound.len();
+        if let Some(old) = self.index.insert(key, entry, &records)? {
+            self.live -= old.bound.len();
+        }
+        Ok(())
+    }
+
+    /// remove the entry of key, keeping the count of live bytes, see insert_entry
+    fn remove_entry(&mut self, key: &str, replaying: Option<(Option<u64>, &[u8])>) -> Result<()> {
+        let records = Records {
+            storage: &*self.storage,
+            replaying,
+        };
+        if let Some(old) = self.index.remove(key, &records)? {
+            self.live -= old.bound.len();
+        }
+        Ok(())
+    }
+
+    /// merge every sealed segment into the log, see KvStore::compact
+    fn compact(&mut self) -> Result<()> {
+        let run = self.all_segments();
+        self.merge(&run)
+    }
+
+    /// merge rewrites run, a sequence of segments oldest first, into its last segment, keeping
+    /// only the records the index points at, the rest of the run is removed
+    /// if access tracking is enabled, the access times of the keys kept are written after them
+    /// a removed key set in the rest of the run keeps a removal in the target, as the rest of the
+    /// run is only removed once the target is written, and a crash in between would otherwise
+    /// revive the set, other removals are dropped if the run begins with the oldest segment. A run
+    /// that begins later keeps the removal of every removed key it holds, as a set in an older
+    /// segment would otherwise be revived
+    fn merge(&mut self, run: &[Option<u64>]) -> Result<()> {
+        let (&target, merged) = match run.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
         // initialize temporary buffer to make writes to
         let mut buf = Vec::<u8>::new();
-        // if state is dirty, clean it
-        if self.dirty {
-            self.read_log()?;
-        }
-        // most updated state is cached, iterate over it and
-        // write the serialized data to buffer
-        File::options()
-            .read(true)
-            .open(&self.file)
-            // Box err if it exists
-            .map_err(|err| Into::<Box<dyn Error>>::into(err))?
-            // read log contents to buffer, return Boxed error if needed
-            .read_to_end(&mut buf)?;
-        // data is read into buf, drain un-needed elements
-        let (mut begin, mut end, mut drain_size) = (0, 0, 0);
-        // sort the log_pointers so we are draining contiguous sections of un-needed space from vec
-        // collect values of bound into vec
-        let mut bounds = self.log_pointers.values().cloned().collect::<Vec<Bound>>();
-        bounds.sort();
-        // drain un-needed elements from buf
-        for bound in bounds.iter() {
-            // can remove new-line
-            end = bound.begin - drain_size;
-            // drain contents including ending new-line of erased entry
-            buf.drain(begin..end);
-            // adjust indices to the drained vec
-            drain_size += end - begin;
-            // keep trailing newline
-            begin = bound.end + 1 - drain_size;
-        }
-        // finally drain from end if needed
-        buf.drain(begin..buf.len());
+        // removed keys set in the rest of the run, kept as removals in the target
+        let mut removed = BTreeSet::new();
+        let from_oldest = run.first() == self.all_segments().first();
+        // where each record kept lands in the target, by the segment and offset it is moved from
+        let mut moved = HashMap::new();
+        // writes keep the index current, it is only re-read if it has been invalidated
+        self.read_log()?;
+        for &segment in run {
+            // most updated state is cached, iterate over it and
+            // write the serialized data to buffer
+            let mut segment_buf = self.storage.read(segment)?;
+            if segment != target || !from_oldest {
+                for line in segment_buf.split(|&byte| byte == b'\n') {
+                    let key = match serde_json::from_slice(line) {
+                        Ok(CommandData::Set { key, .. }) => key,
+                        Ok(CommandData::Rm { key }) if !from_oldest => key,
+                        _ => continue,
+                    };
+                    if self.entry(&key)?.is_none() {
+                        removed.insert(key);
+                    }
+                }
+            }
+            // data is read into buf, drain un-needed elements
+            // collect values of bound into vec
+            let mut bounds: Vec<_> = self
+                .index
+                .entries()
+                .map(|entry| &entry.bound)
+                .filter(|bound| bound.segment == segment)
+                .cloned()
+                .collect();
+            // the records kept are appended to buf in order, without the bytes between them
+            bounds.sort();
+            let mut begin = buf.len();
+            for bound in &bounds {
+                moved.insert((segment, bound.begin), begin);
+                begin += bound.len() as usize;
+            }
+            drain_stale(&mut segment_buf, bounds)?;
+            buf.append(&mut segment_buf);
+        }
+        for key in removed {
+            serde_json::to_writer(&mut buf, &CommandData::Rm { key })?;
+            buf.push(b'\n');
+        }
+        // keys are never hashed while access is tracked
+        if let (Some(accessed), Index::Keys(keys)) = (&self.accessed, &self.index) {
+            for (key, Entry { bound, .. }) in keys.iter() {
+                let at = accessed[key].load(atomic::Ordering::Relaxed);
+                if at == 0 || !run.contains(&bound.segment) {
+                    continue;
+                }
+                let record = CommandData::AccessTime {
+                    key: key.to_owned(),
+                    at,
+                };
+                serde_json::to_writer(&mut buf, &record)?;
+                buf.push(b'\n');
+            }
+        }
         // finally, write buf
-        // buf is drained of the un-needed sections, truncate original contents of file, and
-        // write new buffer
-        File::options()
-            .write(true)
-            .truncate(true)
-            .open(&self.file)
-            // return file Opening err if it exists
-            .map_err(Into::<Box<dyn Error>>::into)?
-            .write(&buf)
-            .map_err(Into::<Box<dyn Error>>::into)?;
+        // buf is drained of the un-needed sections, replace original contents of the segment
+        // with the new buffer
+        self.storage.write(target, &buf)?;
+        self.written += buf.len() as u64;
+        if target.is_none() {
+            self.compacted = buf.len() as u64;
+        }
+        // the live records of the rest of the run are in the target, remove them, only the
+        // target may be the log
+        for id in merged.iter().flatten() {
+            self.storage.remove(*id)?;
+        }
+        self.segments.retain(|&id| !merged.contains(&Some(id)));
+        // the records have moved, point the index at them in the target
+        for entry in self.index.entries_mut() {
+            let bound = &mut entry.bound;
+            if let Some(&begin) = moved.get(&(bound.segment, bound.begin)) {
+                *bound = Bound {
+                    segment: target,
+                    begin,
+                    end: begin + bound.len() as usize - 1,
+                };
+            }
+        }
+        // the access times counted as live are only recounted by a replay of the log
+        if self.accessed.is_some() {
+            self.dirty = true;
+        }
         Ok(())
     }
 
     /// write log appends the given log entry to the logfile, determined by command type
+    /// the index is updated with the new record, so it remains
+    /// accurate without re-reading the log
     /// #Errors
     ///    Resulting from OS / Serialization of CommandData
     /// After a successful write to log, the log is compacted to reduce Filesystem overhead
     fn write_log(&mut self, data: CommandData) -> Result<()> {
-        File::options()
-            .write(true)
-            .append(true)
-            .open(&self.file)
-            // if opening the file resulted in an error, Box it
-            .map_err(Into::<Box<dyn Error>>::into)
-            // file exists, now write the serialized data to it
-            .and_then(|mut file| {
-                // ok the file is opened, lets first serialize CommandData::Set
-                // update the number of actions taken
-                self.actions = file.metadata()?.len();
-                serde_json::to_string(&data)
-                    // in case of error map err to Box<dyn Error>
-                    .map_err(|err| Box::from(err))
-                    // write the serialized data to file
-                    .and_then(|serial| writeln!(file, "{}", serial).map_err(Box::from))
-            })
-            // this method returns Ok(())
-            .map(|_| ())?;
+        self.write_logs(vec![data])
+    }
+
+    /// write logs appends the given log entries to the logfile in a single write, so they land in
+    /// the log together, see write_log
+    fn write_logs(&mut self, records: Vec<CommandData>) -> Result<()> {
+        // the records are appended at the current end of the log
+        // update the number of actions taken
+        self.actions = self.storage.len(None)?;
+        // lets first serialize CommandData, and append it to the log
+        let serials = records
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let buf: String = serials
+            .iter()
+            .map(|serial| format!("{}\n", serial))
+            .collect();
+        self.storage.append(buf.as_bytes())?;
+        if self.durability == Durability::EveryWrite {
+            self.storage.sync()?;
+        }
+        self.written += buf.len() as u64;
+        // the records are in the log, update the cached state
+        let mut begin = self.actions as usize;
+        for (data, serial) in records.into_iter().zip(serials) {
+            match data {
+                CommandData::Set { key, value } => {
+                    if let Some(accessed) = &mut self.accessed {
+                        accessed.insert(key.clone(), AtomicU64::new(now_millis()));
+                    }
+                    let entry = Entry {
+                        value: self.index_value(value),
+                        bound: Bound {
+                            segment: None,
+                            begin,
+                            end: begin + serial.len(),
+                        },
+                    };
+                    self.insert_entry(key, entry, None)?;
+                }
+                CommandData::Rm { key } => {
+                    if let Some(accessed) = &mut self.accessed {
+                        accessed.remove(&key);
+                    }
+                    self.remove_entry(&key, None)?;
+                }
+                // reads do not affect state
+                _ => (),
+            }
+            begin += serial.len() + 1;
+        }
         // compact log
         self.compact_log()
     }
 }
+/// drain_stale drains every byte of buf that is not covered by one of bounds, keeping the
+/// trailing newline of each bound, so only the records bounds point at remain
+/// # Errors
+/// KvsError::CompactionState - the bounds are inverted, overlap, or point past the end of buf,
+/// buf is left untouched
+fn drain_stale(buf: &mut Vec<u8>, mut bounds: Vec<Bound>) -> Result<()> {
+    // sort the bounds so we are draining contiguous sections of un-needed space from vec
+    bounds.sort();
+    // validate every bound before draining, so a violation never leaves buf half-drained
+    let mut next_begin = 0;
+    for bound in bounds.iter() {
+        if bound.end < bound.begin {
+            return Err(compaction_state(format!(
+                "{:?} ends before it begins",
+                bound
+            )));
+        }
+        if bound.begin < next_begin {
+            return Err(compaction_state(format!(
+                "{:?} overlaps the previous record",
+                bound
+            )));
+        }
+        if bound.end >= buf.len() {
+            return Err(compaction_state(format!(
+                "{:?} points past the end of a {} byte log",
+                bound,
+                buf.len()
+            )));
+        }
+        next_begin = bound.end + 1;
+    }
+    let (mut begin, mut drain_size) = (0usize, 0usize);
+    // drain un-needed elements from buf
+    for bound in bounds.iter() {
+        // can remove new-line
+        let end = bound
+            .begin
+            .checked_sub(drain_size)
+            .ok_or_else(|| compaction_state(format!("{:?} precedes drained bytes", bound)))?;
+        // drain contents including ending new-line of erased entry
+        let size = end
+            .checked_sub(begin)
+            .ok_or_else(|| compaction_state(format!("{:?} precedes the previous record", bound)))?;
+        buf.drain(begin..end);
+        // adjust indices to the drained vec
+        drain_size += size;
+        // keep trailing newline
+        begin = (bound.end + 1)
+            .checked_sub(drain_size)
+            .ok_or_else(|| compaction_state(format!("{:?} precedes drained bytes", bound)))?;
+    }
+    // finally drain from end if needed
+    buf.drain(begin..);
+    Ok(())
+}
+
+/// box a KvsError::CompactionState with the given reason
+fn compaction_state(reason: String) -> Box<dyn Error> {
+    Box::from(KvsError::CompactionState { reason })
+}
+
+/// the error returned for an access time while access tracking is not enabled
+fn untracked() -> Box<dyn Error> {
+    Box::from("access tracking is not enabled")
+}
+
+/// milliseconds since the unix epoch, access times are kept in these
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
 impl KvsEngine for KvStore {
     /// Inserts a (key, value) pair into map
     /// serialized set, key, value
@@ -253,38 +1490,58 @@ impl KvsEngine for KvStore {
     /// It then appends the serialized command to a file containing the log
     /// If that succeeds, it exits silently with error code 0
     /// If it fails, it exits by printing the error and returning a non-zero error code
-    fn set(&mut self, key: String, val: String) -> Result<()> {
-        self.write_log(CommandData::Set { key, value: val })
-            .map(|_| {
-                // update actions after write is successful
-                self.dirty = true;
-                ()
-            })
+    fn set(&self, key: String, val: String) -> Result<()> {
+        let mut state = self.state.write();
+        state.key_policy.check(&key)?;
+        state.value_format.check(val.as_bytes())?;
+        state.write_log(CommandData::Set { key, value: val })
     }
 
-    /// Gets a value associated with the key in KvStore.map
+    /// Gets a value associated with the key
     /// returns None if the key does not exist
-    /// clones the string from the map if it exists
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    /// clones the value if it is held inline, otherwise reads it from the log, only a read lock
+    /// is taken, so gets do not block one another
+    fn get(&self, key: String) -> Result<Option<String>> {
         // read the logs
-        self.read_log()?;
-        // get value from map, return ErrKeyNotFound if the key DNE,
-        let val = self.map.get(&key).map(|x| x.to_owned());
-        if let None = val {
-            // return the error if the key is not found
-            return Ok(None);
-        }
-        // key is found, write to log
-        self.write_log(CommandData::Get { key }).map(|_| {
-            // can panic here as we have exhausted earlier check
-            Some(val.unwrap())
-        })
+        let state = self.read_state()?;
+        state.key_policy.check(&key)?;
+        let value = state.value(&key)?;
+        state.touch(&key);
+        Ok(value)
+    }
+
+    /// Gets a reader over the value associated with the key, a value held inline is read from
+    /// memory, larger values are decoded directly from their Set record in the log. The record is
+    /// opened under a read lock, which is released before returning, a storage's readers outlive
+    /// later writes, so a slow reader holds up neither writes nor compaction
+    /// returns None if the key does not exist
+    fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        // read the logs
+        let state = self.read_state()?;
+        state.key_policy.check(&key)?;
+        state.touch(&key);
+        let (len, reader) = match state.entry(&key)? {
+            Some(Entry {
+                value: Value::Inline(val),
+                ..
+            }) => {
+                return Ok(Some(ValueStream {
+                    len: val.len() as u64,
+                    reader: Box::new(Cursor::new(val.clone().into_bytes())),
+                }))
+            }
+            Some(Entry {
+                value: Value::InLog(len),
+                bound,
+            }) => (*len, state.value_reader(&key, bound)?),
+            None => return Ok(None),
+        };
+        Ok(Some(ValueStream { len, reader }))
     }
 
-    /// Remves the value associated with the key in KvStore.map
+    /// Remves the value associated with the key
     /// if the key has no value, this is a no-op
-    /// The cached (key, value) pairs will not reflect log state after this routine
-    /// As such, future reads will read directly from log, and will cache results
+    /// The cached (key, value) pairs are updated along with the log
     // The user invokes kvs rm mykey
     // Same as the "get" command, kvs reads the entire log to build the in-memory index
     // It then checks the map if the given key exists
@@ -293,18 +1550,433 @@ impl KvsEngine for KvStore {
     // It creates a value representing the "rm" command, containing its key
     // It then appends the serialized command to the log
     // If that succeeds, it exits silently with error code 0
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<()> {
         // update hashmap from log
-        self.read_log()?;
-        // remove value from hashmap
-        if let None = self.map.remove(&key) {
+        let mut state = self.write_state()?;
+        state.key_policy.check(&key)?;
+        // check the key exists
+        if state.entry(&key)?.is_none() {
             // return error if the key is not found
             return Err(Into::<Box<dyn Error>>::into(ErrKeyNotFound { key }));
         }
-        // write command to log
-        self.write_log(CommandData::Rm { key }).and_then(|_| {
-            self.dirty = true;
-            Ok(())
-        })
+        // write command to log, this removes the value from the index
+        state.write_log(CommandData::Rm { key })
+    }
+
+    /// fsync the log and its sealed segments, every record written so far is durable once this
+    /// returns
+    fn sync(&self) -> Result<()> {
+        self.state.write().storage.sync()
+    }
+
+    /// remove the sealed segments, truncate the log, and clear the cached state, the empty log
+    /// and cache agree so the state is not dirty afterwards
+    fn clear(&self) -> Result<()> {
+        let mut state = self.state.write();
+        for id in std::mem::take(&mut state.segments) {
+            state.storage.remove(id)?;
+        }
+        state.storage.write(None, &[])?;
+        state.index.clear();
+        if let Some(accessed) = &mut state.accessed {
+            accessed.clear();
+        }
+        state.actions = 0;
+        state.compacted = 0;
+        state.live = 0;
+        state.dirty = false;
+        Ok(())
+    }
+
+    /// the log is synced, so every record before the offset is durable. Compaction and clear
+    /// rewrite the log, and a size-tiered store seals it, so an offset taken before any of these
+    /// may point past the end of the log, or into the middle of a record
+    fn commit_offset(&self) -> Result<Option<u64>> {
+        let mut state = self.state.write();
+        state.storage.sync()?;
+        Ok(Some(state.storage.len(None)?))
+    }
+
+    /// the next batch of changes from the log, see KvStore::stream_changes_since
+    fn changes_since(&self, offset: u64) -> Result<Vec<Change>> {
+        self.stream_changes_since(offset)?
+            .take(CHANGES_BATCH)
+            .collect()
+    }
+
+    /// restrict the keys accepted by the store
+    fn set_key_policy(&self, policy: KeyPolicy) {
+        self.state.write().key_policy = policy;
+    }
+
+    /// restrict the values accepted by the store
+    fn set_value_format(&self, format: ValueFormat) {
+        self.state.write().value_format = format;
+    }
+
+    /// the number of keys in the index, the log is only read if the state is dirty
+    fn len(&self) -> Result<usize> {
+        Ok(self.read_state()?.index.len())
+    }
+
+    /// kvs
+    fn name(&self) -> String {
+        "kvs".to_owned()
+    }
+
+    /// the compaction strategy, see CompactionStrategy
+    fn compaction(&self) -> String {
+        self.state.read().compaction.strategy.to_string()
+    }
+
+    /// the index is kept in key order, so the page is read from it directly, in O(log n + limit),
+    /// values are read under a single read lock
+    /// # Errors
+    /// KvsError::Unsupported - keys are hashed, so they are not held in order, see
+    /// KvStore::set_key_hashing
+    fn scan_page(&self, start: Option<String>, limit: usize) -> Result<Page> {
+        let state = self.read_state()?;
+        let keys = match &state.index {
+            Index::Keys(keys) => keys,
+            Index::Hashed { .. } => {
+                return Err(Box::from(KvsError::Unsupported {
+                    operation: "scan of hashed keys".to_owned(),
+                }))
+            }
+        };
+        // the empty key sorts before every other key
+        let mut range = keys.range(start.unwrap_or_default()..);
+        let page: Vec<_> = range.by_ref().take(limit).collect();
+        let next = range.next().map(|(key, _)| key.to_owned());
+        let mut entries = Vec::with_capacity(page.len());
+        for (key, entry) in page {
+            entries.push((key.to_owned(), state.entry_value(key, entry)?));
+        }
+        Ok((entries, next))
+    }
+
+    /// the access time is updated under a read lock, see KvStore::set_access_tracking
+    fn touch(&self, key: String) -> Result<()> {
+        let state = self.read_state()?;
+        state.key_policy.check(&key)?;
+        match state.accessed.as_ref().ok_or_else(untracked)?.get(&key) {
+            Some(at) => {
+                at.store(now_millis(), atomic::Ordering::Relaxed);
+                Ok(())
+            }
+            None => Err(Box::from(ErrKeyNotFound { key })),
+        }
+    }
+
+    /// times are kept to the millisecond, see KvStore::set_access_tracking
+    fn last_accessed(&self, key: String) -> Result<Option<SystemTime>> {
+        let state = self.read_state()?;
+        state.key_policy.check(&key)?;
+        match state.accessed.as_ref().ok_or_else(untracked)?.get(&key) {
+            Some(at) => Ok(match at.load(atomic::Ordering::Relaxed) {
+                0 => None,
+                at => Some(UNIX_EPOCH + Duration::from_millis(at)),
+            }),
+            None => Err(Box::from(ErrKeyNotFound { key })),
+        }
+    }
+
+    /// the values are read from the Set records of the sealed segments and the log, so every
+    /// value written since the log was last compacted is returned, including values set before
+    /// the key was removed, with CompactionStrategy::Disabled that is every value ever written.
+    /// The whole log is read, under a read lock
+    fn get_versions(&self, key: String, limit: usize) -> Result<Vec<String>> {
+        let state = self.read_state()?;
+        state.key_policy.check(&key)?;
+        // only Set records of key start with this, the key's closing quote is part of it
+        let prefix = format!("{}{}", SET_KEY_PREFIX, serde_json::to_string(&key)?);
+        // the newest limit values, oldest first
+        let mut versions = VecDeque::new();
+        for segment in state.all_segments() {
+            let log = state.storage.read(segment)?;
+            for record in log.split(|&byte| byte == b'\n') {
+                if !record.starts_with(prefix.as_bytes()) {
+                    continue;
+                }
+                if let CommandData::Set { value, .. } = serde_json::from_slice(record)? {
+                    versions.push_back(value);
+                    if versions.len() > limit {
+                        versions.pop_front();
+                    }
+                }
+            }
+        }
+        Ok(versions.into_iter().rev().collect())
+    }
+
+    /// the Set of to and Rm of from are written to the log in a single write
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let mut state = self.write_state()?;
+        state.key_policy.check(&from)?;
+        state.key_policy.check(&to)?;
+        let value = match state.value(&from)? {
+            Some(value) => value,
+            None => return Err(Box::from(ErrKeyNotFound { key: from })),
+        };
+        if from == to {
+            return Ok(());
+        }
+        state.write_logs(vec![
+            CommandData::Set { key: to, value },
+            CommandData::Rm { key: from },
+        ])
+    }
+
+    /// append to the current value, only the resulting Set is written to the log, under one write
+    /// lock
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let mut state = self.write_state()?;
+        state.key_policy.check(&key)?;
+        let val = state.value(&key)?.unwrap_or_default() + &suffix;
+        state.value_format.check(val.as_bytes())?;
+        let len = val.len();
+        state.write_log(CommandData::Set { key, value: val })?;
+        Ok(len)
+    }
+
+    /// prepend to the current value, only the resulting Set is written to the log, under one write
+    /// lock
+    fn prepend(&self, key: String, prefix: String) -> Result<usize> {
+        let mut state = self.write_state()?;
+        state.key_policy.check(&key)?;
+        let val = prefix + &state.value(&key)?.unwrap_or_default();
+        state.value_format.check(val.as_bytes())?;
+        let len = val.len();
+        state.write_log(CommandData::Set { key, value: val })?;
+        Ok(len)
+    }
+}
+
+/// Change is a mutation read from the log, see KvStore::stream_changes_since
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Change {
+    /// the mutation, a set or rm
+    pub cmd: CommandData,
+    /// the offset of the record following this one
+    pub next_offset: u64,
+}
+
+/// ChangeStream iterates over the mutations recorded in the log, see KvStore::stream_changes_since
+pub struct ChangeStream {
+    // the log, from the offset of the next record to the end of the log
+    reader: BufReader<Box<dyn Read + Send>>,
+    // offset of the next record
+    offset: u64,
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = match self.reader.read_until(b'\n', &mut line) {
+                Ok(n) => n,
+                Err(e) => return Some(Err(Box::from(e))),
+            };
+            // a record without its trailing newline was never completely written
+            if n == 0 || line[n - 1] != b'\n' {
+                return None;
+            }
+            self.offset += n as u64;
+            match serde_json::from_slice(&line[..n - 1]) {
+                Ok(cmd @ (CommandData::Set { .. } | CommandData::Rm { .. })) => {
+                    return Some(Ok(Change {
+                        cmd,
+                        next_offset: self.offset,
+                    }))
+                }
+                // reads do not affect state
+                Ok(_) => continue,
+                Err(e) => return Some(Err(Box::from(e))),
+            }
+        }
+    }
+}
+
+/// JsonStrReader decodes the escaped contents of a JSON string as written by serde_json,
+/// so a value can be read out of its log record without deserializing the whole record
+struct JsonStrReader<R: BufRead> {
+    inner: R,
+    // decoded bytes of an escaped character that did not fit in the caller's buffer
+    pending: [u8; 4],
+    pending_pos: usize,
+    pending_len: usize,
+}
+
+impl<R: BufRead> JsonStrReader<R> {
+    fn new(inner: R) -> Self {
+        JsonStrReader {
+            inner,
+            pending: [0; 4],
+            pending_pos: 0,
+            pending_len: 0,
+        }
+    }
+
+    /// read the next raw byte of an escape sequence, which must not end the string
+    fn escaped_byte(&mut self) -> io::Result<u8> {
+        let byte = *self
+            .inner
+            .fill_buf()?
+            .first()
+            .ok_or_else(|| invalid_escape("truncated escape sequence in log"))?;
+        self.inner.consume(1);
+        Ok(byte)
+    }
+
+    /// read the four hex digits of a \u escape
+    fn hex_escape(&mut self) -> io::Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.escaped_byte()? as char)
+                .to_digit(16)
+                .ok_or_else(|| invalid_escape("invalid \\u escape in log"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    /// decode the escape sequence following a backslash into self.pending
+    fn unescape(&mut self) -> io::Result<()> {
+        let ch = match self.escaped_byte()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\x08',
+            b'f' => '\x0c',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let mut code = self.hex_escape()?;
+                if (0xD800..0xDC00).contains(&code) {
+                    // high surrogate, the low surrogate follows as another \u escape
+                    if self.escaped_byte()? != b'\\' || self.escaped_byte()? != b'u' {
+                        return Err(invalid_escape("unpaired surrogate in log"));
+                    }
+                    let low = self.hex_escape()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(invalid_escape("unpaired surrogate in log"));
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+                char::from_u32(code).ok_or_else(|| invalid_escape("invalid \\u escape in log"))?
+            }
+            _ => return Err(invalid_escape("invalid escape sequence in log")),
+        };
+        self.pending_len = ch.encode_utf8(&mut self.pending).len();
+        self.pending_pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for JsonStrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            // flush the remains of a decoded escape first
+            if self.pending_pos < self.pending_len {
+                buf[n] = self.pending[self.pending_pos];
+                self.pending_pos += 1;
+                n += 1;
+                continue;
+            }
+            let raw = self.inner.fill_buf()?;
+            if raw.is_empty() {
+                break;
+            }
+            if raw[0] == b'\\' {
+                self.inner.consume(1);
+                self.unescape()?;
+                continue;
+            }
+            // copy the run of bytes up to the next escape as is
+            let run = raw
+                .iter()
+                .position(|byte| *byte == b'\\')
+                .unwrap_or(raw.len())
+                .min(buf.len() - n);
+            buf[n..n + run].copy_from_slice(&raw[..run]);
+            self.inner.consume(run);
+            n += run;
+        }
+        Ok(n)
+    }
+}
+
+/// error returned when a value in the log is not a well-formed JSON string
+fn invalid_escape(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the log used by these tests, records begin at 0, 6, 12 and their newlines sit at 5, 11, 17
+    const LOG: &[u8] = b"rec_a\nrec_b\nrec_c\n";
+
+    #[test]
+    // draining keeps the records pointed at, with their newlines
+    fn drain_stale_keeps_bounds() {
+        let mut buf = LOG.to_vec();
+        let bounds = vec![
+            Bound {
+                segment: None,
+                begin: 12,
+                end: 17,
+            },
+            Bound {
+                segment: None,
+                begin: 0,
+                end: 5,
+            },
+        ];
+        drain_stale(&mut buf, bounds).unwrap();
+        assert_eq!(buf, b"rec_a\nrec_c\n");
+    }
+
+    #[test]
+    // bounds that are out of order, overlap, or point past the log are rejected without draining
+    fn drain_stale_rejects_invalid_bounds() {
+        for bounds in [
+            vec![Bound {
+                segment: None,
+                begin: 11,
+                end: 6,
+            }],
+            vec![
+                Bound {
+                    segment: None,
+                    begin: 0,
+                    end: 8,
+                },
+                Bound {
+                    segment: None,
+                    begin: 6,
+                    end: 11,
+                },
+            ],
+            vec![Bound {
+                segment: None,
+                begin: 12,
+                end: 40,
+            }],
+        ] {
+            let mut buf = LOG.to_vec();
+            let err = drain_stale(&mut buf, bounds).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<KvsError>(),
+                Some(KvsError::CompactionState { .. })
+            ));
+            assert_eq!(buf, LOG);
+        }
     }
 }
@@ -1,70 +1,346 @@
-use parking_lot::Mutex;
+use crate::engines::kvs::Change;
+use clap::ValueEnum;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::io::{self, Cursor, Read, Write};
 use std::sync::Arc;
+use std::time::SystemTime;
 use std::{error::Error, fmt};
 /// type alias used for wrapping arbitrary error messages / returns in Result
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-// embed dyn KvsEngine in an Arc, protected by a reference count and a Mutex,
-// the impl KvsEngine will be stored on the heap, and de-allocated once Arc's
-// reference count goes to zero
+/// SharedKvsEngine is a KvsEngine shared between threads, each method calls the engine's method
+/// of the same name
+// embed dyn KvsEngine in an Arc, protected by a reference count, the engine synchronizes its own
+// state, so clones are cheap and calls from many threads do not contend on a lock held here. The
+// impl KvsEngine will be stored on the heap, and de-allocated once Arc's reference count goes to
+// zero
 #[derive(Clone)]
 pub struct SharedKvsEngine {
-    engine: Arc<Mutex<dyn KvsEngine>>,
+    engine: Arc<dyn KvsEngine>,
 }
 
 impl SharedKvsEngine {
-    /// instantiate a SharedKvsEngine as a locked. atomically referenced counted pointer to
-    /// the object on the heap
+    /// instantiate a SharedKvsEngine as an atomically referenced counted pointer to the object on
+    /// the heap
     pub fn from(engine: impl KvsEngine) -> Self {
         SharedKvsEngine {
-            engine: Arc::from(Mutex::from(engine)),
+            engine: Arc::new(engine),
         }
     }
 
-    /// direct implementation of KvsEngine, as there cannot be cloned mutable refs between threads
+    /// set key to val, see KvsEngine::set
     pub fn set(&self, key: String, val: String) -> Result<()> {
-        // take lock
-        let mut unlocked_engine = self.engine.lock();
-        // return value from underlying KvsEngine
-        unlocked_engine.set(key, val)
+        self.engine.set(key, val)
     }
 
-    /// direct implementation of KvsEngine, as there cannot be cloned mutable refs between threads
+    /// the value of key, see KvsEngine::get, reads do not block one another
     pub fn get(&self, key: String) -> Result<Option<String>> {
-        // take lock
-        let mut unlocked_engine = self.engine.lock();
-        // return value from underlying KvsEngine
-        unlocked_engine.get(key)
+        self.engine.get(key)
     }
 
-    /// direct implementation of KvsEngine, as there cannot be cloned mutable refs between threads
+    /// write the value of key to writer, see KvsEngine::get_into
+    pub fn get_into(&self, key: String, writer: &mut dyn Write) -> Result<bool> {
+        self.engine.get_into(key, writer)
+    }
+
+    /// stream the value for key through f, the value stream is held until f returns
+    /// returns false without calling f if the key does not exist
+    pub fn get_stream<F>(&self, key: String, f: F) -> Result<bool>
+    where
+        F: FnOnce(ValueStream) -> Result<()>,
+    {
+        match self.engine.get_stream(key)? {
+            Some(value) => f(value).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// remove key, see KvsEngine::remove
     pub fn remove(&self, key: String) -> Result<()> {
-        // take lock
-        let mut unlocked_engine = self.engine.lock();
-        // return value from underlying KvsEngine
-        unlocked_engine.remove(key)
+        self.engine.remove(key)
+    }
+
+    /// make every write so far durable, see KvsEngine::sync
+    pub fn sync(&self) -> Result<()> {
+        self.engine.sync()
+    }
+
+    /// remove every key, see KvsEngine::clear
+    pub fn clear(&self) -> Result<()> {
+        self.engine.clear()
+    }
+
+    /// the changes made from offset on, see KvsEngine::changes_since
+    pub fn changes_since(&self, offset: u64) -> Result<Vec<Change>> {
+        self.engine.changes_since(offset)
+    }
+
+    /// the keys accepted from now on, see KvsEngine::set_key_policy
+    pub fn set_key_policy(&self, policy: KeyPolicy) {
+        self.engine.set_key_policy(policy)
+    }
+
+    /// the values accepted from now on, see KvsEngine::set_value_format
+    pub fn set_value_format(&self, format: ValueFormat) {
+        self.engine.set_value_format(format)
+    }
+
+    /// the number of keys, see KvsEngine::len
+    pub fn len(&self) -> Result<usize> {
+        self.engine.len()
+    }
+
+    /// whether there are no keys, see KvsEngine::is_empty
+    pub fn is_empty(&self) -> Result<bool> {
+        self.engine.is_empty()
+    }
+
+    /// append suffix to the value of key, see KvsEngine::append, the engine makes the read and
+    /// write atomic, so concurrent appends are never lost
+    pub fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.engine.append(key, suffix)
+    }
+
+    /// prepend prefix to the value of key, see KvsEngine::prepend, the engine makes the read and
+    /// write atomic, so concurrent prepends are never lost
+    pub fn prepend(&self, key: String, prefix: String) -> Result<usize> {
+        self.engine.prepend(key, prefix)
+    }
+
+    /// move the value of from to to, see KvsEngine::rename, the engine makes the rename atomic,
+    /// so no other command sees from and to both set, or both missing
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        self.engine.rename(from, to)
+    }
+
+    /// the engine's name, see KvsEngine::name
+    pub fn name(&self) -> String {
+        self.engine.name()
+    }
+
+    /// the engine's compaction strategy, see KvsEngine::compaction
+    pub fn compaction(&self) -> String {
+        self.engine.compaction()
+    }
+
+    /// a page of (key, value) pairs from start on, see KvsEngine::scan_page
+    pub fn scan_page(&self, start: Option<String>, limit: usize) -> Result<Page> {
+        self.engine.scan_page(start, limit)
+    }
+
+    /// the offset every durable write is before, see KvsEngine::commit_offset
+    pub fn commit_offset(&self) -> Result<Option<u64>> {
+        self.engine.commit_offset()
+    }
+
+    /// mark key as accessed, see KvsEngine::touch
+    pub fn touch(&self, key: String) -> Result<()> {
+        self.engine.touch(key)
+    }
+
+    /// when key was last accessed, see KvsEngine::last_accessed
+    pub fn last_accessed(&self, key: String) -> Result<Option<SystemTime>> {
+        self.engine.last_accessed(key)
+    }
+
+    /// up to limit of the values key has been set to, see KvsEngine::get_versions
+    pub fn get_versions(&self, key: String, limit: usize) -> Result<Vec<String>> {
+        self.engine.get_versions(key, limit)
     }
 }
 
+/// Page is a page of (key, value) pairs in key order, returned by scan_page, and the key the next
+/// page starts at, None if this is the last page
+pub type Page = (Vec<(String, String)>, Option<String>);
+
 /// this is the trait that both SledKvsEngine and KvStore implement, it is composed of
 /// three methods
-/// 1. set(&mut self, key: String, val: String) -> Result<()>
-/// 2. get(&mut self, key: String) -> Result<Option<String>>
-/// 3. remove(&mut self, key: String) -> Result<()>
+/// 1. set(&self, key: String, val: String) -> Result<()>
+/// 2. get(&self, key: String) -> Result<Option<String>>
+/// 3. remove(&self, key: String) -> Result<()>
+///
+/// Every method takes &self, an engine synchronizes its own state, so it may be shared between
+/// threads, see SharedKvsEngine
 pub trait KvsEngine: Send + 'static + Sync {
     /// Inserts a (key, value) pair into map
     /// serialized set, key, value
     /// overwrites existing value is key exists
-    fn set(&mut self, key: String, val: String) -> Result<()>;
+    fn set(&self, key: String, val: String) -> Result<()>;
 
     /// Gets a value associated with the key in KvStore.map
     /// returns None if the key does not exist
-    /// clones the string from the map if it exists
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// clones the string from the map if it exists, an empty value is returned as Some(""), only
+    /// a key that was never set, or was removed, returns None
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets a reader over the value associated with the key, and the value's length in bytes
+    /// returns None if the key does not exist
+    /// by default the value is read into memory with get, engines that can read the value
+    /// from disk incrementally should override this
+    fn get_stream(&self, key: String) -> Result<Option<ValueStream>> {
+        Ok(self.get(key)?.map(|val| ValueStream {
+            len: val.len() as u64,
+            reader: Box::new(Cursor::new(val.into_bytes())),
+        }))
+    }
+
+    /// Writes the value associated with the key to writer, through get_stream, so engines that
+    /// read the value from disk incrementally never hold a copy of the value in memory
+    /// returns false if the key does not exist
+    fn get_into(&self, key: String, writer: &mut dyn Write) -> Result<bool> {
+        match self.get_stream(key)? {
+            Some(mut value) => {
+                io::copy(&mut value.reader, writer)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
     /// Remves the value associated with the key in KvStore.map
     /// if the key has no value, this is a no-op
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Flushes every prior write to durable storage, returns once the writes are durable
+    fn sync(&self) -> Result<()>;
+
+    /// Removes every (key, value) pair from the store
+    fn clear(&self) -> Result<()>;
+
+    /// Returns the next batch of mutations recorded at or after offset, in the order they were
+    /// made, see KvStore::stream_changes_since. An empty batch means there are no further changes
+    /// yet, engines without a replayable log return KvsError::Unsupported
+    fn changes_since(&self, offset: u64) -> Result<Vec<Change>> {
+        let _ = offset;
+        Err(Box::from(KvsError::Unsupported {
+            operation: "replication".to_owned(),
+        }))
+    }
+
+    /// Restricts the keys the engine accepts to those allowed by policy, operations on any
+    /// other key return KvsError::InvalidKey
+    fn set_key_policy(&self, policy: KeyPolicy);
+
+    /// Restricts the values the engine accepts to those in format, writes of any other value,
+    /// including the value resulting from an append / prepend, return KvsError::InvalidValue.
+    /// Only writes are checked, values written before the format was set are read as they are
+    fn set_value_format(&self, format: ValueFormat);
+
+    /// Returns the number of keys in the store
+    fn len(&self) -> Result<usize>;
+
+    /// Returns true if the store contains no keys
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Appends suffix to the value associated with the key, the value is created if the key
+    /// does not exist
+    /// returns the length of the new value in bytes
+    /// This is a get and set, engines override it so concurrent appends are never lost
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let val = self.get(key.clone())?.unwrap_or_default() + &suffix;
+        let len = val.len();
+        self.set(key, val)?;
+        Ok(len)
+    }
+
+    /// Prepends prefix to the value associated with the key, the value is created if the key
+    /// does not exist
+    /// returns the length of the new value in bytes
+    /// This is a get and set, engines override it so concurrent prepends are never lost
+    fn prepend(&self, key: String, prefix: String) -> Result<usize> {
+        let val = prefix + &self.get(key.clone())?.unwrap_or_default();
+        let len = val.len();
+        self.set(key, val)?;
+        Ok(len)
+    }
+
+    /// The engine's name, as given to kvs-server --engine, reported by kvs-client info
+    fn name(&self) -> String {
+        "custom".to_owned()
+    }
+
+    /// How the engine reclaims the space held by overwritten / removed values, reported by
+    /// kvs-client info
+    fn compaction(&self) -> String {
+        "unknown".to_owned()
+    }
+
+    /// Returns up to limit (key, value) pairs in key order, starting at the key start, or at the
+    /// first key if start is None, and the key the next page starts at, None once the last key
+    /// has been returned. Walking the pages, passing each page's next key as the start of the
+    /// following page, returns every key exactly once, as long as the store is not modified
+    /// engines that cannot list their keys return KvsError::Unsupported
+    fn scan_page(&self, start: Option<String>, limit: usize) -> Result<Page> {
+        let _ = (start, limit);
+        Err(Box::from(KvsError::Unsupported {
+            operation: "scan".to_owned(),
+        }))
+    }
+
+    /// Flushes every prior write, and returns the offset of the end of the log, a consumer that
+    /// has recorded the offset resumes from it with changes_since, see
+    /// KvStore::stream_changes_since. The offset never decreases while the log is only written
+    /// to, engines without a replayable log return None
+    fn commit_offset(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Marks the key as accessed now, without reading its value
+    /// returns ErrKeyNotFound if the key does not exist, engines that do not track access times
+    /// return KvsError::Unsupported
+    fn touch(&self, key: String) -> Result<()> {
+        let _ = key;
+        Err(Box::from(KvsError::Unsupported {
+            operation: "touch".to_owned(),
+        }))
+    }
+
+    /// Returns when the key was last set, read by a get, or touched, None if the engine has no
+    /// record of an access
+    /// returns ErrKeyNotFound if the key does not exist, engines that do not track access times
+    /// return KvsError::Unsupported
+    fn last_accessed(&self, key: String) -> Result<Option<SystemTime>> {
+        let _ = key;
+        Err(Box::from(KvsError::Unsupported {
+            operation: "access tracking".to_owned(),
+        }))
+    }
+
+    /// Returns up to limit of the values key has been set to, newest first, the current value,
+    /// if the key has one, is the first. Only the values the engine still holds are returned,
+    /// engines that keep no history return KvsError::Unsupported
+    fn get_versions(&self, key: String, limit: usize) -> Result<Vec<String>> {
+        let _ = (key, limit);
+        Err(Box::from(KvsError::Unsupported {
+            operation: "version history".to_owned(),
+        }))
+    }
+
+    /// Moves the value associated with from to to, replacing any value at to, and removes from
+    /// returns ErrKeyNotFound if from does not exist
+    /// This is a get, set and remove, engines override it so a crash never leaves both keys set
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let val = match self.get(from.clone())? {
+            Some(val) => val,
+            None => return Err(Box::from(ErrKeyNotFound { key: from })),
+        };
+        if from == to {
+            return Ok(());
+        }
+        self.set(to, val)?;
+        self.remove(from)
+    }
+}
+
+/// ValueStream is a value that can be read incrementally, rather than held in memory as a String
+pub struct ValueStream {
+    /// length of the value in bytes
+    pub len: u64,
+    /// reader over the value's bytes
+    pub reader: Box<dyn Read + Send>,
 }
 
 /// Key not found error returned from both kvs_engines
@@ -81,3 +357,179 @@ impl fmt::Display for ErrKeyNotFound {
 }
 
 impl Error for ErrKeyNotFound {}
+
+/// KvsError are the typed errors returned by the kvs engines, the Result returned by an engine
+/// may be downcast to a KvsError to match on the failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvsError {
+    /// the log's record bounds violate the invariants compaction relies on, the log is not modified
+    CompactionState {
+        /// description of the violated invariant
+        reason: String,
+    },
+    /// the key violates the engine's KeyPolicy, nothing is read or written
+    InvalidKey {
+        /// description of the violated rule
+        reason: String,
+    },
+    /// the value is not in the engine's ValueFormat, nothing is written
+    InvalidValue {
+        /// why the value was rejected
+        reason: String,
+    },
+    /// the engine does not support the operation
+    Unsupported {
+        /// the operation that was attempted
+        operation: String,
+    },
+    /// the server does not know the command, i.e it was added in a later version than the server
+    UnsupportedCommand {
+        /// the command's tag, as sent by the client
+        name: String,
+        /// protocol version spoken by the server
+        server_version: u32,
+    },
+    /// the connection made more requests than the server allows, the command was not handled
+    RateLimited {
+        /// the most requests a connection may make per second
+        max_rps: u32,
+    },
+    /// the server is not taking writes for now, i.e writes are paused for maintenance, the
+    /// command was not handled, and may be retried later
+    Unavailable {
+        /// why the server is unavailable
+        reason: String,
+    },
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::CompactionState { reason } => {
+                write!(f, "invalid compaction state: {}", reason)
+            }
+            KvsError::InvalidKey { reason } => write!(f, "invalid key: {}", reason),
+            KvsError::InvalidValue { reason } => write!(f, "invalid value: {}", reason),
+            KvsError::Unsupported { operation } => {
+                write!(f, "{} is not supported by this engine", operation)
+            }
+            KvsError::UnsupportedCommand {
+                name,
+                server_version,
+            } => write!(
+                f,
+                "{} is not supported by this server, which speaks protocol v{}",
+                name, server_version
+            ),
+            KvsError::RateLimited { max_rps } => write!(
+                f,
+                "rate limited, the server allows at most {} requests per second per connection",
+                max_rps
+            ),
+            KvsError::Unavailable { reason } => write!(f, "unavailable: {}", reason),
+        }
+    }
+}
+
+impl Error for KvsError {}
+
+/// KeyPolicy restricts the keys an engine accepts, keys are checked by set / get / remove, and
+/// any operation built on them
+/// The default policy accepts every key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// the maximum length of a key in bytes, None for no limit
+    pub max_len: Option<usize>,
+    /// accept the empty key
+    pub allow_empty: bool,
+    /// accept keys containing control characters, i.e '\n', '\0'
+    pub allow_control: bool,
+    /// characters no key may contain
+    pub forbidden: Vec<char>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        KeyPolicy {
+            max_len: None,
+            allow_empty: true,
+            allow_control: true,
+            forbidden: Vec::new(),
+        }
+    }
+}
+
+impl KeyPolicy {
+    /// check returns KvsError::InvalidKey if key violates the policy
+    pub fn check(&self, key: &str) -> Result<()> {
+        if key.is_empty() && !self.allow_empty {
+            return Err(invalid_key("key is empty".to_owned()));
+        }
+        if let Some(max_len) = self.max_len {
+            if key.len() > max_len {
+                return Err(invalid_key(format!(
+                    "key is {} bytes, longer than the limit of {}",
+                    key.len(),
+                    max_len
+                )));
+            }
+        }
+        if let Some(c) = key
+            .chars()
+            .find(|c| (c.is_control() && !self.allow_control) || self.forbidden.contains(c))
+        {
+            return Err(invalid_key(format!(
+                "key contains forbidden character {:?}",
+                c
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// box a KvsError::InvalidKey with the given reason
+fn invalid_key(reason: String) -> Box<dyn Error> {
+    Box::from(KvsError::InvalidKey { reason })
+}
+
+/// ValueFormat is the format an engine requires its values to be in, the default, Raw, accepts
+/// any value, so values are stored byte for byte as they are given
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueFormat {
+    /// any value
+    #[default]
+    Raw,
+    /// values must be a single JSON document, parsed with serde_json, the value is stored as
+    /// given, not re-serialized
+    Json,
+}
+
+impl ValueFormat {
+    /// check returns KvsError::InvalidValue if value is not in the format
+    pub fn check(&self, value: &[u8]) -> Result<()> {
+        match self {
+            ValueFormat::Raw => Ok(()),
+            ValueFormat::Json => match serde_json::from_slice::<IgnoredAny>(value) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(Box::from(KvsError::InvalidValue {
+                    reason: format!("not valid JSON, {}", e),
+                })),
+            },
+        }
+    }
+
+    /// pretty returns value laid out for reading, JSON is indented, a raw value is returned as is
+    /// # Errors
+    /// KvsError::InvalidValue - value is not in the format
+    pub fn pretty(&self, value: String) -> Result<String> {
+        self.check(value.as_bytes())?;
+        match self {
+            ValueFormat::Raw => Ok(value),
+            ValueFormat::Json => {
+                let json: serde_json::Value = serde_json::from_str(&value)?;
+                Ok(serde_json::to_string_pretty(&json)?)
+            }
+        }
+    }
+}
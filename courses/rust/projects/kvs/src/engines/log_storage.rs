@@ -0,0 +1,564 @@
+use crate::engines::kvs_engine::Result;
+use log::*;
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// LogStorage is where a KvStore keeps its log, and the segments sealed from it by a size-tiered
+/// store. Segments are identified by id, None is the log, the only segment that is appended to
+pub trait LogStorage: Send + Sync + 'static {
+    /// ids of the sealed segments, in any order
+    fn segments(&self) -> Result<Vec<u64>>;
+
+    /// length of segment in bytes
+    fn len(&self, segment: Option<u64>) -> Result<u64>;
+
+    /// read the whole of segment
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>>;
+
+    /// a reader over len bytes of segment, starting at offset, the reader may outlive later
+    /// writes to the storage
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>>;
+
+    /// append buf to the end of the log
+    fn append(&mut self, buf: &[u8]) -> Result<()>;
+
+    /// replace the contents of segment with buf, storage that outlives the process replaces it
+    /// atomically, so a crash leaves either the old or the new contents
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()>;
+
+    /// seal the log into the segment id, leaving an empty log
+    fn seal(&mut self, id: u64) -> Result<()>;
+
+    /// remove the sealed segment id
+    fn remove(&mut self, id: u64) -> Result<()>;
+
+    /// make every write so far durable
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// suffix of the file a segment's replacement is written to, before it is renamed over the
+/// segment, i.e `log.tmp`, `log.<id>.tmp`
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// FileStorage keeps the log in the file `log` in a directory, and each sealed segment in a file
+/// `log.<id>` beside it
+pub struct FileStorage {
+    // directory holding the log and its sealed segments
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// open the storage in dir, creating an empty log if there is none, replacements left over
+    /// from a write interrupted by a crash are removed, the segments they were replacing are
+    /// intact
+    pub fn open(dir: impl Into<PathBuf>) -> Result<FileStorage> {
+        let storage = FileStorage { dir: dir.into() };
+        for entry in fs::read_dir(&storage.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name
+                .to_str()
+                .is_some_and(|name| name.starts_with("log") && name.ends_with(TEMP_SUFFIX))
+            {
+                warn!(
+                    "removing {}, left over from an interrupted write",
+                    entry.path().display()
+                );
+                fs::remove_file(entry.path())?;
+            }
+        }
+        // open file with given path, (write permissions must be given if creating file)
+        File::options()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(storage.path(None))?;
+        Ok(storage)
+    }
+
+    /// path to the file holding segment
+    fn path(&self, segment: Option<u64>) -> PathBuf {
+        match segment {
+            Some(id) => self.dir.join(format!("log.{}", id)),
+            None => self.dir.join("log"),
+        }
+    }
+
+    /// path to the file a replacement of segment is written to
+    fn temp_path(&self, segment: Option<u64>) -> PathBuf {
+        let mut path = self.path(segment).into_os_string();
+        path.push(TEMP_SUFFIX);
+        path.into()
+    }
+
+    /// fsync the directory, so the files renamed within it stay renamed after a crash
+    fn sync_dir(&self) -> Result<()> {
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl LogStorage for FileStorage {
+    fn segments(&self) -> Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            if let Some(id) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("log."))
+                .and_then(|id| id.parse().ok())
+            {
+                segments.push(id);
+            }
+        }
+        Ok(segments)
+    }
+
+    fn len(&self, segment: Option<u64>) -> Result<u64> {
+        Ok(fs::metadata(self.path(segment))?.len())
+    }
+
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>> {
+        Ok(fs::read(self.path(segment))?)
+    }
+
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.path(segment))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(file.take(len)))
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        File::options()
+            .append(true)
+            .open(self.path(None))?
+            .write_all(buf)?;
+        Ok(())
+    }
+
+    /// buf is written to a temporary file beside the segment and fsynced, and only then renamed
+    /// over the segment, a crash before the rename leaves the segment as it was
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()> {
+        let temp = self.temp_path(segment);
+        let mut file = File::create(&temp)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.path(segment))?;
+        self.sync_dir()
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        fs::rename(self.path(None), self.path(Some(id)))?;
+        File::create(self.path(None))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        Ok(fs::remove_file(self.path(Some(id)))?)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        for segment in self.segments()?.into_iter().map(Some).chain([None]) {
+            File::options()
+                .write(true)
+                .open(self.path(segment))?
+                .sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// StreamStorage keeps the log in any Read + Write + Seek stream, i.e a Cursor<Vec<u8>> for a
+/// store held in memory. A stream cannot be truncated, so the length of the log is tracked
+/// separately, and bytes past it are left over from before the log was last rewritten
+/// Sealed segments are held in memory
+pub struct StreamStorage<S> {
+    // the log, reads seek the stream, so it is locked even when the storage is only borrowed
+    stream: Mutex<S>,
+    // length of the log within stream
+    len: u64,
+    // sealed segments by id
+    segments: HashMap<u64, Vec<u8>>,
+}
+
+impl StreamStorage<Cursor<Vec<u8>>> {
+    /// an empty log held in memory
+    pub fn in_memory() -> Self {
+        StreamStorage {
+            stream: Mutex::new(Cursor::new(Vec::new())),
+            len: 0,
+            segments: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Read + Write + Seek + Send + 'static> StreamStorage<S> {
+    /// keep the log in stream, everything already in stream is read as the log
+    pub fn new(mut stream: S) -> Result<Self> {
+        let len = stream.seek(SeekFrom::End(0))?;
+        Ok(StreamStorage {
+            stream: Mutex::new(stream),
+            len,
+            segments: HashMap::new(),
+        })
+    }
+
+    /// the stream the log is kept in, see StreamStorage for the bytes it may hold past the log
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// read len bytes of segment, starting at offset
+    fn read_range(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len as usize];
+        match segment {
+            Some(id) => {
+                let segment = self.segment(id)?;
+                let range = segment
+                    .get(offset as usize..(offset + len) as usize)
+                    .ok_or_else(|| past_end(offset + len, segment.len() as u64))?;
+                buf.copy_from_slice(range);
+            }
+            None => {
+                if offset + len > self.len {
+                    return Err(past_end(offset + len, self.len));
+                }
+                let mut stream = self.stream.lock();
+                stream.seek(SeekFrom::Start(offset))?;
+                stream.read_exact(&mut buf)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    /// the sealed segment id
+    fn segment(&self, id: u64) -> Result<&Vec<u8>> {
+        self.segments.get(&id).ok_or_else(|| {
+            Box::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no segment {}", id),
+            ))
+        })
+    }
+}
+
+// error for a read ending at end, past the end of a segment of len bytes
+fn past_end(end: u64, len: u64) -> Box<dyn std::error::Error> {
+    Box::from(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("read to {} past the end of a {} byte segment", end, len),
+    ))
+}
+
+impl<S: Read + Write + Seek + Send + 'static> LogStorage for StreamStorage<S> {
+    fn segments(&self) -> Result<Vec<u64>> {
+        Ok(self.segments.keys().copied().collect())
+    }
+
+    fn len(&self, segment: Option<u64>) -> Result<u64> {
+        match segment {
+            Some(id) => Ok(self.segment(id)?.len() as u64),
+            None => Ok(self.len),
+        }
+    }
+
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>> {
+        self.read_range(segment, 0, self.len(segment)?)
+    }
+
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        // the stream is owned by the storage, so the range is copied out
+        Ok(Box::new(Cursor::new(
+            self.read_range(segment, offset, len)?,
+        )))
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.seek(SeekFrom::Start(self.len))?;
+        stream.write_all(buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()> {
+        match segment {
+            Some(id) => {
+                self.segments.insert(id, buf.to_vec());
+            }
+            None => {
+                let stream = self.stream.get_mut();
+                stream.seek(SeekFrom::Start(0))?;
+                stream.write_all(buf)?;
+                self.len = buf.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        let log = self.read(None)?;
+        self.segments.insert(id, log);
+        self.len = 0;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        self.segment(id)?;
+        self.segments.remove(&id);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.stream.get_mut().flush()?)
+    }
+}
+
+/// GroupCommitStorage buffers appends to the log of another storage in memory, a background
+/// thread appends the buffer to the storage and fsyncs it every interval, or as soon as the buffer
+/// reaches max_bytes. An append returns once its bytes are buffered, so a crash loses at most the
+/// writes of the last unflushed window. Reads of the log consult the buffer, so buffered writes
+/// are visible immediately, every other operation flushes the buffer first
+pub(crate) struct GroupCommitStorage {
+    // the buffer and storage, shared with the flusher thread
+    shared: Arc<Shared>,
+    // handle to the flusher thread, taken once it has been stopped
+    flusher: Option<JoinHandle<()>>,
+}
+
+// the state shared between a GroupCommitStorage and its flusher thread
+struct Shared {
+    buffered: Mutex<Buffered>,
+    // notified when the buffer reaches max_bytes, or the flusher is stopped
+    wake: Condvar,
+    max_bytes: usize,
+}
+
+struct Buffered {
+    // the storage written to, taken by into_inner
+    storage: Option<Box<dyn LogStorage>>,
+    // bytes appended to the log that have not been flushed to the storage
+    pending: Vec<u8>,
+    // the first error from a background flush, every later write fails with it
+    error: Option<String>,
+    // the flusher thread exits once this is set
+    stopped: bool,
+}
+
+impl Buffered {
+    fn storage(&self) -> &dyn LogStorage {
+        self.storage
+            .as_deref()
+            .expect("storage is only taken by into_inner")
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn LogStorage {
+        self.storage
+            .as_deref_mut()
+            .expect("storage is only taken by into_inner")
+    }
+
+    /// fail if a background flush has failed
+    fn check(&self) -> Result<()> {
+        match &self.error {
+            Some(e) => Err(format!("group commit failed: {}", e).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// append the buffer to the storage and fsync it, a no-op when nothing is buffered. The buffer
+    /// is only cleared once both succeed, so a failed flush loses nothing and can be retried
+    fn flush(&mut self) -> Result<()> {
+        self.check()?;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let storage = self
+            .storage
+            .as_deref_mut()
+            .expect("storage is only taken by into_inner");
+        let stored = storage.len(None)?;
+        if let Err(e) = storage.append(&self.pending).and_then(|()| storage.sync()) {
+            // the buffer is still pending, take back whatever part of it reached the log, so a
+            // retry appends it once
+            if storage.len(None)? > stored {
+                let log = storage.read(None)?;
+                storage.write(None, &log[..stored as usize])?;
+            }
+            return Err(e);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl GroupCommitStorage {
+    /// buffer appends to storage, spawning the thread that flushes them every interval, or once
+    /// max_bytes are buffered
+    pub(crate) fn new(
+        storage: Box<dyn LogStorage>,
+        interval: Duration,
+        max_bytes: usize,
+    ) -> GroupCommitStorage {
+        let shared = Arc::new(Shared {
+            buffered: Mutex::new(Buffered {
+                storage: Some(storage),
+                pending: Vec::new(),
+                error: None,
+                stopped: false,
+            }),
+            wake: Condvar::new(),
+            max_bytes,
+        });
+        let flusher_shared = shared.clone();
+        let flusher = thread::spawn(move || {
+            let shared = flusher_shared;
+            let mut buffered = shared.buffered.lock();
+            loop {
+                if buffered.pending.len() < shared.max_bytes && !buffered.stopped {
+                    shared.wake.wait_for(&mut buffered, interval);
+                }
+                // the buffer left once the flusher is stopped is flushed by the storage itself
+                if buffered.stopped {
+                    return;
+                }
+                if let Err(e) = buffered.flush() {
+                    if buffered.error.is_none() {
+                        error!("group commit flush failed: {}", e);
+                        buffered.error = Some(e.to_string());
+                    }
+                }
+            }
+        });
+        GroupCommitStorage {
+            shared,
+            flusher: Some(flusher),
+        }
+    }
+
+    /// stop and join the flusher thread
+    fn stop(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            self.shared.buffered.lock().stopped = true;
+            self.shared.wake.notify_all();
+            let _ = flusher.join();
+        }
+    }
+
+    /// stop the flusher thread, and flush the buffer, returning the storage written to
+    pub(crate) fn into_inner(mut self) -> Result<Box<dyn LogStorage>> {
+        self.stop();
+        let mut buffered = self.shared.buffered.lock();
+        buffered.flush()?;
+        Ok(buffered.storage.take().expect("storage is only taken once"))
+    }
+}
+
+/// impl Drop for GroupCommitStorage, stop the flusher thread and flush what is left in the buffer,
+/// so every write is in the storage once the GroupCommitStorage is gone
+impl Drop for GroupCommitStorage {
+    fn drop(&mut self) {
+        self.stop();
+        let mut buffered = self.shared.buffered.lock();
+        if buffered.storage.is_some() {
+            if let Err(e) = buffered.flush() {
+                error!("group commit flush failed: {}", e);
+            }
+        }
+    }
+}
+
+impl LogStorage for GroupCommitStorage {
+    fn segments(&self) -> Result<Vec<u64>> {
+        self.shared.buffered.lock().storage().segments()
+    }
+
+    fn len(&self, segment: Option<u64>) -> Result<u64> {
+        let buffered = self.shared.buffered.lock();
+        let len = buffered.storage().len(segment)?;
+        match segment {
+            Some(_) => Ok(len),
+            None => Ok(len + buffered.pending.len() as u64),
+        }
+    }
+
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>> {
+        let buffered = self.shared.buffered.lock();
+        let mut buf = buffered.storage().read(segment)?;
+        if segment.is_none() {
+            buf.extend_from_slice(&buffered.pending);
+        }
+        Ok(buf)
+    }
+
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        let buffered = self.shared.buffered.lock();
+        let storage = buffered.storage();
+        let stored = storage.len(segment)?;
+        if segment.is_some() || offset + len <= stored {
+            return storage.reader(segment, offset, len);
+        }
+        // the range runs into the buffer, copy out the stored part, followed by the buffered part
+        let mut buf = Vec::new();
+        if offset < stored {
+            storage
+                .reader(None, offset, stored - offset)?
+                .read_to_end(&mut buf)?;
+        }
+        let begin = offset.saturating_sub(stored) as usize;
+        let end = (offset + len - stored) as usize;
+        let pending = buffered
+            .pending
+            .get(begin..end)
+            .ok_or_else(|| past_end(offset + len, stored + buffered.pending.len() as u64))?;
+        buf.extend_from_slice(pending);
+        Ok(Box::new(Cursor::new(buf)))
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        let mut buffered = self.shared.buffered.lock();
+        buffered.check()?;
+        buffered.pending.extend_from_slice(buf);
+        if buffered.pending.len() >= self.shared.max_bytes {
+            self.shared.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()> {
+        let mut buffered = self.shared.buffered.lock();
+        if segment.is_none() {
+            // the buffer is part of the log being replaced
+            buffered.check()?;
+            buffered.pending.clear();
+        } else {
+            buffered.flush()?;
+        }
+        buffered.storage_mut().write(segment, buf)
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        let mut buffered = self.shared.buffered.lock();
+        buffered.flush()?;
+        buffered.storage_mut().seal(id)
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        let mut buffered = self.shared.buffered.lock();
+        buffered.check()?;
+        buffered.storage_mut().remove(id)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut buffered = self.shared.buffered.lock();
+        buffered.flush()?;
+        buffered.storage_mut().sync()
+    }
+}
@@ -1,5 +1,14 @@
+/// KvStore, the log-structured engine
 pub mod kvs;
 
+/// SledKvsEngine, an engine backed by sled
 pub mod sled;
 
+/// the KvsEngine trait, and the types shared by every engine
 pub mod kvs_engine;
+
+/// TieredEngine, a hot engine in front of a cold one
+pub mod tiered;
+
+/// LogStorage, where a KvStore keeps its log
+pub mod log_storage;
@@ -1,31 +1,98 @@
 use std::path::PathBuf;
 
-use crate::engines::kvs_engine::{ErrKeyNotFound, KvsEngine, Result};
+use crate::engines::kvs_engine::{
+    ErrKeyNotFound, KeyPolicy, KvsEngine, Page, Result, ValueFormat,
+};
+use parking_lot::RwLock;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::{Config, Db};
 use std::error::Error;
 use std::path::Path;
+use std::sync::Arc;
 /// SledKvsEngine is a wrapper around a Sled embedded database for observing reads / writes
 #[derive(Clone)]
 pub struct SledKvsEngine {
     // sled DB located in dir,
     Db: Db,
+    // keys accepted by the engine, shared by every clone of the engine
+    key_policy: Arc<RwLock<KeyPolicy>>,
+    // values accepted by the engine, shared by every clone of the engine
+    value_format: Arc<RwLock<ValueFormat>>,
+}
+
+/// SledOptions are the sled settings a SledKvsEngine is opened with, a setting left as None keeps
+/// sled's default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SledOptions {
+    /// the maximum size of sled's page cache in bytes
+    pub cache_capacity: Option<u64>,
+    /// how often sled flushes its dirty buffers in the background, in milliseconds, Some(0)
+    /// disables background flushes, so writes are only flushed by sync
+    pub flush_every_ms: Option<u64>,
+    /// compress the data sled writes to disk with zstd
+    pub use_compression: bool,
 }
 
 /// this method contains the methods for opening and returning a SledKvsEngine
 impl SledKvsEngine {
     /// open a Db file at the specified path, and then return a newly instantiated SledKvsEngine
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_config(path, SledOptions::default())
+    }
+
+    /// open a Db file at the specified path, configured by options, and then return a newly
+    /// instantiated SledKvsEngine
+    pub fn open_with_config<P: AsRef<Path>>(path: P, options: SledOptions) -> Result<Self> {
+        let mut config = Config::new()
+            .path(path.as_ref())
+            .use_compression(options.use_compression);
+        if let Some(cache_capacity) = options.cache_capacity {
+            config = config.cache_capacity(cache_capacity);
+        }
+        if let Some(flush_every_ms) = options.flush_every_ms {
+            config = config.flush_every_ms(Some(flush_every_ms).filter(|&ms| ms > 0));
+        }
         // open db at address
-        let db = Config::new().path(path.as_ref()).open()?;
+        let db = config.open()?;
         // return db
-        Ok(SledKvsEngine { Db: db })
+        Ok(SledKvsEngine {
+            Db: db,
+            key_policy: Arc::new(RwLock::new(KeyPolicy::default())),
+            value_format: Arc::new(RwLock::new(ValueFormat::default())),
+        })
+    }
+
+    /// update atomically replaces the value of key with the value f makes of it, returning the
+    /// length of the new value, if the new value is not in the value format the value is left as
+    /// it is, and KvsError::InvalidValue returned
+    fn update<F>(&self, key: &str, mut f: F) -> Result<usize>
+    where
+        F: FnMut(Option<&[u8]>) -> Vec<u8>,
+    {
+        let format = *self.value_format.read();
+        // sled may call the closure more than once, only the last call's outcome holds
+        let mut invalid = None;
+        let val = self.Db.update_and_fetch(key.as_bytes(), |old| {
+            let val = f(old);
+            invalid = format.check(&val).err();
+            match invalid {
+                Some(_) => old.map(|old| old.to_vec()),
+                None => Some(val),
+            }
+        })?;
+        if let Some(e) = invalid {
+            return Err(e);
+        }
+        // the closure returns a value unless the value was invalid
+        Ok(val.map(|val| val.len()).unwrap_or_default())
     }
 }
 
 // implementation of KvsEngine for SledKvsEngine
 impl KvsEngine for SledKvsEngine {
     /// passes a get method to the underlying Sled Db
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.key_policy.read().check(&key)?;
         let res = self.Db.get(&key)?;
         if let Some(vec) = res {
             // this is an ivec, convert to a string, and return the underlying value
@@ -37,7 +104,9 @@ impl KvsEngine for SledKvsEngine {
     }
 
     /// set a value to the underlying SledKvsEngine
-    fn set(&mut self, key: String, val: String) -> Result<()> {
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.key_policy.read().check(&key)?;
+        self.value_format.read().check(val.as_bytes())?;
         // set key, value pair in the SledKvsEngine
         self.Db.insert(key.as_bytes(), val.as_bytes())?;
         // ignore last value if it was set
@@ -45,7 +114,8 @@ impl KvsEngine for SledKvsEngine {
     }
 
     /// remove a value from the underlying SledKvsEngine
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<()> {
+        self.key_policy.read().check(&key)?;
         // remove key from the Db, return error and ignore result
         if let None = self.Db.remove(key.as_bytes())? {
             // return error if the key is not found
@@ -53,4 +123,102 @@ impl KvsEngine for SledKvsEngine {
         }
         Ok(())
     }
+
+    /// flush the underlying SledKvsEngine's dirty buffers to disk
+    fn sync(&self) -> Result<()> {
+        self.Db.flush()?;
+        Ok(())
+    }
+
+    /// remove every key from the underlying SledKvsEngine
+    fn clear(&self) -> Result<()> {
+        self.Db.clear()?;
+        Ok(())
+    }
+
+    /// restrict the keys accepted by the engine
+    fn set_key_policy(&self, policy: KeyPolicy) {
+        *self.key_policy.write() = policy;
+    }
+
+    /// restrict the values accepted by the engine
+    fn set_value_format(&self, format: ValueFormat) {
+        *self.value_format.write() = format;
+    }
+
+    /// the number of keys in the underlying SledKvsEngine
+    fn len(&self) -> Result<usize> {
+        Ok(self.Db.len())
+    }
+
+    /// sled
+    fn name(&self) -> String {
+        "sled".to_owned()
+    }
+
+    /// sled reclaims space on its own, as it rewrites its pages
+    fn compaction(&self) -> String {
+        "managed by sled".to_owned()
+    }
+
+    /// scan the keys of the underlying SledKvsEngine in order, from start on
+    fn scan_page(&self, start: Option<String>, limit: usize) -> Result<Page> {
+        let iter = match &start {
+            Some(start) => self.Db.range(start.as_bytes()..),
+            None => self.Db.iter(),
+        };
+        let mut entries = Vec::new();
+        for item in iter {
+            let (key, val) = item?;
+            let key = String::from_utf8(key.to_vec())?;
+            // the key after the page is where the next page starts
+            if entries.len() == limit {
+                return Ok((entries, Some(key)));
+            }
+            entries.push((key, String::from_utf8(val.to_vec())?));
+        }
+        Ok((entries, None))
+    }
+
+    /// append atomically in the underlying SledKvsEngine
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        self.key_policy.read().check(&key)?;
+        self.update(&key, |old| {
+            let mut val = old.map(|old| old.to_vec()).unwrap_or_default();
+            val.extend_from_slice(suffix.as_bytes());
+            val
+        })
+    }
+
+    /// prepend atomically in the underlying SledKvsEngine
+    fn prepend(&self, key: String, prefix: String) -> Result<usize> {
+        self.key_policy.read().check(&key)?;
+        self.update(&key, |old| {
+            let mut val = prefix.as_bytes().to_vec();
+            val.extend_from_slice(old.unwrap_or_default());
+            val
+        })
+    }
+
+    /// rename in a transaction on the underlying SledKvsEngine
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.key_policy.read().check(&from)?;
+        self.key_policy.read().check(&to)?;
+        let res = self.Db.transaction(|tx| {
+            let val = match tx.remove(from.as_bytes())? {
+                Some(val) => val,
+                None => return Err(ConflictableTransactionError::Abort(())),
+            };
+            tx.insert(to.as_bytes(), val)?;
+            Ok(())
+        });
+        match res {
+            Ok(()) => Ok(()),
+            // from does not exist
+            Err(TransactionError::Abort(())) => {
+                Err(Into::<Box<dyn Error>>::into(ErrKeyNotFound { key: from }))
+            }
+            Err(TransactionError::Storage(e)) => Err(Box::from(e)),
+        }
+    }
 }
@@ -0,0 +1,261 @@
+use crate::engines::kvs_engine::{
+    ErrKeyNotFound, KeyPolicy, KvsEngine, Page, Result, ValueFormat,
+};
+use crossbeam_channel::{unbounded, Sender};
+use log::*;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// WriteBack is the message sent from the TieredEngine to its write-back thread,
+/// mutations are applied to the cold engine in the order they were made against the hot engine
+enum WriteBack {
+    Set { key: String, val: String },
+    Rm { key: String },
+    // acknowledge once every write-back queued before this message has been applied
+    Flush(Sender<()>),
+}
+
+/// TieredEngine is a composite KvsEngine made of a hot engine and a cold engine
+/// # Behavior
+/// get - read from the hot engine, on a miss fall through to the cold engine, promoting the value into
+/// the hot engine if found
+/// set - write to the hot engine, the write is then asynchronously written back to the cold engine
+/// remove - remove from both tiers, ErrKeyNotFound is returned only if the key is in neither tier
+/// Writes, and reads that miss the hot engine, are serialized, reads that hit the hot engine run
+/// concurrently with each other, but never with a write
+pub struct TieredEngine<H: KvsEngine, C: KvsEngine> {
+    // engine that serves reads / writes first
+    hot: H,
+    // engine that holds every value written through the hot engine, shared with the write-back thread
+    cold: Arc<C>,
+    // held exclusively by writes and by promotions from the cold engine, so the hot engine and the
+    // queue of write-backs see writes in the same order, and a promotion never revives a stale value,
+    // shared by reads of the hot engine, so a read never sees a write half applied
+    writes: RwLock<()>,
+    // queue of write-backs to the cold engine, dropped before the worker is joined
+    write_back: Option<Sender<WriteBack>>,
+    // handle to the write-back thread
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<H: KvsEngine, C: KvsEngine> TieredEngine<H, C> {
+    /// instantiate a TieredEngine from a hot and cold engine, and spawn the thread that
+    /// writes mutations back to the cold engine
+    pub fn new(hot: H, cold: C) -> Self {
+        let cold = Arc::new(cold);
+        let (tx, rx) = unbounded::<WriteBack>();
+        let worker_cold = cold.clone();
+        let worker = thread::spawn(move || {
+            // the loop exits once the TieredEngine drops its sender
+            for msg in rx {
+                match msg {
+                    WriteBack::Set { key, val } => {
+                        if let Err(e) = worker_cold.set(key, val) {
+                            error!("write-back of set to cold engine failed: {}", e);
+                        }
+                    }
+                    WriteBack::Rm { key } => match worker_cold.remove(key) {
+                        // the key may never have reached the cold engine, that is fine
+                        Err(e) if !e.is::<ErrKeyNotFound>() => {
+                            error!("write-back of rm to cold engine failed: {}", e);
+                        }
+                        _ => (),
+                    },
+                    WriteBack::Flush(ack) => {
+                        let _ = ack.send(());
+                    }
+                }
+            }
+        });
+        TieredEngine {
+            hot,
+            cold,
+            writes: RwLock::new(()),
+            write_back: Some(tx),
+            worker: Some(worker),
+        }
+    }
+
+    /// flush blocks until every write-back queued so far has been applied to the cold engine
+    pub fn flush(&self) -> Result<()> {
+        let (tx, rx) = unbounded();
+        self.queue(WriteBack::Flush(tx))?;
+        rx.recv()?;
+        Ok(())
+    }
+
+    /// queue a write-back for the worker thread
+    fn queue(&self, msg: WriteBack) -> Result<()> {
+        self.write_back
+            .as_ref()
+            .ok_or("write-back thread has been shut down")?
+            .send(msg)
+            .map_err(|_| "write-back thread has exited".into())
+    }
+
+    /// get the value from the hot engine, falling through to the cold engine on a miss, values
+    /// found in the cold engine are promoted into the hot engine. The caller must hold the writes
+    /// lock exclusively
+    fn get_locked(&self, key: String) -> Result<Option<String>> {
+        if let Some(val) = self.hot.get(key.clone())? {
+            return Ok(Some(val));
+        }
+        // a pending write-back may be a removal of this key, apply it before reading the cold engine
+        self.flush()?;
+        let val = self.cold.get(key.clone())?;
+        if let Some(val) = &val {
+            // promote, the cold engine already holds this value so nothing is written back
+            self.hot.set(key, val.to_owned())?;
+        }
+        Ok(val)
+    }
+
+    /// set the value in the hot engine, and queue the write-back to the cold engine, the caller
+    /// must hold the writes lock exclusively
+    fn set_locked(&self, key: String, val: String) -> Result<()> {
+        self.hot.set(key.clone(), val.clone())?;
+        self.queue(WriteBack::Set { key, val })
+    }
+
+    /// remove the value from both engines, the caller must hold the writes lock exclusively
+    fn remove_locked(&self, key: String) -> Result<()> {
+        match self.hot.remove(key.clone()) {
+            // the cold engine may also hold the key, queue its removal
+            Ok(()) => self.queue(WriteBack::Rm { key }),
+            Err(e) if e.is::<ErrKeyNotFound>() => {
+                // the key only lives in the cold engine, remove it directly so a miss is reported synchronously
+                self.flush()?;
+                self.cold.remove(key)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<H: KvsEngine, C: KvsEngine> KvsEngine for TieredEngine<H, C> {
+    /// set the value in the hot engine, and queue the write-back to the cold engine
+    fn set(&self, key: String, val: String) -> Result<()> {
+        let _writes = self.writes.write();
+        self.set_locked(key, val)
+    }
+
+    /// get the value from the hot engine, falling through to the cold engine on a miss
+    /// values found in the cold engine are promoted into the hot engine
+    fn get(&self, key: String) -> Result<Option<String>> {
+        {
+            let _reads = self.writes.read();
+            if let Some(val) = self.hot.get(key.clone())? {
+                return Ok(Some(val));
+            }
+        }
+        // the key may have been written since the read lock was released, get_locked checks the hot
+        // engine again before falling through to the cold engine
+        let _writes = self.writes.write();
+        self.get_locked(key)
+    }
+
+    /// remove the value from both engines, returns ErrKeyNotFound if neither engine has the key
+    fn remove(&self, key: String) -> Result<()> {
+        let _writes = self.writes.write();
+        self.remove_locked(key)
+    }
+
+    /// sync both engines, after applying every pending write-back to the cold engine
+    fn sync(&self) -> Result<()> {
+        self.hot.sync()?;
+        self.flush()?;
+        self.cold.sync()
+    }
+
+    /// both engines enforce the policy, so keys are rejected before either engine is touched
+    fn set_key_policy(&self, policy: KeyPolicy) {
+        self.hot.set_key_policy(policy.clone());
+        self.cold.set_key_policy(policy);
+    }
+
+    /// both engines check the format, so values are rejected before either engine is touched
+    fn set_value_format(&self, format: ValueFormat) {
+        self.hot.set_value_format(format);
+        self.cold.set_value_format(format);
+    }
+
+    /// every key is written back to the cold engine, so once write-backs are applied the cold
+    /// engine holds every key
+    fn len(&self) -> Result<usize> {
+        self.flush()?;
+        self.cold.len()
+    }
+
+    /// the names of both engines
+    fn name(&self) -> String {
+        format!("tiered, {} over {}", self.hot.name(), self.cold.name())
+    }
+
+    /// the compaction of both engines
+    fn compaction(&self) -> String {
+        format!(
+            "hot: {}, cold: {}",
+            self.hot.compaction(),
+            self.cold.compaction()
+        )
+    }
+
+    /// as for len, once write-backs are applied the cold engine holds every key
+    fn scan_page(&self, start: Option<String>, limit: usize) -> Result<Page> {
+        self.flush()?;
+        self.cold.scan_page(start, limit)
+    }
+
+    /// clear both engines, after applying every pending write-back to the cold engine
+    fn clear(&self) -> Result<()> {
+        let _writes = self.writes.write();
+        self.flush()?;
+        self.hot.clear()?;
+        self.cold.clear()
+    }
+
+    /// the read and write happen under the writes lock, so concurrent appends are never lost
+    fn append(&self, key: String, suffix: String) -> Result<usize> {
+        let _writes = self.writes.write();
+        let val = self.get_locked(key.clone())?.unwrap_or_default() + &suffix;
+        let len = val.len();
+        self.set_locked(key, val)?;
+        Ok(len)
+    }
+
+    /// the read and write happen under the writes lock, so concurrent prepends are never lost
+    fn prepend(&self, key: String, prefix: String) -> Result<usize> {
+        let _writes = self.writes.write();
+        let val = prefix + &self.get_locked(key.clone())?.unwrap_or_default();
+        let len = val.len();
+        self.set_locked(key, val)?;
+        Ok(len)
+    }
+
+    /// the get, set and remove happen under the writes lock, so no other command sees from and
+    /// to both set, or both missing
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let _writes = self.writes.write();
+        let val = match self.get_locked(from.clone())? {
+            Some(val) => val,
+            None => return Err(Box::from(ErrKeyNotFound { key: from })),
+        };
+        if from == to {
+            return Ok(());
+        }
+        self.set_locked(to, val)?;
+        self.remove_locked(from)
+    }
+}
+
+/// impl Drop for TieredEngine, close the write-back queue and wait for the worker to drain it,
+/// so the cold engine is up to date once the TieredEngine is gone
+impl<H: KvsEngine, C: KvsEngine> Drop for TieredEngine<H, C> {
+    fn drop(&mut self) {
+        drop(self.write_back.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
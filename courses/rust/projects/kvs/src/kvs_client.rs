@@ -1,63 +1,387 @@
-use crate::engines::{kvs::CommandData, kvs_engine::Result};
+use crate::engines::{
+    kvs::{Change, CommandData},
+    kvs_engine::{ErrKeyNotFound, KvsError, Page, Result},
+};
+use crate::protocol::{client_handshake, copy_chunks, read_frame, write_frame, Info, Response};
+use crate::stats::Stats;
+use crate::transport::Transport;
 use log::*;
-use serde_json;
+use rustls::ClientConfig;
 use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-/// kvs-client is composed of
-/// 1. StdErrLog, as well as a
-/// 2. TcpStream connected to the addr passed in KvsClient::init()
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// the number of times a command is resent after the connection to the server fails
+const RETRIES: u32 = 3;
+/// the wait before the first retry, doubled on each retry after it
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// kvs-client is composed of a TcpStream connected to the addr passed in KvsClient::init(),
+/// the connection is kept open so a client may send any number of commands over it, and may
+/// be encrypted with TLS, see KvsClient::init_tls
+/// A client of a primary and its replicas sends reads to the replicas, see KvsClient::init_cluster
+/// A command whose connection to the primary fails is resent over a new connection, up to RETRIES
+/// times, writes are sent with a request id that is kept across the retries, so a write the
+/// server handled before the connection failed is not applied twice, unless the server is too old
+/// to know request ids, see CommandData::Idempotent
 pub struct KvsClient {
-    stream: TcpStream,
-    log: stderrlog::StdErrLog,
+    // connection to the server, the primary of a cluster
+    stream: Transport,
+    // opens a new connection to the server, handshaken, replacing stream after a failure
+    connect: Box<dyn Fn() -> Result<Transport> + Send>,
+    // replicas reads are spread across, round-robin, empty unless the client is of a cluster
+    replicas: Vec<Replica>,
+    // the replica the next read is sent to
+    next_replica: usize,
+    // the replica the last read was answered by, None if it was answered by the primary
+    read_from: Option<usize>,
+    // whether writes are sent wrapped in Idempotent, false once the server has answered that it
+    // does not know Idempotent
+    idempotent: bool,
+}
+
+/// Replica is a replica of a cluster's primary, connected to once a read is sent to it, and
+/// reconnected to after a failure
+struct Replica {
+    addr: SocketAddr,
+    conn: Option<Transport>,
+}
+
+impl Replica {
+    /// send cmd to the replica, connecting first if need be, the connection is dropped on failure
+    fn request(&mut self, cmd: &CommandData) -> Result<Response> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let mut conn = Transport::Plain(TcpStream::connect(self.addr)?);
+                client_handshake(&mut conn)?;
+                self.conn.insert(conn)
+            }
+        };
+        let res = exchange(conn, cmd);
+        if res.is_err() {
+            self.conn = None;
+        }
+        res
+    }
 }
 
 impl KvsClient {
     /// KvsClient init, this method instantiates a TcpStream with the provided address
-    /// and a StdErrLog
+    /// and a StdErrLog, the client then handshakes with the server
+    /// # Errors
+    /// ErrVersionMismatch - the server speaks a different protocol version
     pub fn init<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
-        // first connect to socket provided,  and return the boxed err if necessary
-        let stream = TcpStream::connect(addr).map_err(|err| Box::<dyn Error>::from(err))?;
+        // the address is resolved once, reconnects go to the same server
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        Self::handshake(Box::new(move || {
+            // connect to socket provided, and return the boxed err if necessary
+            let stream =
+                TcpStream::connect(&addrs[..]).map_err(|err| Box::<dyn Error>::from(err))?;
+            Ok(Transport::Plain(stream))
+        }))
+    }
+
+    /// KvsClient init_tls, this method connects to the server as init does, encrypting the
+    /// connection with TLS under config, see transport::client_tls_config. The server's
+    /// certificate must be valid for server_name, i.e the host in addr
+    /// # Errors
+    /// the TLS handshake fails, i.e the server is not speaking TLS, or its certificate is not
+    /// trusted, see init
+    pub fn init_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<KvsClient> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let server_name = server_name.to_owned();
+        Self::handshake(Box::new(move || {
+            let stream = TcpStream::connect(&addrs[..])?;
+            Transport::client_tls(stream, &server_name, config.clone())
+        }))
+    }
+
+    /// connect to the server, and handshake with it, the TLS handshake, if any, is made first
+    fn handshake(connect: Box<dyn Fn() -> Result<Transport> + Send>) -> Result<KvsClient> {
+        // agree on a protocol version before any commands are sent, on every connection
+        let connect = Box::new(move || {
+            let mut stream = connect()?;
+            client_handshake(&mut stream)?;
+            Ok(stream)
+        });
+        let stream = connect()?;
+        // a logger may already be installed by an earlier client in this process
+        let _ = stderrlog::new().verbosity(3).init();
         // return the KvsClient to caller
         Ok(KvsClient {
             stream: stream,
-            log: stderrlog::new().verbosity(3).to_owned(),
+            connect,
+            replicas: Vec::new(),
+            next_replica: 0,
+            read_from: None,
+            idempotent: true,
         })
     }
 
+    /// KvsClient init_cluster, this method connects to the primary as init does, the replicas are
+    /// connected to once reads are sent to them. Writes, and every command other than a get, len
+    /// or scan, are sent to the primary, reads are sent to the replicas round-robin. A replica
+    /// that cannot be reached is skipped, and reconnected to on its next turn, once every replica
+    /// has failed a read, the read is sent to the primary
+    /// Replicas apply the primary's writes as they stream in, so a read from a replica may not
+    /// see a write the primary has just acknowledged
+    /// # Errors
+    /// see init, only the primary must be reachable
+    pub fn init_cluster<A: ToSocketAddrs>(
+        primary: A,
+        replicas: &[SocketAddr],
+    ) -> Result<KvsClient> {
+        let mut client = Self::init(primary)?;
+        client.replicas = replicas
+            .iter()
+            .map(|&addr| Replica { addr, conn: None })
+            .collect();
+        Ok(client)
+    }
+
     ///KvsClient send, this method  sends a serialized command over the TcpStream
     /// to the KvsServer
+    /// returns the value for a get, the new length for an append / prepend, the number of keys
+    /// for a len, the server's stats for a stats, the last access in milliseconds since the unix
+    /// epoch, or "never", for an accessed, or "Key not found" if a get / rm / rename / touch /
+    /// accessed targeted a missing key
     pub fn send(&mut self, cmd: &CommandData) -> Result<Option<String>> {
-        self.log.init()?;
-        // write serialized bytes to TcpStream
-        info!("sending request: {:?}", cmd);
-        // send request
-        let mut buf = Vec::<u8>::new();
-        serde_json::to_writer(&mut buf, cmd).map_err(|err| Box::<dyn Error>::from(err))?;
-        // write the buffer to TcpStream
-        self.stream.write(&buf)?;
-        // now receive the request
-        match cmd {
-            CommandData::Get { key: _ } => {
-                info!("receiving response");
-                let mut buf = String::new();
-                // receive data from stream
-                self.stream.read_to_string(&mut buf)?;
-                // return value
-                Ok(Some(buf))
-            }
-            CommandData::Rm { key: _ } => {
-                info!("receiving response");
-                let mut buf = String::new();
-                // receive data from stream
-                let size = self.stream.read_to_string(&mut buf)?;
-                if size != 0 {
-                    return Ok(Some(buf));
+        if let CommandData::Get { key } = cmd {
+            // collect the streamed value
+            let mut buf = Vec::<u8>::new();
+            if !self.get_to(key.to_owned(), &mut buf)? {
+                return Ok(Some("Key not found".to_owned()));
+            }
+            return Ok(Some(String::from_utf8(buf)?));
+        }
+        match self.request(cmd)? {
+            Response::Ok => Ok(None),
+            Response::KeyNotFound => Ok(Some("Key not found".to_owned())),
+            Response::Len(len) => Ok(Some(len.to_string())),
+            Response::Stats(stats) => Ok(Some(stats.to_string())),
+            Response::Accessed(at) => Ok(Some(at.map_or("never".to_owned(), |at| at.to_string()))),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient stats, this method asks the server for the commands it has handled, and the rate
+    /// it handled them at over the last window seconds, see ServerStats::snapshot
+    pub fn stats(&mut self, window: u64) -> Result<Stats> {
+        match self.request(&CommandData::Stats { window })? {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient info, this method asks the server for its build and configuration
+    pub fn info(&mut self) -> Result<Info> {
+        match self.request(&CommandData::Info)? {
+            Response::Info(info) => Ok(info),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient scan_page, this method asks the server for up to limit (key, value) pairs in key
+    /// order from start on, and the key the next page starts at, see KvsEngine::scan_page
+    pub fn scan_page(&mut self, start: Option<String>, limit: usize) -> Result<Page> {
+        match self.request(&CommandData::Scan { start, limit })? {
+            Response::Page { entries, next } => Ok((entries, next)),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient commit_offset, this method asks the server to flush prior writes, and for the
+    /// offset of the end of its log, which may be passed to replicate to resume from there, see
+    /// KvsEngine::commit_offset
+    pub fn commit_offset(&mut self) -> Result<Option<u64>> {
+        match self.request(&CommandData::CommitOffset)? {
+            Response::Offset(offset) => Ok(offset),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient last_accessed, this method asks the server when key was last accessed, see
+    /// KvsEngine::last_accessed
+    /// # Errors
+    /// ErrKeyNotFound - the key does not exist
+    pub fn last_accessed(&mut self, key: String) -> Result<Option<SystemTime>> {
+        match self.request(&CommandData::Accessed { key: key.clone() })? {
+            Response::Accessed(at) => Ok(at.map(|at| UNIX_EPOCH + Duration::from_millis(at))),
+            Response::KeyNotFound => Err(Box::from(ErrKeyNotFound { key })),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient get_versions, this method asks the server for up to limit of the values key has
+    /// been set to, newest first, see KvsEngine::get_versions
+    pub fn get_versions(&mut self, key: String, limit: usize) -> Result<Vec<String>> {
+        match self.request(&CommandData::History { key, limit })? {
+            Response::Versions(versions) => Ok(versions),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient get_to, this method sends a get for key, and writes each chunk of the value
+    /// to out as it arrives from the server, so the value is never held in memory
+    /// returns false if the key does not exist
+    pub fn get_to<W: Write + ?Sized>(&mut self, key: String, out: &mut W) -> Result<bool> {
+        match self.request(&CommandData::Get { key })? {
+            Response::Stream { len } => {
+                let res = match self.read_from {
+                    Some(i) => {
+                        let replica = &mut self.replicas[i];
+                        let res = copy_chunks(replica.conn.as_mut().unwrap(), out, len);
+                        // the rest of the value is still on the connection, it cannot be reused
+                        if res.is_err() {
+                            replica.conn = None;
+                        }
+                        res
+                    }
+                    None => copy_chunks(&mut self.stream, out, len),
+                };
+                res.map(|_| true)
+            }
+            Response::KeyNotFound => Ok(false),
+            Response::Err(msg) => Err(Box::from(msg)),
+            res => Err(Box::from(format!("unexpected response: {:?}", res))),
+        }
+    }
+
+    /// KvsClient replicate, this method asks the server for every mutation recorded from
+    /// from_offset onward, and passes each to f as it arrives. The server keeps the stream open,
+    /// sending mutations as they are written, so this only returns once the connection fails or
+    /// f returns an error
+    pub fn replicate<F: FnMut(Change) -> Result<()>>(
+        &mut self,
+        from_offset: u64,
+        mut f: F,
+    ) -> Result<()> {
+        info!("replicating from offset {}", from_offset);
+        write_frame(&mut self.stream, &CommandData::Replicate { from_offset })?;
+        loop {
+            match read_frame(&mut self.stream)? {
+                Response::Change(change) => f(change)?,
+                // the server sends a heartbeat while there are no changes
+                Response::Ok => (),
+                Response::Err(msg) => return Err(Box::from(msg)),
+                res => return Err(Box::from(format!("unexpected response: {:?}", res))),
+            }
+        }
+    }
+
+    /// send the command to the server, reads are sent to the next replica that answers, if the
+    /// client is of a cluster, see init_cluster
+    fn request(&mut self, cmd: &CommandData) -> Result<Response> {
+        self.read_from = None;
+        if cmd.is_read() && !self.replicas.is_empty() {
+            for _ in 0..self.replicas.len() {
+                let i = self.next_replica;
+                self.next_replica = (i + 1) % self.replicas.len();
+                match self.replicas[i].request(cmd) {
+                    Ok(res) => {
+                        self.read_from = Some(i);
+                        return Ok(res);
+                    }
+                    Err(e) => warn!("replica {} failed: {}", self.replicas[i].addr, e),
                 }
-                Ok(None)
-                // return value
             }
-            _ => Ok(None),
+            warn!("every replica failed, reading from the primary");
         }
+        // a write is sent with a request id, so the server does not apply a retry of it twice
+        if cmd.is_write() && self.idempotent {
+            let idempotent = CommandData::Idempotent {
+                request_id: request_id(),
+                cmd: Box::new(cmd.clone()),
+            };
+            match self.retry(&idempotent) {
+                // the server predates Idempotent, and did not handle the write, it is resent as is
+                Err(e) if is_unsupported(&*e, "Idempotent") => {
+                    warn!("the server does not support idempotent writes, retried writes may be applied twice");
+                    self.idempotent = false;
+                }
+                res => return res,
+            }
+        }
+        self.retry(cmd)
     }
+
+    /// send the command to the server, resending it over a new connection if the connection
+    /// fails, up to RETRIES times
+    fn retry(&mut self, cmd: &CommandData) -> Result<Response> {
+        let mut backoff = RETRY_BACKOFF;
+        for retry in 1..=RETRIES {
+            match exchange(&mut self.stream, cmd) {
+                // the command may or may not have reached the server, resend it
+                Err(e) if e.is::<io::Error>() => {
+                    warn!(
+                        "{} failed: {}, retrying in {:?} ({} of {})",
+                        cmd.name(),
+                        e,
+                        backoff,
+                        retry,
+                        RETRIES
+                    );
+                }
+                res => return res,
+            }
+            thread::sleep(backoff);
+            backoff *= 2;
+            // the failed connection is kept if the server cannot be reached, failing the retry
+            match (self.connect)() {
+                Ok(stream) => self.stream = stream,
+                Err(e) => warn!("reconnecting failed: {}", e),
+            }
+        }
+        exchange(&mut self.stream, cmd)
+    }
+}
+
+/// whether e is the server answering that it does not know the command name
+fn is_unsupported(e: &(dyn Error + 'static), name: &str) -> bool {
+    matches!(
+        e.downcast_ref::<KvsError>(),
+        Some(KvsError::UnsupportedCommand { name: unsupported, .. }) if unsupported == name
+    )
+}
+
+/// a random (version 4) UUID, identifying a command across its retries
+fn request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    // the version, and the variant of RFC 4122
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// write the framed command to the server, and read the framed response
+fn exchange(stream: &mut Transport, cmd: &CommandData) -> Result<Response> {
+    // write serialized bytes to TcpStream
+    info!("sending request: {:?}", cmd);
+    write_frame(stream, cmd)?;
+    // now receive the response, a command the server does not know is returned as an error
+    info!("receiving response");
+    read_frame::<_, Response>(stream)?.into_result()
 }
@@ -1,43 +1,102 @@
+use crate::kvs_client::KvsClient;
 use crate::thread_pool::naive::*;
 use crate::{
     engines::{
         kvs::{CommandData, KvStore},
-        kvs_engine::{ErrKeyNotFound, KvsEngine, Result, SharedKvsEngine},
+        kvs_engine::{
+            ErrKeyNotFound, KeyPolicy, KvsEngine, KvsError, Result, SharedKvsEngine, ValueFormat,
+        },
         sled::SledKvsEngine,
     },
+    protocol::{
+        copy_chunks, read_command, server_handshake, write_frame, Info, Response, PROTOCOL_VERSION,
+    },
+    stats::{ServerStats, Stats},
     thread_pool::ThreadPool,
+    transport::Transport,
 };
 use log::*;
-use serde_json;
+use parking_lot::{Condvar, Mutex, RwLock, RwLockReadGuard};
+use rustls::ServerConfig;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use stderrlog;
+
+/// how often a replication stream checks the engine for new changes
+const REPLICATION_POLL: Duration = Duration::from_millis(50);
+/// a replication stream with no changes sends a heartbeat this often, so a replica that has gone
+/// away is noticed
+const REPLICATION_HEARTBEAT: Duration = Duration::from_secs(1);
+/// a replica whose connection to the primary fails waits this long before reconnecting
+const REPLICATION_RETRY: Duration = Duration::from_secs(1);
+/// once accept fails for lack of file descriptors or memory the server waits this long before
+/// accepting again, doubling on each consecutive error up to ACCEPT_BACKOFF_MAX
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+/// the longest the server waits between accepts while they keep failing
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// the number of request ids whose responses the server remembers, see RequestCache
+const REQUEST_CACHE_SIZE: usize = 10_000;
+
 /// the kvs-server is composed of three parts
 /// 1. A TcpListener - this listener is spawned
 /// 2. A storage engine - impl KvStore, this is what will be
-/// used to process requests from clients
+///    used to process requests from clients
 /// 3. A slog::Logger, this will be used to log messages from server running in prod
 pub struct KvsServer {
     engine: SharedKvsEngine,
     listener: TcpListener,
     log: stderrlog::StdErrLog,
+    // connections idle for longer than this are closed
+    idle_timeout: Option<Duration>,
+    // the primary this server replicates from, if any
+    replicate_from: Option<SocketAddr>,
+    // commands taking longer than this are logged
+    slow_log_threshold: Option<Duration>,
+    // commands handled, shared by every connection
+    stats: Arc<ServerStats>,
+    // responses to the latest idempotent commands, shared by every connection, as a retry is
+    // usually sent over a new connection
+    requests: Arc<RequestCache>,
+    // connections are encrypted with this TLS config, plaintext if None
+    tls: Option<Arc<ServerConfig>>,
+    // the most requests a connection may make per second, unlimited if None
+    max_rps: Option<u32>,
+    // writes are let through this, unless they are paused
+    writes: Arc<WriteGate>,
+    // clients may pause and resume writes
+    allow_pause: bool,
+    // set by ServerHandle::shutdown, the accept loop stops once it is set
+    shutdown: Arc<AtomicBool>,
 }
 
 impl KvsServer {
     /// KvsServer init, this method instantiates a KvStore in the current directory,
     /// binds a TcpListener to the provided socket, and instantiates a logger to stderr
+    /// The engine is opened before the socket is bound, so a server whose engine fails to open
+    /// never accepts a connection
     /// This returns a Result<KvsServer>
     pub fn init<A: ToSocketAddrs>(addr: A, is_sled: bool) -> Result<KvsServer> {
-        // first bind to the socket provided, and return the boxed error if necessary
-        let listener = TcpListener::bind(addr).map_err(|err| Into::<Box<dyn Error>>::into(err))?;
-        // now that server is listening on provided port, open a KvStore in ./
+        // first open the engine, i.e a KvStore in ./ or a SledKvsEngine in ./db
         let engine: SharedKvsEngine;
         if is_sled {
             engine = SharedKvsEngine::from(SledKvsEngine::open("./db")?);
         } else {
             engine = SharedKvsEngine::from(KvStore::open("./")?)
         }
+        Self::with_engine(addr, engine)
+    }
+
+    /// KvsServer with_engine, this method serves an already opened engine, binding a TcpListener
+    /// to the provided socket, and instantiating a logger to stderr
+    pub fn with_engine<A: ToSocketAddrs>(addr: A, engine: SharedKvsEngine) -> Result<KvsServer> {
+        // the engine is ready, bind to the socket provided, and return the boxed error if necessary
+        let listener = TcpListener::bind(addr).map_err(|err| Into::<Box<dyn Error>>::into(err))?;
 
         // finally create the logger and recieve requests from the stream
         let log = stderrlog::new().verbosity(3).to_owned();
@@ -45,108 +104,1327 @@ impl KvsServer {
             engine: engine,
             listener: listener,
             log: log,
+            idle_timeout: None,
+            replicate_from: None,
+            slow_log_threshold: None,
+            stats: ServerStats::new(),
+            requests: Arc::new(RequestCache::new(REQUEST_CACHE_SIZE)),
+            tls: None,
+            max_rps: None,
+            writes: Arc::new(WriteGate::default()),
+            allow_pause: false,
+            shutdown: Arc::new(AtomicBool::new(false)),
         })
     }
     /// KvsServer serve, this method instantiates a KvStore in the current directory
     /// Instantiates it's logger, and begins serving on the designated port / address
-    /// It returns a Result<()>
-    pub fn serve<A: ThreadPool>(&mut self, mut pool: A) -> Result<()> {
-        // init logger
-        self.log.init().map_err(Box::<dyn Error>::from)?;
-        // initialze 100 threads
+    /// It returns a Result<()>, once the server is shut down through its ServerHandle, see spawn
+    /// Each connection waits for its commands on a thread of its own, and the commands are
+    /// handled on pool, so idle connections do not hold the pool's threads
+    pub fn serve<A: ThreadPool + Send + 'static>(&mut self, pool: A) -> Result<()> {
+        // init logger, unless the application embedding the server already installed one
+        let _ = self.log.init();
+        // follow the primary in the background, writes are applied as the primary makes them
+        if let Some(primary) = self.replicate_from {
+            let engine = self.engine.clone();
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || Self::follow(primary, engine, &shutdown));
+        }
+        // answered to every info, the configuration does not change while serving
+        let info = Arc::new(self.info(&pool)?);
+        let config = Arc::new(ConnectionConfig {
+            tls: self.tls.clone(),
+            idle_timeout: self.idle_timeout,
+            slow_log_threshold: self.slow_log_threshold,
+            max_rps: self.max_rps,
+            writes: self.writes.clone(),
+            allow_pause: self.allow_pause,
+        });
+        // the connections being served, drained once the server is shut down
+        let connections = Arc::new(Connections::default());
+        // shared by every connection's thread
+        let pool = Arc::new(Mutex::new(pool));
         // iterate over all active connections
+        let mut backoff = ACCEPT_BACKOFF;
         for stream in self.listener.try_clone()?.incoming() {
+            // shutdown wakes the loop with a connection of its own, stop accepting
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
-                Ok(mut stream) => {
-                    // log client request
-                    info!("connection request: {:?}", stream);
-                    // read the data and return err if needed
-                    let mut buf = [0 as u8; 100];
-                    // read data from stream into buf
-                    // if there is a failure reading, close the connection on both sides
-                    let cmd: CommandData;
-                    // read into fixed size buf so the reader doesn't block for FIN packet
-                    match stream.read(&mut buf) {
+                Ok(stream) => {
+                    // the connection is registered with a clone of its socket, if the process is
+                    // out of descriptors for the clone, the connection is dropped
+                    let registered = match connections.register(&stream) {
+                        Ok(registered) => registered,
                         Err(e) => {
-                            info!("error: {:?}", e);
-                            // shutdown stream, `send` FIN packet to client to stop reading stream
-                            stream.shutdown(Shutdown::Both)?;
-                            return Err(Box::<dyn Error>::from(e));
+                            warn!(
+                                "error accepting connection: {}, retrying in {:?}",
+                                e, backoff
+                            );
+                            thread::sleep(backoff);
+                            backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                            continue;
                         }
-                        Ok(size) => {
-                            // read [0..size] into Command data
-                            // deserialize
-                            cmd = serde_json::from_slice(&buf[0..size])
-                                .map_err(Box::<dyn Error>::from)?;
-                        }
-                    }
-                    // handle request
+                    };
+                    backoff = ACCEPT_BACKOFF;
+                    // log client request
+                    info!("connection request: {:?}", stream);
+                    // wait for the connection's commands on a thread of its own, until the client
+                    // hangs up
                     let eng = self.engine.clone();
-                    pool.spawn(|| {
-                        Self::handle_request(eng, cmd, stream);
-                    })
+                    let config = config.clone();
+                    let stats = self.stats.clone();
+                    let requests = self.requests.clone();
+                    let info = info.clone();
+                    let pool = pool.clone();
+                    thread::spawn(move || {
+                        // the connection is deregistered once it is closed
+                        let _registered = registered;
+                        let handled = Self::handle_connection(
+                            eng, stream, config, stats, requests, info, pool,
+                        );
+                        if let Err(e) = handled {
+                            error!("error handling connection: {}", e);
+                        }
+                    });
+                }
+                Err(e) if is_transient_accept_error(&e) => {
+                    warn!("error accepting connection: {}", e);
+                }
+                Err(e) if is_exhausted_accept_error(&e) => {
+                    // i.e the process is out of file descriptors, the connection waits in the
+                    // backlog, and is accepted once a descriptor is freed
+                    warn!(
+                        "error accepting connection: {}, retrying in {:?}",
+                        e, backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
                 }
                 Err(e) => {
-                    // return the error if there is error in recv of TcpStream
+                    // return the error if the listener itself is unusable
                     return Err(Box::from(e));
                 }
             }
         }
+        // let every connection finish the command it is handling, and wait for them to close,
+        // the pool is dropped once they have
+        connections.drain();
+        Ok(())
+    }
+
+    /// KvsServer spawn, serves on pool from a background thread, see serve, for embedding the
+    /// server in another program. The returned ServerHandle shuts the server down
+    pub fn spawn<A: ThreadPool + Send + 'static>(mut self, pool: A) -> Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let engine = self.engine.clone();
+        let shutdown = self.shutdown.clone();
+        let writes = self.writes.clone();
+        // the error is sent back as a string, as the boxed error cannot leave the thread
+        let thread = thread::spawn(move || self.serve(pool).map_err(|e| e.to_string()));
+        Ok(ServerHandle {
+            addr,
+            engine,
+            shutdown,
+            writes,
+            thread,
+        })
+    }
+
+    /// KvsServer pause_writes, from now on writes are answered with KvsError::Unavailable, and
+    /// not handled, while reads are served as usual. This returns once every write already being
+    /// handled has finished, so the engine may be snapshotted or compacted without writes landing
+    /// under it, until resume_writes
+    pub fn pause_writes(&self) {
+        self.writes.pause();
+    }
+
+    /// KvsServer resume_writes, writes are handled again, see pause_writes
+    pub fn resume_writes(&self) {
+        self.writes.resume();
+    }
+
+    /// KvsServer set_allow_pause, clients may pause and resume writes with the Pause and Resume
+    /// commands, see pause_writes, false (the default) answers them with an error
+    pub fn set_allow_pause(&mut self, allow: bool) {
+        self.allow_pause = allow;
+    }
+
+    /// KvsServer set_key_policy, commands on keys the policy rejects are answered with an error
+    pub fn set_key_policy(&mut self, policy: KeyPolicy) {
+        self.engine.set_key_policy(policy);
+    }
+
+    /// KvsServer set_value_format, writes of values not in format are answered with an error
+    pub fn set_value_format(&mut self, format: ValueFormat) {
+        self.engine.set_value_format(format);
+    }
+
+    /// KvsServer engine, a handle to the engine the server is serving
+    pub fn engine(&self) -> SharedKvsEngine {
+        self.engine.clone()
+    }
+
+    /// KvsServer stats, the commands the server has handled, and the rate it handled them at over
+    /// the last window seconds, see ServerStats::snapshot
+    pub fn stats(&self, window: u64) -> Stats {
+        self.stats.snapshot(window)
+    }
+
+    /// KvsServer info, the server's build and configuration, when serving on pool
+    pub fn info<A: ThreadPool>(&self, pool: &A) -> Result<Info> {
+        let thread_pool = std::any::type_name::<A>();
+        Ok(Info {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            engine: self.engine.name(),
+            compaction: self.engine.compaction(),
+            // the name of the type, without its path
+            thread_pool: thread_pool
+                .rsplit("::")
+                .next()
+                .unwrap_or(thread_pool)
+                .to_owned(),
+            threads: pool.threads(),
+            addr: self.local_addr()?.to_string(),
+        })
+    }
+
+    /// KvsServer local_addr, the address the listener is bound to, when the server is bound to
+    /// port 0 this reports the port chosen by the OS
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// KvsServer set_replicate_from, the server follows the primary at addr, applying every
+    /// set / rm the primary makes to its own engine. Only a single primary is supported, and the
+    /// primary's log must only be appended to, compaction or clear on the primary are not
+    /// replicated
+    pub fn set_replicate_from(&mut self, addr: Option<SocketAddr>) {
+        self.replicate_from = addr;
+    }
+
+    /// KvsServer set_slow_log_threshold, commands whose engine call takes longer than threshold
+    /// are logged as a warning, with their key and the time taken. None (the default) disables
+    /// the slow log, and commands are not timed at all
+    pub fn set_slow_log_threshold(&mut self, threshold: Option<Duration>) {
+        self.slow_log_threshold = threshold;
+    }
+
+    /// KvsServer set_tls, every connection accepted from now on is encrypted with TLS under
+    /// config, see transport::server_tls_config, None (the default) serves plaintext. Clients
+    /// speaking plaintext to a TLS server fail the TLS handshake, and are disconnected
+    pub fn set_tls(&mut self, config: Option<Arc<ServerConfig>>) {
+        self.tls = config;
+    }
+
+    /// KvsServer set_max_rps, each connection may make at most max_rps requests a second, with
+    /// bursts of up to max_rps requests, requests over the rate are not handled, and are answered
+    /// with KvsError::RateLimited. Each connection has a rate of its own, so one client making
+    /// too many requests does not slow the others. None (the default) does not limit requests
+    pub fn set_max_rps(&mut self, max_rps: Option<u32>) {
+        self.max_rps = max_rps;
+    }
+
+    /// KvsServer set_idle_timeout, connections that send no command for longer than timeout
+    /// are closed, None (the default) keeps idle connections open indefinitely
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// KvsServer handle_connection, this is a private method, it handshakes with the client, and
+    /// then handles the client's commands until the client closes the connection, or stays idle
+    /// for longer than the idle timeout. Each command is handled on pool, the connection is handed
+    /// to a worker for the command and back once it is answered, except a replication stream,
+    /// which lasts as long as the replica follows, and is served from the connection's thread
+    fn handle_connection<A: ThreadPool + Send + 'static>(
+        engine: SharedKvsEngine,
+        stream: TcpStream,
+        config: Arc<ConnectionConfig>,
+        stats: Arc<ServerStats>,
+        requests: Arc<RequestCache>,
+        info: Arc<Info>,
+        pool: Arc<Mutex<A>>,
+    ) -> Result<()> {
+        let idle_timeout = config.idle_timeout;
+        // every read is bounded by the idle timeout, including the handshakes
+        stream.set_read_timeout(idle_timeout)?;
+        // the TLS handshake is made as the protocol handshake is read
+        let mut stream = match &config.tls {
+            Some(tls) => Transport::server_tls(stream, tls.clone())?,
+            None => Transport::Plain(stream),
+        };
+        // the connection's requests are limited by a bucket of its own, dropped with the connection
+        let mut bucket = config.max_rps.map(TokenBucket::new);
+        // reject clients speaking another protocol version before reading any command
+        if let Err(e) = server_handshake(&mut stream) {
+            error!("rejecting connection {:?}: {}", stream.tcp().peer_addr(), e);
+            let _ = stream.shutdown();
+            return Ok(());
+        }
+        loop {
+            // wait for the next command without consuming it, timing out here means the client
+            // is idle, whereas a timeout once a command has started means the client stalled
+            match stream.readable() {
+                // the client closed the connection
+                Ok(false) => break,
+                Ok(true) => (),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    info!(
+                        "closing connection {:?}, idle for {:?}",
+                        stream.tcp().peer_addr(),
+                        idle_timeout
+                    );
+                    break;
+                }
+                Err(e) => return Err(Box::from(e)),
+            }
+            // read the framed command, if there is a failure reading close the connection, unless
+            // the command is only unknown to this server
+            let cmd = match read_command(&mut stream) {
+                Ok(cmd) => cmd,
+                Err(e) => match e.downcast_ref::<KvsError>() {
+                    Some(KvsError::UnsupportedCommand {
+                        name,
+                        server_version,
+                    }) => {
+                        warn!(
+                            "unsupported command {:?} from {:?}",
+                            name,
+                            stream.tcp().peer_addr()
+                        );
+                        let res = Response::Unsupported {
+                            name: name.to_owned(),
+                            server_version: *server_version,
+                        };
+                        write_frame(&mut stream, &res)?;
+                        continue;
+                    }
+                    _ => return Err(e),
+                },
+            };
+            if let Some(bucket) = &mut bucket {
+                if !bucket.take() {
+                    warn!(
+                        "rate limiting {} from {:?}",
+                        cmd.name(),
+                        stream.tcp().peer_addr()
+                    );
+                    let res = Response::RateLimited {
+                        max_rps: bucket.rate,
+                    };
+                    write_frame(&mut stream, &res)?;
+                    continue;
+                }
+            }
+            if let CommandData::Replicate { .. } = cmd {
+                Self::handle_request(&engine, cmd, &mut stream, &config, &stats, &requests, &info)?;
+                continue;
+            }
+            let (done, handled) = mpsc::channel();
+            let (engine, config, stats, requests, info) = (
+                engine.clone(),
+                config.clone(),
+                stats.clone(),
+                requests.clone(),
+                info.clone(),
+            );
+            pool.lock().spawn(move || {
+                let res = Self::handle_request(
+                    &engine,
+                    cmd,
+                    &mut stream,
+                    &config,
+                    &stats,
+                    &requests,
+                    &info,
+                );
+                // the error is sent back as a string, as the boxed error cannot leave the thread
+                let _ = done.send((stream, res.map_err(|e| e.to_string())));
+            });
+            let res;
+            (stream, res) = handled
+                .recv()
+                .map_err(|_| "worker panicked handling a command")?;
+            res?;
+        }
+        // shutdown stream, `send` FIN packet to client to stop reading stream
+        let _ = stream.shutdown();
         Ok(())
     }
 
     /// KvsServer handle_request, this is a private method, it does 3 things
     /// 1. Match on Command Received from caller
     /// 2. Pass command to underlying storage engine
-    /// 3. Return result to client, whatever it may be
+    /// 3. Return result to client in a Response frame, whatever it may be,
+    ///    values from a get are streamed after the frame
+    ///
+    /// - the connection is left open for the client's next command
+    /// - engine calls taking longer than slow_log_threshold are logged, for a get this includes
+    ///   streaming the value to the client
+    /// - every command is counted in stats, along with whether it was answered with an error
+    /// - while writes are paused, writes are answered with Response::Unavailable, and not handled
     fn handle_request(
-        engine: SharedKvsEngine,
+        engine: &SharedKvsEngine,
         cmd: CommandData,
-        mut stream: TcpStream,
+        stream: &mut Transport,
+        config: &ConnectionConfig,
+        stats: &ServerStats,
+        requests: &RequestCache,
+        info: &Info,
     ) -> Result<()> {
+        stats.record(&cmd);
+        // a write holds the gate open until it has been handled, so a pause waits for it
+        let _writing = match cmd.is_write() {
+            true => match config.writes.enter() {
+                Some(writing) => Some(writing),
+                None => {
+                    let res = Response::Unavailable {
+                        reason: "writes are paused for maintenance".to_owned(),
+                    };
+                    info!("sending response: {:?}", res);
+                    return write_frame(stream, &res);
+                }
+            },
+            false => None,
+        };
+        // the command is consumed by the engine call, describe it up front if it is being timed
+        let timer = config
+            .slow_log_threshold
+            .map(|threshold| SlowLogTimer::start(threshold, &cmd));
+        let res = Self::respond(engine, cmd, stream, config, stats, requests, info)?;
+        if let Some(timer) = timer {
+            timer.finish();
+        }
+        if let Some(Response::Err(_)) = res {
+            stats.record_error();
+        }
+        // write the result back to client, unless a value was already streamed
+        if let Some(res) = res {
+            info!("sending response: {:?}", res);
+            write_frame(stream, &res)?;
+        }
+        Ok(())
+    }
+
+    /// KvsServer respond, this is a private method, it passes cmd to the engine, and returns the
+    /// response to send the client, None if the response was already streamed to the client, i.e
+    /// the value of a get
+    fn respond(
+        engine: &SharedKvsEngine,
+        cmd: CommandData,
+        stream: &mut Transport,
+        config: &ConnectionConfig,
+        stats: &ServerStats,
+        requests: &RequestCache,
+        info: &Info,
+    ) -> Result<Option<Response>> {
         // match on CommandData and execute requests as necessary
-        match cmd {
+        let res = match cmd {
             CommandData::Get { key } => {
-                // get key from log
-                match engine.get(key.to_owned())? {
-                    None => {
-                        // write the result back to client
-                        info!("sending response: {:?}", "Key not found");
-                        stream.write("Key not found".as_bytes())?;
-                        // shutdown stream
-                        stream.shutdown(Shutdown::Both)?;
-                    }
-                    Some(data) => {
-                        // write the result back to client
-                        info!("sending response: {:?}", data);
-                        stream.write(data.as_bytes())?;
-                        // shutdown stream
-                        stream.shutdown(Shutdown::Both)?;
-                    }
+                // stream the value straight from the engine to the client, chunk by chunk
+                let mut streaming = false;
+                let found = engine.get_stream(key, |mut value| {
+                    info!("sending response: {} byte value", value.len);
+                    streaming = true;
+                    write_frame(stream, &Response::Stream { len: value.len })?;
+                    copy_chunks(&mut value.reader, stream, value.len)
+                });
+                match found {
+                    Ok(true) => None,
+                    Ok(false) => Some(Response::KeyNotFound),
+                    // the client is mid-read of the value, the only option is to drop the connection
+                    Err(e) if streaming => return Err(e),
+                    Err(e) => Some(Response::Err(e.to_string())),
                 }
-                // write the data back to stream
             }
             CommandData::Set { key, value } => {
-                // remove key from log
-                engine.set(key.to_owned(), value.to_owned())?;
+                // set (key, value) in log
+                Some(match engine.set(key, value) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Err(e.to_string()),
+                })
             }
             CommandData::Rm { key } => {
-                // set (key, value) in log
-                match engine.remove(key.to_owned()) {
-                    // return nothing if successful
-                    Ok(_) => (),
-                    Err(e) => {
-                        if e.is::<ErrKeyNotFound>() {
-                            // write the result back to client
-                            info!("sending response: {:?}", "Key not found");
-                            stream.write("Key not found".as_bytes())?;
-                            // shutdown stream
-                            stream.shutdown(Shutdown::Both)?;
-                        }
+                // remove key from log
+                Some(match engine.remove(key) {
+                    Ok(_) => Response::Ok,
+                    Err(e) if e.is::<ErrKeyNotFound>() => Response::KeyNotFound,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Sync => {
+                // respond only once prior writes are durable
+                Some(match engine.sync() {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Clear => {
+                // remove every key from the store
+                Some(match engine.clear() {
+                    Ok(_) => Response::Ok,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Rename { from, to } => {
+                // move the value, the source must exist
+                Some(match engine.rename(from, to) {
+                    Ok(_) => Response::Ok,
+                    Err(e) if e.is::<ErrKeyNotFound>() => Response::KeyNotFound,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Len => {
+                // respond with the number of keys
+                Some(match engine.len() {
+                    Ok(len) => Response::Len(len as u64),
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Append { key, value } => {
+                // respond with the length of the new value
+                Some(match engine.append(key, value) {
+                    Ok(len) => Response::Len(len as u64),
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Replicate { from_offset } => {
+                // the stream holds the connection until the replica goes away
+                return Self::stream_changes(engine, from_offset, stream).map(|_| None);
+            }
+            CommandData::Prepend { key, value } => {
+                // respond with the length of the new value
+                Some(match engine.prepend(key, value) {
+                    Ok(len) => Response::Len(len as u64),
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Stats { window } => {
+                // respond with the counters, and the rate over the window
+                Some(Response::Stats(stats.snapshot(window)))
+            }
+            CommandData::StatsReset => {
+                // zero the counters, the rate is unaffected
+                stats.reset();
+                Some(Response::Ok)
+            }
+            CommandData::Info => {
+                // respond with the build and configuration
+                Some(Response::Info(info.clone()))
+            }
+            CommandData::Scan { start, limit } => {
+                // respond with the page, and where the next one starts
+                Some(match engine.scan_page(start, limit) {
+                    Ok((entries, next)) => Response::Page { entries, next },
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Touch { key } => {
+                // mark the key as accessed, the key must exist
+                Some(match engine.touch(key) {
+                    Ok(_) => Response::Ok,
+                    Err(e) if e.is::<ErrKeyNotFound>() => Response::KeyNotFound,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::Accessed { key } => {
+                // respond with the last access, in milliseconds since the unix epoch
+                Some(match engine.last_accessed(key) {
+                    Ok(at) => Response::Accessed(at.map(|at| {
+                        at.duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_millis() as u64)
+                    })),
+                    Err(e) if e.is::<ErrKeyNotFound>() => Response::KeyNotFound,
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::CommitOffset => {
+                // respond once prior writes are durable, with the offset a replica resumes from
+                Some(match engine.commit_offset() {
+                    Ok(offset) => Response::Offset(offset),
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::History { key, limit } => {
+                // respond with the values the engine still holds, newest first
+                Some(match engine.get_versions(key, limit) {
+                    Ok(versions) => Response::Versions(versions),
+                    Err(e) => Response::Err(e.to_string()),
+                })
+            }
+            CommandData::AccessTime { .. } => {
+                // only compaction writes access times
+                Some(Response::Err(
+                    "access times are only written to the log".to_owned(),
+                ))
+            }
+            CommandData::Idempotent { request_id, cmd } => {
+                // a retry of a command already handled is answered as the command was, without
+                // handling it again
+                if let Some(res) = requests.get(&request_id) {
+                    info!("answering retried request {}", request_id);
+                    return Ok(Some(res));
+                }
+                let res = Self::respond(engine, *cmd, stream, config, stats, requests, info)?;
+                // a command that failed is handled again when retried
+                if let Some(res @ (Response::Ok | Response::KeyNotFound | Response::Len(_))) = &res
+                {
+                    requests.insert(request_id, res.clone());
+                }
+                res
+            }
+            CommandData::Pause | CommandData::Resume if !config.allow_pause => Some(Response::Err(
+                "pausing writes is not allowed, see kvs-server --allow-pause".to_owned(),
+            )),
+            CommandData::Pause => {
+                // respond once the writes being handled have finished
+                config.writes.pause();
+                warn!("writes paused");
+                Some(Response::Ok)
+            }
+            CommandData::Resume => {
+                config.writes.resume();
+                warn!("writes resumed");
+                Some(Response::Ok)
+            }
+        };
+        Ok(res)
+    }
+
+    /// KvsServer stream_changes, this is a private method, it writes every change in the engine's
+    /// log from offset onward to the replica, and then each new change as it is written. This only
+    /// returns once a write to the replica fails
+    fn stream_changes(
+        engine: &SharedKvsEngine,
+        mut offset: u64,
+        stream: &mut Transport,
+    ) -> Result<()> {
+        info!(
+            "replicating to {:?} from offset {}",
+            stream.tcp().peer_addr(),
+            offset
+        );
+        let mut last_write = Instant::now();
+        loop {
+            let changes = match engine.changes_since(offset) {
+                Ok(changes) => changes,
+                Err(e) => return write_frame(stream, &Response::Err(e.to_string())),
+            };
+            if changes.is_empty() {
+                // the replica hung up, or the server is draining its connections
+                if read_closed(stream.tcp())? {
+                    return Ok(());
+                }
+                if last_write.elapsed() >= REPLICATION_HEARTBEAT {
+                    write_frame(stream, &Response::Ok)?;
+                    last_write = Instant::now();
+                }
+                thread::sleep(REPLICATION_POLL);
+                continue;
+            }
+            for change in changes {
+                offset = change.next_offset;
+                write_frame(stream, &Response::Change(change))?;
+            }
+            last_write = Instant::now();
+        }
+    }
+
+    /// KvsServer follow, this is a private method, it replicates from the primary, applying its
+    /// changes to engine. If the connection to the primary fails, the replica reconnects, resuming
+    /// after the last change it applied. No change is applied once the server is shut down
+    fn follow(primary: SocketAddr, engine: SharedKvsEngine, shutdown: &AtomicBool) {
+        let mut offset = 0;
+        while !shutdown.load(Ordering::SeqCst) {
+            let res = KvsClient::init(primary).and_then(|mut client| {
+                client.replicate(offset, |change| {
+                    if shutdown.load(Ordering::SeqCst) {
+                        return Err(Box::from("server shut down"));
                     }
+                    match change.cmd {
+                        CommandData::Set { key, value } => engine.set(key, value)?,
+                        CommandData::Rm { key } => match engine.remove(key) {
+                            // the key may already be gone if the change was applied before a reconnect
+                            Err(e) if !e.is::<ErrKeyNotFound>() => return Err(e),
+                            _ => (),
+                        },
+                        _ => (),
+                    }
+                    offset = change.next_offset;
+                    Ok(())
+                })
+            });
+            if let Err(e) = res {
+                error!("replicating from {}: {}, retrying", primary, e);
+            }
+            thread::sleep(REPLICATION_RETRY);
+        }
+    }
+}
+
+/// ServerHandle is a KvsServer serving from a background thread, returned by KvsServer::spawn
+pub struct ServerHandle {
+    addr: SocketAddr,
+    engine: SharedKvsEngine,
+    shutdown: Arc<AtomicBool>,
+    writes: Arc<WriteGate>,
+    thread: JoinHandle<std::result::Result<(), String>>,
+}
+
+impl ServerHandle {
+    /// ServerHandle local_addr, the address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// ServerHandle pause_writes, see KvsServer::pause_writes
+    pub fn pause_writes(&self) {
+        self.writes.pause();
+    }
+
+    /// ServerHandle resume_writes, see KvsServer::resume_writes
+    pub fn resume_writes(&self) {
+        self.writes.resume();
+    }
+
+    /// ServerHandle shutdown, stops accepting connections, lets each open connection finish the
+    /// command it is handling before closing it, waits for the server's thread to exit, and
+    /// syncs the engine
+    /// # Errors
+    /// the server failed while serving, or the engine fails to sync
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake the accept loop, a server listening on every interface is reached on loopback
+        let mut wake = self.addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        // if the listener has already failed, the thread has returned, and there is none to wake
+        let _ = TcpStream::connect(wake);
+        let served = self
+            .thread
+            .join()
+            .map_err(|_| "server thread panicked")?
+            .map_err(Box::<dyn Error>::from);
+        // the engine is synced even if the server failed
+        self.engine.sync()?;
+        served
+    }
+}
+
+/// Connections are the connections a server is serving, so they can be drained on shutdown
+#[derive(Default)]
+struct Connections {
+    // the open connections, by the id they were registered under
+    open: Mutex<HashMap<u64, TcpStream>>,
+    // notified as each connection is closed
+    closed: Condvar,
+    next_id: AtomicU64,
+}
+
+/// Registered is a connection's entry in Connections, removed once it is dropped
+struct Registered {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    /// register stream as open, until the returned Registered is dropped
+    fn register(self: &Arc<Self>, stream: &TcpStream) -> Result<Registered> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open.lock().insert(id, stream.try_clone()?);
+        Ok(Registered {
+            connections: self.clone(),
+            id,
+        })
+    }
+
+    /// drain closes the read half of every open connection, so each sees the end of the stream
+    /// once it has handled the command it is reading, and waits until they are all closed
+    fn drain(&self) {
+        let mut open = self.open.lock();
+        for stream in open.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !open.is_empty() {
+            self.closed.wait(&mut open);
+        }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connections.open.lock().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+/// read_closed reports whether the read half of stream is closed, by the peer hanging up or the
+/// server draining its connections, without waiting for data
+fn read_closed(stream: &TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(&mut [0; 1]);
+    stream.set_nonblocking(false)?;
+    match peeked {
+        Ok(n) => Ok(n == 0),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// is_transient_accept_error reports whether accept failed for the connection being accepted
+/// alone, i.e it was aborted before it was accepted, or the call was interrupted, rather than the
+/// listener being unusable, the next connection may be accepted at once
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::Interrupted
+    )
+}
+
+/// errnos of an accept that failed as the process / system is out of file descriptors or memory
+#[cfg(unix)]
+const EXHAUSTED_ERRNOS: &[i32] = &[libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+#[cfg(not(unix))]
+const EXHAUSTED_ERRNOS: &[i32] = &[];
+
+/// is_exhausted_accept_error reports whether accept failed as the process / system is out of file
+/// descriptors or memory for the moment, accepting again only succeeds once some are freed
+fn is_exhausted_accept_error(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(errno) if EXHAUSTED_ERRNOS.contains(&errno))
+}
+
+/// ConnectionConfig is the configuration each connection is served under, see the setters of
+/// KvsServer
+struct ConnectionConfig {
+    tls: Option<Arc<ServerConfig>>,
+    idle_timeout: Option<Duration>,
+    slow_log_threshold: Option<Duration>,
+    max_rps: Option<u32>,
+    writes: Arc<WriteGate>,
+    allow_pause: bool,
+}
+
+/// WriteGate lets writes through unless they are paused, each write holds the gate open until
+/// it has been handled, so pausing waits for the writes already let through
+#[derive(Default)]
+struct WriteGate {
+    paused: AtomicBool,
+    writing: RwLock<()>,
+}
+
+impl WriteGate {
+    /// stop letting writes through, returns once the writes let through have finished
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        drop(self.writing.write());
+    }
+
+    /// let writes through again
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// let a write through, the write is handled while the returned guard is held, None if
+    /// writes are paused
+    fn enter(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        let writing = self.writing.read();
+        match self.paused.load(Ordering::SeqCst) {
+            true => None,
+            false => Some(writing),
+        }
+    }
+}
+
+/// RequestCache is a bounded LRU of the responses to the latest commands sent with a request id,
+/// a command retried by the client with the same id is answered from the cache. Only responses
+/// to commands that succeeded are held, and only in memory, so a server that restarts handles a
+/// retry again. Two attempts arriving at once are both handled, the client only retries once an
+/// attempt has failed
+struct RequestCache {
+    capacity: usize,
+    inner: Mutex<CachedResponses>,
+}
+
+/// CachedResponses is the content of a RequestCache
+#[derive(Default)]
+struct CachedResponses {
+    // the response to each request id, and when it was last used
+    responses: HashMap<String, (Response, u64)>,
+    // the request ids by when they were last used, the least recently used first
+    used: BTreeMap<u64, String>,
+    // the next use
+    tick: u64,
+}
+
+impl RequestCache {
+    /// an empty cache, holding up to capacity responses
+    fn new(capacity: usize) -> RequestCache {
+        RequestCache {
+            capacity,
+            inner: Mutex::new(CachedResponses::default()),
+        }
+    }
+
+    /// the response to request_id, if it is held, marking it as the most recently used
+    fn get(&self, request_id: &str) -> Option<Response> {
+        let mut inner = self.inner.lock();
+        let tick = inner.tick;
+        let (res, used) = inner.responses.get_mut(request_id)?;
+        let (res, last) = (res.clone(), std::mem::replace(used, tick));
+        inner.used.remove(&last);
+        inner.used.insert(tick, request_id.to_owned());
+        inner.tick += 1;
+        Some(res)
+    }
+
+    /// hold res as the response to request_id, evicting the least recently used response if the
+    /// cache is full
+    fn insert(&self, request_id: String, res: Response) {
+        let mut inner = self.inner.lock();
+        let tick = inner.tick;
+        inner.tick += 1;
+        if let Some((_, last)) = inner.responses.insert(request_id.clone(), (res, tick)) {
+            inner.used.remove(&last);
+        }
+        inner.used.insert(tick, request_id);
+        while inner.responses.len() > self.capacity {
+            let (_, evicted) = inner.used.pop_first().expect("every response is used");
+            inner.responses.remove(&evicted);
+        }
+    }
+}
+
+/// TokenBucket limits the rate of a connection's requests, the bucket holds up to rate tokens,
+/// and is refilled at rate tokens a second, each request takes a token
+struct TokenBucket {
+    rate: u32,
+    tokens: f64,
+    // when the bucket was last refilled
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket, refilled at rate tokens a second
+    fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// take a token for a request, returns false if there are none left
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// SlowLogTimer times an engine call, logging it if it takes longer than the threshold
+struct SlowLogTimer {
+    threshold: Duration,
+    // name and key of the command being timed
+    name: &'static str,
+    key: Option<String>,
+    started: Instant,
+}
+
+impl SlowLogTimer {
+    /// start timing cmd
+    fn start(threshold: Duration, cmd: &CommandData) -> SlowLogTimer {
+        SlowLogTimer {
+            threshold,
+            name: cmd.name(),
+            key: cmd.key().map(str::to_owned),
+            started: Instant::now(),
+        }
+    }
+
+    /// stop timing, logging the command if it was slow
+    fn finish(self) {
+        let elapsed = self.started.elapsed();
+        if elapsed <= self.threshold {
+            return;
+        }
+        match self.key {
+            Some(key) => warn!("slow {} of key {:?}: took {:?}", self.name, key, elapsed),
+            None => warn!("slow {}: took {:?}", self.name, elapsed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::thread_pool::shared_queue::SharedQueueThreadPool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    // Capture records the warnings logged by the servers under test
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    // install CAPTURE as the logger, before any server or client under test installs one of its
+    // own, every server under test is started by spawn_server, which calls this first
+    fn capture_logs() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(LevelFilter::Warn);
+        });
+    }
+
+    // HookedEngine is a KvStore that calls its hook with the name of each operation, before the
+    // store handles it
+    struct HookedEngine<F>(KvStore, F);
+
+    impl<F: Fn(&str) + Send + Sync + 'static> KvsEngine for HookedEngine<F> {
+        fn set(&self, key: String, value: String) -> Result<()> {
+            (self.1)("set");
+            self.0.set(key, value)
+        }
+
+        fn get(&self, key: String) -> Result<Option<String>> {
+            (self.1)("get");
+            self.0.get(key)
+        }
+
+        fn remove(&self, key: String) -> Result<()> {
+            (self.1)("remove");
+            self.0.remove(key)
+        }
+
+        fn sync(&self) -> Result<()> {
+            (self.1)("sync");
+            self.0.sync()
+        }
+
+        fn clear(&self) -> Result<()> {
+            (self.1)("clear");
+            self.0.clear()
+        }
+
+        fn set_key_policy(&self, policy: KeyPolicy) {
+            self.0.set_key_policy(policy)
+        }
+
+        fn set_value_format(&self, format: ValueFormat) {
+            self.0.set_value_format(format)
+        }
+
+        fn len(&self) -> Result<usize> {
+            (self.1)("len");
+            self.0.len()
+        }
+    }
+
+    const SLOW_SET: Duration = Duration::from_millis(100);
+
+    // a KvStore in a new temporary directory, the directory must outlive the store
+    fn temp_store() -> (TempDir, KvStore) {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        (temp_dir, store)
+    }
+
+    // spawn a server of engine on a pool of 2 threads, configure sets the server up before it
+    // starts, logs are captured first
+    fn spawn_server(
+        engine: SharedKvsEngine,
+        configure: impl FnOnce(&mut KvsServer),
+    ) -> ServerHandle {
+        capture_logs();
+        let mut server = KvsServer::with_engine("127.0.0.1:0", engine).unwrap();
+        configure(&mut server);
+        server
+            .spawn(*SharedQueueThreadPool::new(2).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    // commands slower than the threshold are logged, with their key, and faster ones are not
+    fn slow_log_threshold() {
+        // sets sleep for SLOW_SET, served with the given slow log threshold
+        let serve_slow = |threshold: Duration| {
+            let (temp_dir, store) = temp_store();
+            let slow = HookedEngine(store, |op: &str| {
+                if op == "set" {
+                    thread::sleep(SLOW_SET);
                 }
+            });
+            let handle = spawn_server(SharedKvsEngine::from(slow), |server| {
+                server.set_slow_log_threshold(Some(threshold))
+            });
+            (temp_dir, handle)
+        };
+        let (_above_dir, above) = serve_slow(SLOW_SET / 2);
+        let (_below_dir, below) = serve_slow(SLOW_SET * 20);
+        let (above, below) = (above.local_addr(), below.local_addr());
+
+        let set = |key: &str| CommandData::Set {
+            key: key.to_owned(),
+            value: "value".to_owned(),
+        };
+        let mut client = KvsClient::init(above).unwrap();
+        client.send(&set("above")).unwrap();
+        // gets do not sleep, so stay under the threshold
+        client
+            .send(&CommandData::Get {
+                key: "above".to_owned(),
+            })
+            .unwrap();
+        KvsClient::init(below).unwrap().send(&set("below")).unwrap();
+
+        let warnings = CAPTURE.0.lock().unwrap().clone();
+        assert!(
+            warnings
+                .iter()
+                .any(|line| line.starts_with("slow set of key \"above\": took")),
+            "{:?}",
+            warnings
+        );
+        assert!(!warnings.iter().any(|line| line.contains("slow get")));
+        assert!(!warnings.iter().any(|line| line.contains("\"below\"")));
+    }
+
+    #[test]
+    // every command is counted, along with whether it failed, a reset zeroes the counters
+    fn stats_counters() {
+        let (_temp_dir, store) = temp_store();
+        let handle = spawn_server(SharedKvsEngine::from(store), |server| {
+            server.set_key_policy(KeyPolicy {
+                allow_empty: false,
+                ..KeyPolicy::default()
+            })
+        });
+        let addr = handle.local_addr();
+
+        let set = |key: &str| CommandData::Set {
+            key: key.to_owned(),
+            value: "value".to_owned(),
+        };
+        let mut client = KvsClient::init(addr).unwrap();
+        client.send(&set("key")).unwrap();
+        client
+            .send(&CommandData::Get {
+                key: "key".to_owned(),
+            })
+            .unwrap();
+        client.send(&CommandData::Len).unwrap();
+        // the empty key is rejected
+        assert!(client.send(&set("")).is_err());
+
+        let stats = client.stats(60).unwrap();
+        assert_eq!(stats.commands, 4);
+        assert_eq!((stats.gets, stats.writes, stats.errors), (1, 2, 1));
+        // the server has not been up for the window asked for
+        assert!(stats.window < 60);
+
+        assert_eq!(client.send(&CommandData::StatsReset).unwrap(), None);
+        let stats = client.stats(60).unwrap();
+        assert_eq!(
+            (stats.commands, stats.gets, stats.writes, stats.errors),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    // shutdown stops accepting, closes the connections still open, joins the server's thread, and
+    // syncs the engine, every write made before shutdown is kept
+    fn spawn_shutdown() {
+        let (temp_dir, store) = temp_store();
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let counter = syncs.clone();
+        let engine = HookedEngine(store, move |op: &str| {
+            if op == "sync" {
+                counter.fetch_add(1, Ordering::SeqCst);
             }
+        });
+        let handle = spawn_server(SharedKvsEngine::from(engine), |_| ());
+        let addr = handle.local_addr();
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut client = KvsClient::init(addr).unwrap();
+                    for j in 0..5 {
+                        let set = CommandData::Set {
+                            key: format!("key{}-{}", i, j),
+                            value: format!("value{}", j),
+                        };
+                        client.send(&set).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
         }
-        Ok(())
+        // a connection left open is closed by the shutdown
+        let mut idle = KvsClient::init(addr).unwrap();
+        assert_eq!(idle.send(&CommandData::Len).unwrap(), Some("20".to_owned()));
+
+        handle.shutdown().unwrap();
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+        assert!(idle.send(&CommandData::Len).is_err());
+        assert!(TcpStream::connect(addr).is_err());
+
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.len().unwrap(), 20);
+        assert_eq!(
+            store.get("key3-4".to_owned()).unwrap(),
+            Some("value4".to_owned())
+        );
+    }
+
+    #[test]
+    // the cache holds the latest responses, evicting the least recently used first
+    fn request_cache_evicts_least_recently_used() {
+        let cache = RequestCache::new(2);
+        cache.insert("a".to_owned(), Response::Len(1));
+        cache.insert("b".to_owned(), Response::Len(2));
+        // using a makes b the least recently used
+        assert!(matches!(cache.get("a"), Some(Response::Len(1))));
+        cache.insert("c".to_owned(), Response::Len(3));
+        assert!(cache.get("b").is_none());
+        assert!(matches!(cache.get("a"), Some(Response::Len(1))));
+        assert!(matches!(cache.get("c"), Some(Response::Len(3))));
+    }
+
+    #[test]
+    // a retry of an append, sent over a new connection with the same request id, is answered as
+    // the first attempt was, and the value is only appended to once
+    fn idempotent_retry() {
+        let (_temp_dir, store) = temp_store();
+        let engine = SharedKvsEngine::from(store);
+        let handle = spawn_server(engine.clone(), |_| ());
+        let append = |request_id: &str| CommandData::Idempotent {
+            request_id: request_id.to_owned(),
+            cmd: Box::new(CommandData::Append {
+                key: "counter".to_owned(),
+                value: "1".to_owned(),
+            }),
+        };
+        // each attempt is made over a connection of its own, as after a failure
+        let send = |cmd: &CommandData| {
+            let mut stream = Transport::Plain(TcpStream::connect(handle.local_addr()).unwrap());
+            crate::protocol::client_handshake(&mut stream).unwrap();
+            write_frame(&mut stream, cmd).unwrap();
+            match crate::protocol::read_frame(&mut stream).unwrap() {
+                Response::Len(len) => len,
+                res => panic!("unexpected response: {:?}", res),
+            }
+        };
+
+        assert_eq!(send(&append("request-1")), 1);
+        assert_eq!(send(&append("request-1")), 1);
+        assert_eq!(
+            engine.get("counter".to_owned()).unwrap(),
+            Some("1".to_owned())
+        );
+        // a new request is applied
+        assert_eq!(send(&append("request-2")), 2);
+        assert_eq!(
+            engine.get("counter".to_owned()).unwrap(),
+            Some("11".to_owned())
+        );
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    // a client whose connection was closed by the server reconnects, and resends the command
+    fn client_retries_on_new_connection() {
+        let (_temp_dir, store) = temp_store();
+        let engine = SharedKvsEngine::from(store);
+        let handle = spawn_server(engine.clone(), |server| {
+            server.set_idle_timeout(Some(Duration::from_millis(100)))
+        });
+        let append = CommandData::Append {
+            key: "counter".to_owned(),
+            value: "1".to_owned(),
+        };
+
+        let mut client = KvsClient::init(handle.local_addr()).unwrap();
+        assert_eq!(client.send(&append).unwrap(), Some("1".to_owned()));
+        // the server closes the idle connection
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(client.send(&append).unwrap(), Some("2".to_owned()));
+        assert_eq!(
+            engine.get("counter".to_owned()).unwrap(),
+            Some("11".to_owned())
+        );
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    // while writes are paused they are answered with KvsError::Unavailable, and reads are served,
+    // once resumed writes are handled again
+    fn pause_writes() {
+        let (_temp_dir, store) = temp_store();
+        let handle = spawn_server(SharedKvsEngine::from(store), |_| ());
+        let set = |value: &str| CommandData::Set {
+            key: "key1".to_owned(),
+            value: value.to_owned(),
+        };
+        let get = CommandData::Get {
+            key: "key1".to_owned(),
+        };
+
+        let mut client = KvsClient::init(handle.local_addr()).unwrap();
+        client.send(&set("value1")).unwrap();
+        handle.pause_writes();
+        let err = client.send(&set("value2")).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<KvsError>(),
+                Some(KvsError::Unavailable { .. })
+            ),
+            "{}",
+            err
+        );
+        assert!(client
+            .send(&CommandData::Rm {
+                key: "key1".to_owned()
+            })
+            .is_err());
+        assert_eq!(client.send(&get).unwrap(), Some("value1".to_owned()));
+        assert_eq!(
+            client.send(&CommandData::Len).unwrap(),
+            Some("1".to_owned())
+        );
+
+        handle.resume_writes();
+        client.send(&set("value2")).unwrap();
+        assert_eq!(client.send(&get).unwrap(), Some("value2".to_owned()));
+        handle.shutdown().unwrap();
     }
 }
@@ -1,11 +1,32 @@
 #![warn(missing_docs)]
 //! kvs is a key-value store
+/// the KvsEngine trait, and the engines implementing it
 pub mod engines;
 
+/// thread pools kvs-server handles commands on
 pub mod thread_pool;
 
+/// command line arguments of kvs, kvs-client and kvs-server
 pub mod cli;
 
+/// KvsClient, a connection to kvs-server
 pub mod kvs_client;
 
+/// KvsServer, serving a KvsEngine over TCP
 pub mod kvs_server;
+
+/// the wire protocol spoken between kvs-client and kvs-server
+pub mod protocol;
+
+/// counters of the commands kvs-server has handled
+pub mod stats;
+
+/// load generation for kvs-client bench
+pub mod bench;
+
+/// plain and TLS connections between kvs-client and kvs-server
+pub mod transport;
+
+/// running kvs-server in the background
+#[cfg(unix)]
+pub mod daemon;
@@ -0,0 +1,305 @@
+use crate::engines::{
+    kvs::{Change, CommandData},
+    kvs_engine::{KvsError, Result},
+};
+use crate::stats::Stats;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+
+/// version of the wire protocol spoken between kvs-client and kvs-server, this must be
+/// bumped whenever the bytes sent over the wire change shape, or a command is added
+/// v2 - commands and responses are sent as length-prefixed frames, get values are streamed
+/// v3 - a command the server does not know is answered with Response::Unsupported, see
+/// CommandData::TAGS
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// size of the chunks a streamed value is written in, this bounds the memory used to
+/// send or receive a value regardless of its size
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// largest frame read or written, a length prefix over this is rejected before its buffer is
+/// allocated, so a peer can't make the reader allocate up to 4GiB
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Response is the envelope the server answers every command with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Response {
+    /// the command succeeded, and there is nothing to return
+    Ok,
+    /// the value for a get follows this frame as `len` raw bytes
+    Stream {
+        /// length of the value in bytes
+        len: u64,
+    },
+    /// the key does not exist
+    KeyNotFound,
+    /// a length, i.e the length of a value after an append / prepend, or the number of keys
+    Len(u64),
+    /// the command failed on the server, with the given message
+    Err(String),
+    /// a mutation streamed to a replica, in reply to a replicate
+    Change(Change),
+    /// the commands handled by the server, in reply to a stats
+    Stats(Stats),
+    /// the server's build and configuration, in reply to an info
+    Info(Info),
+    /// when a key was last accessed, in milliseconds since the unix epoch, None if it has not
+    /// been accessed since the server began tracking access times, in reply to an accessed
+    Accessed(Option<u64>),
+    /// the offset of the end of the primary's log, None if its engine has no replayable log, in
+    /// reply to a commit offset
+    Offset(Option<u64>),
+    /// a page of (key, value) pairs, in reply to a scan
+    Page {
+        /// the (key, value) pairs, in key order
+        entries: Vec<(String, String)>,
+        /// the key the next page starts at, None if this is the last page
+        next: Option<String>,
+    },
+    /// the server does not know the command, the connection stays open for further commands
+    Unsupported {
+        /// the command's tag, as sent by the client
+        name: String,
+        /// protocol version spoken by the server
+        server_version: u32,
+    },
+    /// the connection has made more requests than the server allows, the command was not
+    /// handled, the connection stays open for further commands
+    RateLimited {
+        /// the most requests a connection may make per second
+        max_rps: u32,
+    },
+    /// the values a key has been set to, newest first, in reply to a history
+    Versions(Vec<String>),
+    /// the server is not taking the command for now, it was not handled, and may be retried
+    Unavailable {
+        /// why the server is unavailable
+        reason: String,
+    },
+}
+
+impl Response {
+    /// into_result turns an Unsupported response into KvsError::UnsupportedCommand, a RateLimited
+    /// response into KvsError::RateLimited, and an Unavailable response into
+    /// KvsError::Unavailable, any other response is returned as is
+    pub fn into_result(self) -> Result<Response> {
+        match self {
+            Response::Unsupported {
+                name,
+                server_version,
+            } => Err(Box::from(KvsError::UnsupportedCommand {
+                name,
+                server_version,
+            })),
+            Response::RateLimited { max_rps } => Err(Box::from(KvsError::RateLimited { max_rps })),
+            Response::Unavailable { reason } => Err(Box::from(KvsError::Unavailable { reason })),
+            res => Ok(res),
+        }
+    }
+}
+
+/// Info describes a kvs-server's build and configuration, in reply to an info
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    /// version of kvs the server was built from
+    pub version: String,
+    /// protocol version spoken by the server
+    pub protocol_version: u32,
+    /// the engine served, see KvsEngine::name
+    pub engine: String,
+    /// how the engine compacts, see KvsEngine::compaction
+    pub compaction: String,
+    /// the thread pool connections are served on
+    pub thread_pool: String,
+    /// the number of threads in the pool
+    pub threads: usize,
+    /// the address the server is listening on
+    pub addr: String,
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "protocol: v{}", self.protocol_version)?;
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "compaction: {}", self.compaction)?;
+        writeln!(f, "thread pool: {}", self.thread_pool)?;
+        writeln!(f, "threads: {}", self.threads)?;
+        write!(f, "addr: {}", self.addr)
+    }
+}
+
+/// Handshake is the first message a client sends after connecting, before any commands
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Handshake {
+    /// protocol version spoken by the client
+    pub version: u32,
+}
+
+/// HandshakeResponse is the server's reply to a Handshake, if the versions differ
+/// the server closes the connection after sending it
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HandshakeResponse {
+    /// protocol version spoken by the server
+    pub version: u32,
+    /// whether the server will process commands on this connection
+    pub accepted: bool,
+}
+
+/// Error returned by both client and server when their protocol versions differ
+#[derive(Debug, Clone)]
+pub struct ErrVersionMismatch {
+    /// protocol version spoken by the client
+    pub client: u32,
+    /// protocol version spoken by the server
+    pub server: u32,
+}
+
+impl fmt::Display for ErrVersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "protocol version mismatch: client speaks v{}, server speaks v{}",
+            self.client, self.server
+        )
+    }
+}
+
+impl Error for ErrVersionMismatch {}
+
+/// client_handshake sends the client's protocol version over the stream, and waits for the server
+/// to accept it
+/// # Errors
+/// ErrVersionMismatch - the server speaks a different protocol version
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    serde_json::to_writer(
+        &mut *stream,
+        &Handshake {
+            version: PROTOCOL_VERSION,
+        },
+    )?;
+    // an older server that does not speak the handshake will close the connection here
+    let resp =
+        HandshakeResponse::deserialize(&mut serde_json::Deserializer::from_reader(&mut *stream))
+            .map_err(|err| format!("protocol handshake failed: {}", err))?;
+    if !resp.accepted || resp.version != PROTOCOL_VERSION {
+        return Err(Box::from(ErrVersionMismatch {
+            client: PROTOCOL_VERSION,
+            server: resp.version,
+        }));
+    }
+    Ok(())
+}
+
+/// server_handshake reads the client's Handshake from the stream and replies with the server's version
+/// # Errors
+/// ErrVersionMismatch - the client speaks a different protocol version, the caller must close the connection
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    let hs = Handshake::deserialize(&mut serde_json::Deserializer::from_reader(&mut *stream))
+        .map_err(|err| format!("protocol handshake failed: {}", err))?;
+    let accepted = hs.version == PROTOCOL_VERSION;
+    serde_json::to_writer(
+        &mut *stream,
+        &HandshakeResponse {
+            version: PROTOCOL_VERSION,
+            accepted,
+        },
+    )?;
+    if !accepted {
+        return Err(Box::from(ErrVersionMismatch {
+            client: hs.version,
+            server: PROTOCOL_VERSION,
+        }));
+    }
+    Ok(())
+}
+
+/// write_frame serializes msg, and writes it to w prefixed with its length as a big-endian u32
+/// # Errors
+/// the serialized msg is larger than MAX_FRAME_SIZE
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> Result<()> {
+    let buf = serde_json::to_vec(msg)?;
+    check_frame_size(buf.len())?;
+    let len = buf.len() as u32;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// read_frame reads a length-prefixed frame from r, and deserializes it
+/// # Errors
+/// the length prefix is larger than MAX_FRAME_SIZE
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<T> {
+    Ok(serde_json::from_slice(&read_frame_bytes(r)?)?)
+}
+
+/// read_command reads a framed command from r, every command is tagged with its name, i.e
+/// `{"Set":{..}}` or `"Sync"`, a command whose tag is not in CommandData::TAGS, say one added in a
+/// later version, is returned as KvsError::UnsupportedCommand rather than failing to decode, so
+/// the server may answer it and carry on
+/// # Errors
+/// KvsError::UnsupportedCommand - the command's tag is not known
+/// the frame could not be read, is not JSON, or is a known command that does not decode
+pub fn read_command<R: Read>(r: &mut R) -> Result<CommandData> {
+    let frame: serde_json::Value = serde_json::from_slice(&read_frame_bytes(r)?)?;
+    let name = match &frame {
+        serde_json::Value::String(name) => Some(name.to_owned()),
+        serde_json::Value::Object(fields) if fields.len() == 1 => fields.keys().next().cloned(),
+        _ => None,
+    };
+    match name {
+        Some(name) if !CommandData::TAGS.contains(&name.as_str()) => {
+            Err(Box::from(KvsError::UnsupportedCommand {
+                name,
+                server_version: PROTOCOL_VERSION,
+            }))
+        }
+        _ => serde_json::from_value(frame)
+            .map_err(|e| Box::from(format!("malformed command: {}", e))),
+    }
+}
+
+// read the bytes of a length-prefixed frame from r
+fn read_frame_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    check_frame_size(len)?;
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// fail if a frame of len bytes is larger than MAX_FRAME_SIZE
+fn check_frame_size(len: usize) -> Result<()> {
+    if len > MAX_FRAME_SIZE {
+        return Err(format!(
+            "frame of {} bytes exceeds the maximum of {} bytes",
+            len, MAX_FRAME_SIZE
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// copy_chunks copies exactly len bytes of a streamed value from r to w, CHUNK_SIZE bytes
+/// at a time, the server uses this to send a value and the client to receive it
+pub fn copy_chunks<R: Read + ?Sized, W: Write + ?Sized>(
+    r: &mut R,
+    w: &mut W,
+    len: u64,
+) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let size = remaining.min(CHUNK_SIZE as u64) as usize;
+        r.read_exact(&mut buf[..size])?;
+        w.write_all(&buf[..size])?;
+        remaining -= size as u64;
+    }
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use crate::engines::kvs::CommandData;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the longest window, in seconds, kvs-server reports the rate of commands over
+pub const MAX_WINDOW: u64 = 60;
+/// one bucket is kept per second of the window, plus the bucket for the current second that is
+/// still filling, and a spare that is zeroed ahead of being filled
+const BUCKETS: usize = MAX_WINDOW as usize + 2;
+/// how often the window advances
+const TICK: Duration = Duration::from_secs(1);
+
+/// Stats is a snapshot of the commands a kvs-server has handled, in reply to a stats command
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// commands handled since the server started, or the counters were last reset
+    pub commands: u64,
+    /// gets handled, a subset of commands
+    pub gets: u64,
+    /// sets, rms, appends, prepends, renames and clears handled, a subset of commands
+    pub writes: u64,
+    /// commands answered with an error, a subset of commands
+    pub errors: u64,
+    /// the seconds ops_per_sec was measured over, shorter than the window asked for if the
+    /// server has not been up that long
+    pub window: u64,
+    /// commands handled per second over the last window seconds, the current second is not
+    /// counted until it is over
+    pub ops_per_sec: f64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "commands: {}", self.commands)?;
+        writeln!(f, "gets: {}", self.gets)?;
+        writeln!(f, "writes: {}", self.writes)?;
+        writeln!(f, "errors: {}", self.errors)?;
+        write!(f, "ops/sec ({}s): {:.1}", self.window, self.ops_per_sec)
+    }
+}
+
+/// ServerStats counts the commands a kvs-server handles, both cumulatively and per second over a
+/// rolling window of the last MAX_WINDOW seconds
+/// recording a command is a few relaxed atomic adds, the window is advanced by a background thread
+/// that wakes once a second, and exits once the ServerStats is dropped
+pub struct ServerStats {
+    commands: AtomicU64,
+    gets: AtomicU64,
+    writes: AtomicU64,
+    errors: AtomicU64,
+    // commands handled in each second, the bucket for second n is buckets[n % BUCKETS]
+    buckets: Vec<AtomicU64>,
+    // seconds since the stats were created, i.e the second whose bucket is filling
+    tick: AtomicUsize,
+}
+
+impl ServerStats {
+    /// new creates an empty ServerStats, and starts the thread advancing its window
+    pub fn new() -> Arc<ServerStats> {
+        let stats = Arc::new(ServerStats::stopped());
+        let window = Arc::downgrade(&stats);
+        thread::spawn(move || Self::advance(window));
+        stats
+    }
+
+    // an empty ServerStats whose window only advances through next_second
+    fn stopped() -> ServerStats {
+        ServerStats {
+            commands: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            tick: AtomicUsize::new(0),
+        }
+    }
+
+    /// record counts cmd, stats, stats resets and infos are not counted, they observe the server
+    /// rather than load it
+    pub fn record(&self, cmd: &CommandData) {
+        match cmd {
+            CommandData::Stats { .. } | CommandData::StatsReset | CommandData::Info => return,
+            CommandData::Get { .. } => self.gets.fetch_add(1, Ordering::Relaxed),
+            cmd if cmd.is_write() => self.writes.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        let tick = self.tick.load(Ordering::Acquire);
+        self.buckets[tick % BUCKETS].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record_error counts a command answered with an error, the command itself is counted by
+    /// record
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// snapshot returns the cumulative counters, and the rate of commands over the last window
+    /// seconds, window is clamped to 1..=MAX_WINDOW
+    pub fn snapshot(&self, window: u64) -> Stats {
+        let tick = self.tick.load(Ordering::Acquire);
+        // only whole seconds are counted, of which there are only as many as have passed
+        let window = window.clamp(1, MAX_WINDOW).min(tick as u64);
+        let ops: u64 = (1..=window as usize)
+            .map(|ago| self.buckets[(tick - ago) % BUCKETS].load(Ordering::Relaxed))
+            .sum();
+        Stats {
+            commands: self.commands.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            window,
+            ops_per_sec: if window == 0 {
+                0.0
+            } else {
+                ops as f64 / window as f64
+            },
+        }
+    }
+
+    /// reset zeroes the cumulative counters, the window is left as is, so rates are unaffected
+    pub fn reset(&self) {
+        self.commands.store(0, Ordering::Relaxed);
+        self.gets.store(0, Ordering::Relaxed);
+        self.writes.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+    }
+
+    // advance the window once every TICK, until the stats are dropped, the deadline is kept
+    // from the start, so the window does not drift as the thread is scheduled late
+    fn advance(stats: Weak<ServerStats>) {
+        let mut deadline = Instant::now();
+        loop {
+            deadline += TICK;
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            match stats.upgrade() {
+                Some(stats) => stats.next_second(),
+                None => return,
+            }
+        }
+    }
+
+    // move the window on to the next second, zeroing its bucket before moving on to it
+    fn next_second(&self) {
+        let next = self.tick.load(Ordering::Acquire) + 1;
+        self.buckets[next % BUCKETS].store(0, Ordering::Relaxed);
+        self.tick.store(next, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    // commands recorded in each second are reported at their rate over the window, a reset
+    // zeroes the counters but leaves the rate as is
+    fn window_rate() {
+        let stats = ServerStats::stopped();
+        let record = |ops| {
+            for _ in 0..ops {
+                stats.record(&CommandData::Len);
+            }
+        };
+        for ops in [100, 200, 300] {
+            record(ops);
+            stats.next_second();
+        }
+        // the second still filling is not counted
+        record(50);
+
+        let snapshot = stats.snapshot(3);
+        assert_eq!(snapshot.commands, 650);
+        assert_eq!(snapshot.window, 3);
+        assert_eq!(snapshot.ops_per_sec, 200.0);
+        assert_eq!(stats.snapshot(1).ops_per_sec, 300.0);
+        // whole seconds are reported, up to as many as have passed
+        assert_eq!(stats.snapshot(0).window, 1);
+        assert_eq!(stats.snapshot(MAX_WINDOW).window, 3);
+
+        stats.reset();
+        let reset = stats.snapshot(3);
+        assert_eq!(reset.commands, 0);
+        assert_eq!(reset.ops_per_sec, snapshot.ops_per_sec);
+
+        // seconds that fall out of the window stop counting as their buckets are reused
+        for _ in 0..BUCKETS {
+            stats.next_second();
+        }
+        let snapshot = stats.snapshot(MAX_WINDOW);
+        assert_eq!(snapshot.window, MAX_WINDOW);
+        assert_eq!(snapshot.ops_per_sec, 0.0);
+    }
+}
@@ -10,6 +10,8 @@ pub trait ThreadPool {
     fn spawn<F>(&mut self, job: F)
     where
         F: FnOnce() + Send + 'static;
+    /// the number of threads tasks are run on
+    fn threads(&self) -> usize;
 }
 
 pub mod naive;
@@ -75,6 +75,10 @@ impl ThreadPool for NaiveThreadPool {
         // push recv chan onto availThreads
         self.recvChan.push(rx);
     }
+    /// every worker, whether available or working
+    fn threads(&self) -> usize {
+        self.availThreads.len() + self.workingThreads.len()
+    }
 }
 
 impl NaiveThreadPool {
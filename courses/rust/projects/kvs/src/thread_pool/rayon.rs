@@ -24,4 +24,8 @@ impl ThreadPool for RayonThreadPool {
     {
         self.thread_pool.install(job)
     }
+    /// the threads in the rayon thread pool
+    fn threads(&self) -> usize {
+        self.thread_pool.current_num_threads()
+    }
 }
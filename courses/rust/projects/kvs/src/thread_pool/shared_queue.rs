@@ -1,7 +1,7 @@
 use crate::thread_pool::*;
 use crossbeam::channel::{Receiver, Sender};
 use crossbeam_channel::unbounded;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 use std::thread::{self, JoinHandle};
 use std::{collections::VecDeque, sync::Arc};
 
@@ -20,6 +20,10 @@ pub struct SharedQueueThreadPool {
     // jobs is the shared queue of active tasks, it is protected by an Arc<Mutex>
     // the scheduler process will push tasks onto the queue, and workers pull tasks from the queue
     jobs: Arc<Mutex<VecDeque<StatusMsg>>>,
+    // not_full is notified whenever a worker pops a task off of jobs, spawn waits on it while the queue is full
+    not_full: Arc<Condvar>,
+    // the maximum number of tasks waiting in jobs, spawn blocks while the queue is this deep
+    capacity: Option<usize>,
     // number of threads that are shared in this thread pool
     threads: i32,
     // handles,
@@ -30,6 +34,8 @@ pub struct SharedQueueThreadPool {
 struct Worker {
     // reference to the task queue
     jobs: Arc<Mutex<VecDeque<StatusMsg>>>,
+    // notified once a task is popped from the queue
+    not_full: Arc<Condvar>,
     // help_chan is the channel through which panics are communicated to thread pool
     help_chan: Receiver<StatusMsg>,
     // panic_chain, is the sender of the Panic message, in the event that a thread panics
@@ -58,6 +64,8 @@ impl Worker {
             // pop element from jobs
             let mut job_guard = self.jobs.lock();
             if let Some(task) = job_guard.pop_front() {
+                // there is room in the queue, wake a blocked spawn
+                self.not_full.notify_one();
                 match task {
                     StatusMsg::Shutdown => {
                         // thread is shutting down, return
@@ -84,12 +92,14 @@ impl Worker {
                     StatusMsg::Panic => {
                         // spawn a new task
                         let jobs = self.jobs.clone();
+                        let not_full = self.not_full.clone();
                         let help_chan = self.help_chan.clone();
                         let panic_chan = self.panic_chan.clone();
                         // spawn a new thread to take care of failed chan
                         thread::spawn(|| {
                             let mut worker = Worker {
                                 jobs: jobs,
+                                not_full: not_full,
                                 help_chan: help_chan,
                                 panic_chan: panic_chan,
                             };
@@ -105,12 +115,17 @@ impl Worker {
         }
     }
 }
-/// implementation of ThreadPool for a SharedQueueThreadPool
-impl ThreadPool for SharedQueueThreadPool {
-    /// create a new NaiveThreadPool with threads available threads
-    fn new(threads: i32) -> Result<Box<Self>> {
+impl SharedQueueThreadPool {
+    /// create a new SharedQueueThreadPool with threads available threads, spawn blocks while
+    /// capacity tasks are already waiting in the queue, so a fast producer is slowed down to the
+    /// pace of the workers. A capacity of None leaves the queue unbounded
+    pub fn new_with_capacity(threads: i32, capacity: Option<usize>) -> Result<Box<Self>> {
+        if capacity == Some(0) {
+            return Err("queue capacity must be greater than 0".into());
+        }
         // create taskqueue
         let jobs = Arc::new(Mutex::new(VecDeque::<StatusMsg>::new()));
+        let not_full = Arc::new(Condvar::new());
         // create coord_listener for SharedQueueThreadPool
         let (tx, rx) = unbounded::<StatusMsg>();
         // create vec of handles so that when dropped, SharedQueueThreadPool takes care of all threads
@@ -119,6 +134,7 @@ impl ThreadPool for SharedQueueThreadPool {
         for i in 0..threads {
             // run the worker task on a separate thread
             let jobs = jobs.clone();
+            let not_full = not_full.clone();
             let help_chan = rx.clone();
             let panic_chan = tx.clone();
             // push JoinHandle of thread so that the top level obj will keep track of threads when dropped
@@ -126,6 +142,7 @@ impl ThreadPool for SharedQueueThreadPool {
                 // capture cloned values
                 let mut worker = Worker {
                     jobs: jobs,
+                    not_full: not_full,
                     help_chan: help_chan,
                     panic_chan: panic_chan,
                 };
@@ -135,11 +152,13 @@ impl ThreadPool for SharedQueueThreadPool {
             }));
         }
         let clone_jobs = jobs.clone();
+        let clone_not_full = not_full.clone();
         // spawn a helper worker
         handles.push(thread::spawn(move || {
             // capture values
             let mut worker = Worker {
                 jobs: clone_jobs,
+                not_full: clone_not_full,
                 help_chan: rx,
                 panic_chan: tx,
             };
@@ -151,21 +170,46 @@ impl ThreadPool for SharedQueueThreadPool {
         // must account for the extra help thread
         Ok(Box::from(SharedQueueThreadPool {
             jobs: jobs,
+            not_full: not_full,
+            capacity: capacity,
             threads: threads,
             handles: handles,
         }))
     }
-    /// spawn a new task as one of the threads in the pool
+
+    /// the number of tasks waiting in the queue for a worker
+    pub fn queued(&self) -> usize {
+        self.jobs.lock().len()
+    }
+}
+
+/// implementation of ThreadPool for a SharedQueueThreadPool
+impl ThreadPool for SharedQueueThreadPool {
+    /// create a new SharedQueueThreadPool with threads available threads, and an unbounded queue
+    fn new(threads: i32) -> Result<Box<Self>> {
+        Self::new_with_capacity(threads, None)
+    }
+    /// spawn a new task as one of the threads in the pool, blocks while the queue is full
     fn spawn<F>(&mut self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
         // lock task queue
         let mut task_queue = self.jobs.lock();
+        // wait for a worker to make room in the queue
+        if let Some(capacity) = self.capacity {
+            while task_queue.len() >= capacity {
+                self.not_full.wait(&mut task_queue);
+            }
+        }
         // now we can push the newest StatusMsg into Queue
         task_queue.push_back(StatusMsg::Job(Box::from(job)))
         // Mutex will unlock once the MutexGuard goes out of scope
     }
+    /// the worker threads, not counting the helper thread
+    fn threads(&self) -> usize {
+        self.threads as usize
+    }
 }
 
 
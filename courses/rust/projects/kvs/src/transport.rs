@@ -0,0 +1,187 @@
+use crate::engines::kvs_engine::Result;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, ConnectionCommon, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Transport is a connection between kvs-client and kvs-server, either plaintext, or encrypted
+/// with TLS, the framed protocol is spoken identically over both
+pub enum Transport {
+    /// a plaintext connection
+    Plain(TcpStream),
+    /// the server's end of a TLS connection
+    ServerTls(Box<StreamOwned<ServerConnection, TcpStream>>),
+    /// the client's end of a TLS connection
+    ClientTls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Transport {
+    /// server_tls wraps a connection accepted by the server in TLS, the TLS handshake is made
+    /// by the first read / write
+    pub fn server_tls(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Transport> {
+        let tls = StreamOwned::new(ServerConnection::new(config)?, stream);
+        Ok(Transport::ServerTls(Box::new(tls)))
+    }
+
+    /// client_tls wraps a connection to the server in TLS, the server's certificate must be
+    /// valid for server_name, i.e the host the client connected to
+    pub fn client_tls(
+        stream: TcpStream,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Transport> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| format!("invalid TLS server name: {}", server_name))?;
+        let tls = StreamOwned::new(ClientConnection::new(config, server_name)?, stream);
+        Ok(Transport::ClientTls(Box::new(tls)))
+    }
+
+    /// the socket the transport is over, for setting timeouts, or finding the peer's address
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Transport::Plain(stream) => stream,
+            Transport::ServerTls(tls) => &tls.sock,
+            Transport::ClientTls(tls) => &tls.sock,
+        }
+    }
+
+    /// readable waits until there is data to read, without consuming it, returns false once the
+    /// peer has closed the connection, waiting is bounded by the socket's read timeout
+    pub fn readable(&mut self) -> io::Result<bool> {
+        match self {
+            Transport::Plain(stream) => Ok(stream.peek(&mut [0; 1])? > 0),
+            Transport::ServerTls(tls) => tls_readable(&mut tls.conn, &mut tls.sock),
+            Transport::ClientTls(tls) => tls_readable(&mut tls.conn, &mut tls.sock),
+        }
+    }
+
+    /// shutdown closes the connection, a TLS connection is first closed with a close_notify,
+    /// so the peer can tell the close from a truncation
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(_) => (),
+            Transport::ServerTls(tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()?;
+            }
+            Transport::ClientTls(tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()?;
+            }
+        }
+        self.tcp().shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::ServerTls(tls) => tls.read(buf),
+            Transport::ClientTls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::ServerTls(tls) => tls.write(buf),
+            Transport::ClientTls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::ServerTls(tls) => tls.flush(),
+            Transport::ClientTls(tls) => tls.flush(),
+        }
+    }
+}
+
+// read TLS records from sock until one of them holds data, or the peer closes the connection,
+// records holding no data, i.e session tickets, are consumed
+fn tls_readable<Data>(conn: &mut ConnectionCommon<Data>, sock: &mut TcpStream) -> io::Result<bool> {
+    loop {
+        let state = conn
+            .process_new_packets()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if state.plaintext_bytes_to_read() > 0 {
+            return Ok(true);
+        }
+        if state.peer_has_closed() || conn.read_tls(sock)? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// server_tls_config loads the server's certificate chain from cert_path, and its private key
+/// from key_path, both PEM encoded
+/// # Errors
+/// either file cannot be read, holds no certificate / key, or the key does not match
+pub fn server_tls_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// client_tls_config trusts the certificates in the PEM file at ca_path, or the system's root
+/// certificates if there is none
+/// # Errors
+/// the file cannot be read, or holds no certificate
+pub fn client_tls_config(ca_path: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_path {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots.add(&cert)?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // skip any system certificate rustls cannot parse, rather than trusting none
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+// every certificate in the PEM file at path
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// the first private key in the PEM file at path, PKCS#8, PKCS#1 (RSA), or SEC1 (EC)
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+    Err(format!("no private key in {}", path.display()).into())
+}
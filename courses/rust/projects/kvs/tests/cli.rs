@@ -1,12 +1,66 @@
 use assert_cmd::prelude::*;
+use kvs::engines::kvs::CommandData;
+use kvs::engines::kvs_engine::KvsError;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::kvs_client::KvsClient;
+use kvs::protocol::{
+    client_handshake, read_frame, server_handshake, write_frame, Handshake, HandshakeResponse,
+    Info, Response, MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+use predicates::boolean::PredicateBooleanExt;
 use predicates::str::{contains, is_empty};
+use serde::Deserialize;
 use std::fs::{self, File};
-use std::process::Command;
-use std::sync::mpsc;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::TempDir;
 
+// ServerProcess kills the wrapped kvs-server when dropped, so a failed assertion does not
+// leave the server running
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// spawn a kvs-server with args in dir, on a port chosen by the OS, returns the server and the
+// address it reported
+fn spawn_server(dir: &TempDir, args: &[&str]) -> (ServerProcess, String) {
+    let mut command = Command::cargo_bin("kvs-server").unwrap();
+    command.args(args).current_dir(dir);
+    spawn_server_command(command)
+}
+
+// spawn the kvs-server command on a port chosen by the OS, returns the server and the address
+// it reported
+fn spawn_server_command(mut command: Command) -> (ServerProcess, String) {
+    let mut child = command
+        .args(&["--addr", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let server = ServerProcess(child);
+
+    let mut line = String::new();
+    BufReader::new(stdout).read_line(&mut line).unwrap();
+    let addr: SocketAddr = line
+        .trim()
+        .strip_prefix("listening on ")
+        .expect("server should report the address it is listening on")
+        .parse()
+        .unwrap();
+    (server, addr.to_string())
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -151,6 +205,79 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` with an unknown engine should name the engines it knows and exit with a non-zero
+// code, whether the engine is given as a flag or in the config file
+#[test]
+fn server_cli_invalid_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "bogus"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("\"bogus\" isn't a valid value for '--engine <ENGINE>'"))
+        .stderr(contains("[possible values: kvs, sled]"))
+        .stderr(contains("panicked").not());
+
+    fs::write(temp_dir.path().join("kvs.toml"), "engine = \"bogus\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown variant `bogus`, expected `kvs` or `sled`"));
+}
+
+// `kvs-server` with a malformed address should report the address and exit with a non-zero code
+#[test]
+fn server_cli_invalid_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "invalid_addr"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "invalid --addr 'invalid_addr', expected <address>:<port>",
+        ))
+        .stderr(contains("panicked").not());
+}
+
+// `kvs-server --hash-keys` should only be accepted for the kvs engine, without --track-access
+#[test]
+fn server_cli_hash_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--hash-keys", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--hash-keys requires --engine kvs"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--hash-keys", "--track-access"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--hash-keys and --track-access cannot be used together"));
+
+    let (_server, addr) = spawn_server(&temp_dir, &["--hash-keys"]);
+    let mut client = KvsClient::init(&addr).unwrap();
+    let set = CommandData::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    client.send(&set).unwrap();
+    let get = CommandData::Get {
+        key: "key1".to_owned(),
+    };
+    assert_eq!(client.send(&get).unwrap(), Some("value1".to_owned()));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -163,6 +290,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // reap the server, so the port is free before it is restarted
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -243,6 +372,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // reap the server, so the port is free before it is restarted
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
     Command::cargo_bin("kvs-client")
@@ -267,3 +398,1470 @@ fn cli_access_server(engine: &str, addr: &str) {
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
+
+// A server whose engine fails to open should error during init, before it binds the socket.
+// The address is held by the test, so a server that bound first would report the address in use
+// instead of the locked engine
+#[test]
+fn server_init_locked_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    // hold the lock on the sled database in ./db
+    let _engine = SledKvsEngine::open(temp_dir.path().join("db")).unwrap();
+    let _listener = TcpListener::bind("127.0.0.1:4011").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("could not acquire lock"));
+}
+
+// A client and server speaking different protocol versions should both report the mismatch
+#[test]
+fn handshake_version_mismatch() {
+    let addr = "127.0.0.1:4005";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    // a client from the future is rejected by the server
+    let mut stream = TcpStream::connect(addr).unwrap();
+    serde_json::to_writer(
+        &mut stream,
+        &Handshake {
+            version: PROTOCOL_VERSION + 1,
+        },
+    )
+    .unwrap();
+    let resp = HandshakeResponse::deserialize(&mut serde_json::Deserializer::from_reader(&stream))
+        .unwrap();
+    assert!(!resp.accepted);
+    assert_eq!(resp.version, PROTOCOL_VERSION);
+    // the server closes the connection rather than waiting for a command
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    server.0.kill().expect("server exited before killed");
+    let mut stderr = String::new();
+    server
+        .0
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(stderr.contains(&format!(
+        "protocol version mismatch: client speaks v{}, server speaks v{}",
+        PROTOCOL_VERSION + 1,
+        PROTOCOL_VERSION
+    )));
+
+    // a server from the future is rejected by the client
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        Handshake::deserialize(&mut serde_json::Deserializer::from_reader(&stream)).unwrap();
+        serde_json::to_writer(
+            &mut stream,
+            &HandshakeResponse {
+                version: PROTOCOL_VERSION + 1,
+                accepted: false,
+            },
+        )
+        .unwrap();
+    });
+    let err = KvsClient::init(server_addr).err().unwrap();
+    assert!(err.to_string().contains(&format!(
+        "protocol version mismatch: client speaks v{}, server speaks v{}",
+        PROTOCOL_VERSION,
+        PROTOCOL_VERSION + 1
+    )));
+    handle.join().unwrap();
+}
+
+// A kvs-server that runs out of file descriptors should keep serving once descriptors are freed,
+// rather than exit on the failed accept
+#[cfg(unix)]
+#[test]
+fn accept_survives_fd_exhaustion() {
+    use std::os::unix::process::CommandExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut command = Command::cargo_bin("kvs-server").unwrap();
+    command.current_dir(&temp_dir).stderr(Stdio::piped());
+    // leave the server only a few descriptors beyond those it needs to start
+    unsafe {
+        command.pre_exec(|| {
+            let limit = libc::rlimit {
+                rlim_cur: 32,
+                rlim_max: 32,
+            };
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let (mut server, addr) = spawn_server_command(command);
+
+    // hold more idle connections open than the server has descriptors, the rest wait in the
+    // backlog while the server's accepts fail
+    let idle: Vec<_> = (0..64).map(|_| TcpStream::connect(&addr).unwrap()).collect();
+    thread::sleep(Duration::from_millis(500));
+    drop(idle);
+
+    let mut client = KvsClient::init(&addr).unwrap();
+    let set = CommandData::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert_eq!(client.send(&set).unwrap(), None);
+    assert_eq!(
+        client
+            .send(&CommandData::Get {
+                key: "key1".to_owned()
+            })
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+
+    server.0.kill().unwrap();
+    let mut stderr = String::new();
+    server
+        .0
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(stderr.contains("error accepting connection: Too many open files"));
+}
+
+// A TLS kvs-client should complete a set / get against a TLS kvs-server, while a plaintext client,
+// or a TLS client that does not trust the server's certificate, is rejected
+#[test]
+fn cli_tls() {
+    let tls_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let cert = tls_dir.join("cert.pem");
+    let key = tls_dir.join("key.pem");
+    let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--tls-cert", cert, "--tls-key", key]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "--tls", "--ca-cert", cert, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "--tls", "--ca-cert", cert, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("protocol handshake failed"));
+    // the self-signed certificate is not among the system's roots
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "--tls", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("invalid peer certificate"));
+
+    // a server given only half of the TLS flags refuses to start
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:0", "--tls-cert", cert])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--tls-cert and --tls-key must be given together"));
+}
+
+// A command from a later version should be answered with a typed unsupported-command error, and
+// the connection should stay open for commands the server knows
+#[test]
+fn unsupported_command() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    client_handshake(&mut stream).unwrap();
+    let commands = [
+        serde_json::json!({"Cas": {"key": "key1", "expected": "a", "value": "b"}}),
+        serde_json::json!("Watch"),
+    ];
+    for (cmd, name) in commands.iter().zip(["Cas", "Watch"]) {
+        write_frame(&mut stream, cmd).unwrap();
+        let err = read_frame::<_, Response>(&mut stream)
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KvsError>(),
+            Some(&KvsError::UnsupportedCommand {
+                name: name.to_owned(),
+                server_version: PROTOCOL_VERSION,
+            })
+        );
+    }
+    write_frame(&mut stream, &CommandData::Len).unwrap();
+    assert!(matches!(read_frame(&mut stream).unwrap(), Response::Len(0)));
+
+    // a known command that does not decode is not unsupported, the connection is closed
+    write_frame(&mut stream, &serde_json::json!({"Set": {"key": 1}})).unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+}
+
+// A frame whose length prefix is over MAX_FRAME_SIZE should be rejected before its buffer is
+// allocated, the server closes the connection rather than wait for the frame
+#[test]
+fn oversized_frame() {
+    let err = read_frame::<_, Response>(&mut &u32::MAX.to_be_bytes()[..]).unwrap_err();
+    assert!(err.to_string().contains("exceeds the maximum"), "{}", err);
+
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    client_handshake(&mut stream).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes())
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    // other connections are served as usual
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    client_handshake(&mut stream).unwrap();
+    write_frame(&mut stream, &CommandData::Len).unwrap();
+    assert!(matches!(read_frame(&mut stream).unwrap(), Response::Len(0)));
+}
+
+// Idle connections, more of them than the server's 4 pool threads, should not keep other clients
+// from being served
+#[test]
+fn concurrent_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+    let mut idle: Vec<_> = (0..5)
+        .map(|_| {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client_handshake(&mut stream).unwrap();
+            stream
+        })
+        .collect();
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client_handshake(&mut stream).unwrap();
+    let set = CommandData::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    write_frame(&mut stream, &set).unwrap();
+    assert!(read_frame::<_, Response>(&mut stream)
+        .unwrap()
+        .into_result()
+        .is_ok());
+
+    // the idle connections are still served
+    for stream in &mut idle {
+        write_frame(stream, &CommandData::Len).unwrap();
+        assert!(matches!(read_frame(stream).unwrap(), Response::Len(1)));
+    }
+}
+
+// Requests over --max-rps on a connection should be rejected with KvsError::RateLimited, without
+// limiting other connections
+#[test]
+fn max_rps() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--max-rps", "2"]);
+    let len = |stream: &mut TcpStream| {
+        write_frame(stream, &CommandData::Len).unwrap();
+        read_frame::<_, Response>(stream).unwrap().into_result()
+    };
+
+    let mut burst = TcpStream::connect(&addr).unwrap();
+    client_handshake(&mut burst).unwrap();
+    let results: Vec<_> = (0..10).map(|_| len(&mut burst)).collect();
+    // the bucket starts full
+    assert!(results[..2].iter().all(|res| res.is_ok()));
+    let rejected: Vec<_> = results.iter().filter_map(|res| res.as_ref().err()).collect();
+    assert!(rejected.len() >= 5, "{} requests rejected", rejected.len());
+    for err in rejected {
+        assert_eq!(
+            err.downcast_ref::<KvsError>(),
+            Some(&KvsError::RateLimited { max_rps: 2 })
+        );
+    }
+
+    // another connection has a bucket of its own
+    let mut other = TcpStream::connect(&addr).unwrap();
+    client_handshake(&mut other).unwrap();
+    for _ in 0..2 {
+        assert!(matches!(len(&mut other).unwrap(), Response::Len(0)));
+    }
+}
+
+// A multi-megabyte value should be streamed back byte-for-byte, including characters
+// that are escaped in the log
+#[test]
+fn get_streams_large_value() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let value: String = (0..2 * 1024 * 1024)
+        .map(|i| match i % 7 {
+            0 => '"',
+            1 => '\\',
+            2 => '\n',
+            3 => '\u{1}',
+            4 => 'é',
+            5 => '😀',
+            _ => 'a',
+        })
+        .collect();
+    KvsClient::init(addr)
+        .unwrap()
+        .send(&CommandData::Set {
+            key: "key1".to_owned(),
+            value: value.clone(),
+        })
+        .unwrap();
+
+    let mut buf = Vec::new();
+    assert!(KvsClient::init(addr)
+        .unwrap()
+        .get_to("key1".to_owned(), &mut buf)
+        .unwrap());
+    assert_eq!(buf, value.as_bytes());
+    assert!(!KvsClient::init(addr)
+        .unwrap()
+        .get_to("key2".to_owned(), &mut buf)
+        .unwrap());
+}
+
+// Writes are not fsynced on their own, `kvs-client sync` should return once they are durable,
+// and they should survive the server being killed
+#[test]
+fn cli_sync_survives_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:4007"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4007", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4007", "sync"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    drop(server);
+
+    let _server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:4008"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4008", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// A connection stays open between commands, until it has been idle for longer than `--idle-timeout`,
+// a client whose connection was closed reconnects for its next command
+#[test]
+fn idle_connection_closed() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr, "--idle-timeout", "1"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::init(addr).unwrap();
+    client
+        .send(&CommandData::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        })
+        .unwrap();
+    assert_eq!(
+        client
+            .send(&CommandData::Get {
+                key: "key1".to_owned()
+            })
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+    let mut idle = TcpStream::connect(addr).unwrap();
+    client_handshake(&mut idle).unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+    // the server has closed the connection
+    assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+    assert_eq!(
+        client
+            .send(&CommandData::Get {
+                key: "key1".to_owned()
+            })
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `kvs-client set` should read the value from a file or stdin, in place of the positional value
+#[test]
+fn cli_set_value_from_file_and_stdin() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    thread::sleep(Duration::from_secs(1));
+
+    let value_path = temp_dir.path().join("value.txt");
+    fs::write(&value_path, "line 1\n\"line 2\"\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "set", "key1", "--value-file"])
+        .arg(&value_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("line 1\n\"line 2\"\n\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "set", "key2", "--value-stdin"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("piped\tvalue")
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("piped\tvalue\n");
+
+    // the value sources are mutually exclusive
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", addr, "set", "key3", "value3", "--value-stdin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server --addr 127.0.0.1:0` should serve on a free port, and report it on stdout
+#[test]
+fn server_reports_os_assigned_port() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+    assert!(!addr.ends_with(":0"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs-client append` / `kvs-client prepend` should print the new length of the value
+#[test]
+fn cli_append_prepend() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "append", "key1", "world"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "prepend", "key1", "hello "])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("11\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("hello world\n");
+}
+
+// `kvs-client len` should print the number of keys
+#[test]
+fn cli_len() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "len"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+    for key in &["key1", "key2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--addr", &addr, "set", key, "value"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "len"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+}
+
+// a replica started with --replicate-from should converge to the primary's keys, including
+// writes made before it connected
+#[test]
+fn cli_replicate_from() {
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let (_primary, primary_addr) = spawn_server(&primary_dir, &[]);
+
+    let mut client = KvsClient::init(&primary_addr).unwrap();
+    let set = |key: &str, value: &str| CommandData::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    client.send(&set("key1", "value1")).unwrap();
+
+    let (_replica, replica_addr) = spawn_server(&replica_dir, &["--replicate-from", &primary_addr]);
+    client.send(&set("key2", "value2")).unwrap();
+    client.send(&set("key1", "value3")).unwrap();
+    client
+        .send(&CommandData::Rm {
+            key: "key2".to_owned(),
+        })
+        .unwrap();
+    client.send(&set("key3", "value4")).unwrap();
+
+    // poll the replica until it has applied every write
+    let mut replica = KvsClient::init(&replica_addr).unwrap();
+    let get = |replica: &mut KvsClient, key: &str| {
+        replica
+            .send(&CommandData::Get {
+                key: key.to_owned(),
+            })
+            .unwrap()
+    };
+    for _ in 0..100 {
+        if get(&mut replica, "key3") == Some("value4".to_owned()) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(get(&mut replica, "key1"), Some("value3".to_owned()));
+    assert_eq!(get(&mut replica, "key2"), Some("Key not found".to_owned()));
+    assert_eq!(get(&mut replica, "key3"), Some("value4".to_owned()));
+    assert_eq!(
+        replica.send(&CommandData::Len).unwrap(),
+        Some("2".to_owned())
+    );
+}
+
+// `kvs-server --config <file>` should take its settings from the file, with flags given on the
+// command line taking precedence, and reject unknown settings
+#[test]
+fn cli_config() {
+    let temp_dir = TempDir::new().unwrap();
+    // find a free port for the config to choose
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(
+        &config,
+        format!(
+            "addr = \"127.0.0.1:{}\"\nengine = \"sled\"\nmax_key_len = 4\n",
+            port
+        ),
+    )
+    .unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let server = ServerProcess(child);
+    let mut line = String::new();
+    BufReader::new(stdout).read_line(&mut line).unwrap();
+    assert_eq!(line.trim(), format!("listening on 127.0.0.1:{}", port));
+    // the sled engine stores its data in ./db
+    assert!(temp_dir.path().join("db").is_dir());
+    let addr = format!("127.0.0.1:{}", port);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key12", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid key"));
+    drop(server);
+
+    // flags override the file
+    let (_server, addr) = spawn_server(
+        &temp_dir,
+        &["--config", "kvs-server.toml", "--max-key-len", "8"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key12", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    fs::write(&config, "engine = \"sled\"\nthreads = 4\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `threads`"));
+
+    // settings are held to the bounds of their flags
+    fs::write(&config, "max_rps = 0\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("max_rps must be at least 1"));
+}
+
+// `kvs-client rename <from> <to>` should move the value, and fail if from is missing
+#[test]
+fn cli_rename() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "rename", "key1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "rename", "key1", "key3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+}
+
+// `kvs-client stats` should print the commands the server has handled, `stats reset` zero them
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "stats", "--window", "10"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("commands: 2\ngets: 1\nwrites: 1\nerrors: 0\nops/sec ("));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "stats", "reset"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("commands: 0\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-client scan` should print a page of (key, value) pairs, and the key the next page starts at
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    let mut client = KvsClient::init(&addr).unwrap();
+    for i in (1..=5).rev() {
+        let set = CommandData::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        };
+        client.send(&set).unwrap();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "scan", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\nnext: key3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "scan", "--start", "key3", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key3\tvalue3\nkey4\tvalue4\nnext: key5\n");
+    // the last page has no next key
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "scan", "--start", "key5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key5\tvalue5\n");
+}
+
+// spawn a server speaking the wire protocol without an engine, on a port chosen by the OS, the
+// name of every command it receives is recorded, a get is answered with name as the value, and
+// any other command with Ok. Returns the address, and the commands received
+fn spawn_mock_server(name: &'static str) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let log = log.clone();
+            thread::spawn(move || {
+                server_handshake(&mut stream).unwrap();
+                while let Ok(cmd) = read_frame::<_, CommandData>(&mut stream) {
+                    log.lock().unwrap().push(cmd.name().to_owned());
+                    if let CommandData::Get { .. } = cmd {
+                        let len = name.len() as u64;
+                        write_frame(&mut stream, &Response::Stream { len }).unwrap();
+                        stream.write_all(name.as_bytes()).unwrap();
+                    } else {
+                        write_frame(&mut stream, &Response::Ok).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+// A client of a cluster sends writes to the primary, and spreads reads across the replicas
+// round-robin, replicas that are down are skipped, and once every replica is down reads are sent
+// to the primary
+#[test]
+fn client_cluster_routing() {
+    let (primary, primary_cmds) = spawn_mock_server("primary");
+    let (replica1, replica1_cmds) = spawn_mock_server("replica1");
+    let (replica2, replica2_cmds) = spawn_mock_server("replica2");
+    let get = |client: &mut KvsClient| {
+        let get = CommandData::Get {
+            key: "key1".to_owned(),
+        };
+        client.send(&get).unwrap().unwrap()
+    };
+
+    let mut client = KvsClient::init_cluster(primary, &[replica1, replica2]).unwrap();
+    let mut reads = Vec::new();
+    for i in 0..4 {
+        let set = CommandData::Set {
+            key: format!("key{}", i),
+            value: "value".to_owned(),
+        };
+        client.send(&set).unwrap();
+        reads.push(get(&mut client));
+    }
+    client
+        .send(&CommandData::Rm {
+            key: "key0".to_owned(),
+        })
+        .unwrap();
+    assert_eq!(reads, ["replica1", "replica2", "replica1", "replica2"]);
+    assert_eq!(
+        *primary_cmds.lock().unwrap(),
+        ["set", "set", "set", "set", "rm"]
+    );
+    assert_eq!(*replica1_cmds.lock().unwrap(), ["get", "get"]);
+    assert_eq!(*replica2_cmds.lock().unwrap(), ["get", "get"]);
+
+    // nothing is listening on a port that was just freed
+    let down = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut client = KvsClient::init_cluster(primary, &[down, replica1]).unwrap();
+    assert_eq!(get(&mut client), "replica1");
+    assert_eq!(get(&mut client), "replica1");
+    let mut client = KvsClient::init_cluster(primary, &[down]).unwrap();
+    assert_eq!(get(&mut client), "primary");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &primary.to_string()])
+        .args(&["--replica", &replica2.to_string(), "get", "key1"])
+        .assert()
+        .success()
+        .stdout("replica2\n");
+}
+
+// A commit offset is answered with the end of the kvs engine's log, which only moves with writes,
+// and with None by the sled engine
+#[test]
+fn client_commit_offset() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+    let mut client = KvsClient::init(&addr).unwrap();
+    let set = CommandData::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    client.send(&set).unwrap();
+    let offset = client.commit_offset().unwrap().unwrap();
+    assert!(offset > 0);
+    let get = CommandData::Get {
+        key: "key1".to_owned(),
+    };
+    client.send(&get).unwrap();
+    assert_eq!(client.commit_offset().unwrap(), Some(offset));
+
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--engine", "sled"]);
+    let mut client = KvsClient::init(&addr).unwrap();
+    assert_eq!(client.commit_offset().unwrap(), None);
+}
+
+// Writes to a server that does not know Idempotent should be resent without it, once
+#[test]
+fn client_idempotent_fallback() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    thread::spawn(move || {
+        let mut stream = listener.incoming().next().unwrap().unwrap();
+        server_handshake(&mut stream).unwrap();
+        while let Ok(cmd) = read_frame::<_, CommandData>(&mut stream) {
+            // answered as a server that predates Idempotent would
+            let (name, res) = match cmd {
+                CommandData::Idempotent { .. } => (
+                    "idempotent",
+                    Response::Unsupported {
+                        name: "Idempotent".to_owned(),
+                        server_version: 2,
+                    },
+                ),
+                cmd => (cmd.name(), Response::Ok),
+            };
+            log.lock().unwrap().push(name);
+            write_frame(&mut stream, &res).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::init(addr).unwrap();
+    for key in ["key1", "key2"] {
+        let set = CommandData::Set {
+            key: key.to_owned(),
+            value: "value".to_owned(),
+        };
+        assert_eq!(client.send(&set).unwrap(), None);
+    }
+    assert_eq!(*received.lock().unwrap(), ["idempotent", "set", "set"]);
+}
+
+// `kvs-client bench` should send every request, in the ratio asked for, and report the throughput
+// and latency they were served with
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &[]);
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "bench", "--threads", "2", "--requests", "100"])
+        .args(&["--value-size", "16", "--ratio", "80:20"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("requests: 100 (80 gets, 20 sets)"), "{}", stdout);
+    assert!(stdout.contains("errors: 0"), "{}", stdout);
+    assert!(stdout.contains("latency p99: "), "{}", stdout);
+    let throughput: f64 = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("throughput: "))
+        .and_then(|line| line.strip_suffix(" req/s"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(throughput > 0.0);
+
+    // every 5th request was a set, written to the server
+    let mut client = KvsClient::init(&addr).unwrap();
+    let get = CommandData::Get {
+        key: "key4".to_owned(),
+    };
+    assert_eq!(client.send(&get).unwrap(), Some("x".repeat(16)));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "bench", "--ratio", "80"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid ratio '80', expected <reads>:<writes>"));
+}
+
+// `kvs-client info` should report the settings kvs-server was started with, as text, or as JSON
+// with --format json
+#[test]
+fn cli_info() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--engine", "sled"]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "info"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "version: {}\nprotocol: v{}\nengine: sled\n",
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION
+        )))
+        .stdout(contains("thread pool: SharedQueueThreadPool\nthreads: 4\n"))
+        .stdout(contains(format!("addr: {}\n", addr)));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "info", "--format", "json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let info: Info = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        info,
+        Info {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            engine: "sled".to_owned(),
+            compaction: "managed by sled".to_owned(),
+            thread_pool: "SharedQueueThreadPool".to_owned(),
+            threads: 4,
+            addr: addr.clone(),
+        }
+    );
+    // info is not counted as a command
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("commands: 0\n"));
+}
+
+// `kvs-client touch` should mark a key as accessed on a kvs-server tracking access times, and
+// `kvs-client accessed` print when it was, in milliseconds since the unix epoch
+#[test]
+fn cli_touch() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--track-access"]);
+
+    let mut client = KvsClient::init(&addr).unwrap();
+    let set = CommandData::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    client.send(&set).unwrap();
+    let set = client.last_accessed("key1".to_owned()).unwrap().unwrap();
+    thread::sleep(Duration::from_millis(5));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "touch", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let touch = client.last_accessed("key1".to_owned()).unwrap().unwrap();
+    assert!(touch > set);
+    let millis = touch.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "accessed", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", millis));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "touch", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    // tracking is only available for the kvs engine
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:0", "--engine", "sled", "--track-access"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("requires --engine kvs"));
+}
+
+// `kvs-server --engine sled` accepts the sled tuning flags, which are refused for the kvs engine
+#[test]
+fn cli_sled_options() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(
+        &temp_dir,
+        &["--engine", "sled", "--sled-cache-mb", "1", "--sled-flush-ms", "10"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:0", "--sled-cache-mb", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("require --engine sled"));
+}
+
+// `kvs-client` should exit 0 with the value on stdout for a get hit, and "Key not found" for a get
+// miss, while a rm miss or any error from the server prints to stderr and exits 1
+#[test]
+fn cli_exit_codes() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--max-key-len", "4"]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(0)
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("Key not found"));
+    // the server rejects the key
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key12", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("invalid key"));
+}
+
+// `kvs-server --daemon` should return once the server is listening, write its PID file, and
+// shut down cleanly on SIGTERM
+#[cfg(unix)]
+#[test]
+fn cli_daemon() {
+    // DaemonProcess kills the daemon when dropped, if it is still running
+    struct DaemonProcess(libc::pid_t);
+    impl Drop for DaemonProcess {
+        fn drop(&mut self) {
+            unsafe { libc::kill(self.0, libc::SIGKILL) };
+        }
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    let pid_file = temp_dir.path().join("server.pid");
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:0", "--daemon", "--pid-file"])
+        .arg(&pid_file)
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let pid: libc::pid_t = fs::read_to_string(&pid_file)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    let _daemon = DaemonProcess(pid);
+    let addr = String::from_utf8(output.stdout).unwrap();
+    let addr = addr
+        .trim()
+        .strip_prefix("listening on ")
+        .expect("server should report the address it is listening on")
+        .to_owned();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    assert!(temp_dir.path().join("kvs-server.log").exists());
+
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    // the daemon removes its PID file as it shuts down
+    for _ in 0..50 {
+        if !pid_file.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!pid_file.exists());
+    thread::sleep(Duration::from_millis(100));
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+// keys rejected by the server's key policy should be reported as errors by the client
+#[test]
+fn cli_key_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--max-key-len", "4", "--deny-empty-keys"]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key12", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid key"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", ""])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid key"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+#[test]
+fn cli_value_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_server(&temp_dir, &["--value-format", "json"]);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "{\"a\":"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid value: not valid JSON"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "set", "key1", "{\"a\":[1]}"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"a\":[1]}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "--pretty", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\n  \"a\": [\n    1\n  ]\n}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "get", "--pretty", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+}
+
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--disable-compaction", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--disable-compaction requires --engine kvs"));
+
+    let (_server, addr) = spawn_server(&temp_dir, &["--disable-compaction"]);
+    for value in ["value1", "value2", "value3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["--addr", &addr, "set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "history", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\nvalue2\nvalue1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "history", "key1", "--limit", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "history", "key1", "--limit", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--addr", &addr, "history", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+}
+
+#[test]
+fn cli_pause_resume() {
+    let temp_dir = TempDir::new().unwrap();
+    let client = |args: &[&str], addr: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(&["--addr", addr]).args(args).current_dir(&temp_dir);
+        cmd
+    };
+    {
+        let (_server, addr) = spawn_server(&temp_dir, &[]);
+        client(&["pause"], &addr)
+            .assert()
+            .failure()
+            .stderr(contains("pausing writes is not allowed"));
+    }
+
+    let (_server, addr) = spawn_server(&temp_dir, &["--allow-pause"]);
+    client(&["set", "key1", "value1"], &addr).assert().success();
+    client(&["pause"], &addr).assert().success().stdout(is_empty());
+    client(&["set", "key1", "value2"], &addr)
+        .assert()
+        .failure()
+        .stderr(contains("unavailable: writes are paused for maintenance"));
+    client(&["get", "key1"], &addr)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["resume"], &addr).assert().success();
+    client(&["set", "key1", "value2"], &addr).assert().success();
+    client(&["get", "key1"], &addr)
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
@@ -1,12 +1,24 @@
 use assert_cmd::prelude::*;
 use kvs::engines::{
-    kvs::KvStore,
-    kvs_engine::{KvsEngine, Result, SharedKvsEngine},
-    sled::SledKvsEngine,
+    kvs::{CommandData, CompactOpts, CompactionOptions, CompactionStrategy, Durability, KvStore},
+    kvs_engine::{
+        ErrKeyNotFound, KeyPolicy, KvsEngine, KvsError, Result, SharedKvsEngine, ValueFormat,
+    },
+    log_storage::{FileStorage, LogStorage, StreamStorage, TEMP_SUFFIX},
+    sled::{SledKvsEngine, SledOptions},
+    tiered::TieredEngine,
 };
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::{process::Command, thread, time::Duration};
+use proptest::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, fs, process::Command, thread};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -68,7 +80,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -97,7 +109,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -120,6 +132,49 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs clear` should refuse without --yes, `kvs clear --yes` should remove every key.
+#[test]
+fn cli_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["clear"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["clear", "--yes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -184,7 +239,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -194,7 +249,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -205,14 +260,14 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -224,14 +279,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -240,7 +295,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -248,82 +303,563 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned()).unwrap(), None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Clearing the store removes every key and truncates the log
 #[test]
-fn compaction() -> Result<()> {
+fn clear_store() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.clear()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    // the store is usable after clearing
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.clear()?;
+    drop(store);
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered.
+    assert_eq!(std::fs::metadata(temp_dir.path().join("log"))?.len(), 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
 
-        drop(store);
-        // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+// Clearing the store removes every key in the sled engine
+#[test]
+fn clear_store_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.clear()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    Ok(())
+}
+
+// assert that result failed with KvsError::InvalidKey
+fn assert_invalid_key<T: std::fmt::Debug>(result: Result<T>) {
+    let err = result.unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::InvalidKey { .. })
+        ),
+        "expected an invalid key error, got {}",
+        err
+    );
+}
+
+// Keys violating the KeyPolicy are rejected by set / get / remove, and never reach the store
+fn key_policy<E: KvsEngine>(store: E) -> Result<()> {
+    // the default policy accepts every key
+    store.set("".to_owned(), "empty".to_owned())?;
+    store.set("key\n1".to_owned(), "control".to_owned())?;
+    store.set_key_policy(KeyPolicy {
+        max_len: Some(8),
+        allow_empty: false,
+        allow_control: false,
+        forbidden: vec!['/'],
+    });
+
+    // empty key
+    assert_invalid_key(store.set("".to_owned(), "value".to_owned()));
+    assert_invalid_key(store.get("".to_owned()));
+    assert_invalid_key(store.remove("".to_owned()));
+    // over-length key
+    assert_invalid_key(store.set("key123456".to_owned(), "value".to_owned()));
+    assert_invalid_key(store.append("key123456".to_owned(), "value".to_owned()));
+    // control character
+    assert_invalid_key(store.get("key\n1".to_owned()));
+    assert_invalid_key(store.set("key\u{7}".to_owned(), "value".to_owned()));
+    // forbidden character
+    assert_invalid_key(store.set("key/1".to_owned(), "value".to_owned()));
+
+    store.set("key12345".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key12345".to_owned())?, Some("value".to_owned()));
+    // only the accepted keys were written
+    store.set_key_policy(KeyPolicy::default());
+    assert_eq!(store.len()?, 3);
+    assert_eq!(store.get("key123456".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn key_policy_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_policy(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn key_policy_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_policy(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// assert that result failed with KvsError::InvalidValue
+fn assert_invalid_value<T: std::fmt::Debug>(result: Result<T>) {
+    let err = result.unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<KvsError>(),
+            Some(KvsError::InvalidValue { .. })
+        ),
+        "expected an invalid value error, got {}",
+        err
+    );
+}
+
+// With compaction disabled every value a key was set to stays in the log, get_versions returns
+// them newest first, across removes, up to limit
+#[test]
+fn get_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_options(CompactionOptions {
+        strategy: CompactionStrategy::Disabled,
+    })?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key10".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.get_versions("key1".to_owned(), 10)?,
+        ["value3", "value2", "value1"]
+    );
+    assert_eq!(
+        store.get_versions("key1".to_owned(), 2)?,
+        ["value3", "value2"]
+    );
+    assert!(store.get_versions("key1".to_owned(), 0)?.is_empty());
+    assert!(store.get_versions("key2".to_owned(), 10)?.is_empty());
+
+    // a removed key keeps its history, which survives reopening the store
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versions("key1".to_owned(), 10)?,
+        ["value3", "value2", "value1"]
+    );
+    // compacting drops every value but the live ones
+    store.compact()?;
+    assert!(store.get_versions("key1".to_owned(), 10)?.is_empty());
+    assert_eq!(store.get_versions("key10".to_owned(), 10)?, ["other"]);
+
+    // the log is never compacted automatically, the strategy is not kept by the log
+    store.set_compaction_options(CompactionOptions {
+        strategy: CompactionStrategy::Disabled,
+    })?;
+    for iter in 0..10001 {
+        store.set("key2".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(store.get_versions("key2".to_owned(), 20000)?.len(), 10001);
+    Ok(())
+}
+
+// Raw accepts any value as it is, Json only values that parse as JSON, and stores them unchanged
+fn value_format<E: KvsEngine>(store: E) -> Result<()> {
+    // raw is the default, values that are not JSON are kept byte for byte
+    let raw = "{not json\0\u{7f}\u{1f600}\n".to_owned();
+    store.set("raw".to_owned(), raw.clone())?;
+    store.set("empty".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("raw".to_owned())?, Some(raw.clone()));
+    assert_eq!(store.get("empty".to_owned())?, Some("".to_owned()));
+
+    store.set_value_format(ValueFormat::Json);
+    let json = r#"{ "name": "kvs", "tags": [1, 2.5, null, true] }"#.to_owned();
+    store.set("json".to_owned(), json.clone())?;
+    store.set("number".to_owned(), "42".to_owned())?;
+    assert_eq!(store.get("json".to_owned())?, Some(json));
+    assert_invalid_value(store.set("bad".to_owned(), "{\"name\":".to_owned()));
+    assert_invalid_value(store.set("bad".to_owned(), "".to_owned()));
+    assert_invalid_value(store.set("bad".to_owned(), "1 2".to_owned()));
+    // an append / prepend leaving the value invalid is rejected, and the value left as it was
+    assert_invalid_value(store.append("number".to_owned(), "x".to_owned()));
+    store.append("number".to_owned(), "7".to_owned())?;
+    assert_invalid_value(store.prepend("raw".to_owned(), "[".to_owned()));
+    assert_eq!(store.get("number".to_owned())?, Some("427".to_owned()));
+    assert_eq!(store.get("bad".to_owned())?, None);
+    // values written before the format was set are still read as they are
+    assert_eq!(store.get("raw".to_owned())?, Some(raw));
+
+    store.set_value_format(ValueFormat::Raw);
+    store.set("bad".to_owned(), "{\"name\":".to_owned())?;
+    assert_eq!(store.len()?, 5);
+    Ok(())
+}
+
+#[test]
+fn value_format_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    value_format(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn value_format_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    value_format(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// pretty indents JSON, and leaves a raw value as it is
+#[test]
+fn value_format_pretty() -> Result<()> {
+    let pretty = ValueFormat::Json.pretty(r#"{"a":[1,2]}"#.to_owned())?;
+    assert_eq!(pretty, "{\n  \"a\": [\n    1,\n    2\n  ]\n}");
+    assert_invalid_value(ValueFormat::Json.pretty("{".to_owned()));
+    assert_eq!(ValueFormat::Raw.pretty("{".to_owned())?, "{");
+    Ok(())
+}
+
+// get_into writes the value to the writer, and returns false for a missing key
+fn get_into<E: KvsEngine>(store: E) -> Result<()> {
+    let value = "value \"1\" é\n".repeat(1000);
+    store.set("key1".to_owned(), value.clone())?;
+    let mut buf = Vec::new();
+    assert!(store.get_into("key1".to_owned(), &mut buf)?);
+    assert_eq!(String::from_utf8(buf).unwrap(), value);
+    let mut buf = Vec::new();
+    assert!(!store.get_into("key2".to_owned(), &mut buf)?);
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+#[test]
+fn get_into_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_into(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn get_into_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_into(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn get_into_memory() -> Result<()> {
+    get_into(KvStore::with_storage(StreamStorage::in_memory())?)
+}
+
+// The key count tracks sets and removes, and survives compaction and reopening the store
+#[test]
+fn len_tracks_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // overwriting a key does not change the count
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(store.len()?, 10);
+    store.remove("key0".to_owned())?;
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert_eq!(store.len()?, 9);
+
+    store.compact()?;
+    assert_eq!(store.len()?, 9);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 9);
+    assert!(!store.is_empty()?);
+    store.clear()?;
+    assert!(store.is_empty()?);
+    Ok(())
+}
+
+#[test]
+fn len_tracks_keys_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.len()?, 9);
+    drop(store);
+
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 9);
+    Ok(())
+}
+
+// Only mutations are streamed, and streaming from a change's next_offset resumes after it
+#[test]
+fn stream_changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let changes = store.stream_changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(changes.len(), 3);
+    assert!(
+        matches!(&changes[0].cmd, CommandData::Set { key, value } if key == "key1" && value == "value1")
+    );
+    assert!(matches!(&changes[1].cmd, CommandData::Set { key, .. } if key == "key2"));
+    assert!(matches!(&changes[2].cmd, CommandData::Rm { key } if key == "key1"));
+
+    // resume after the first change, only later writes are seen
+    let offset = changes[0].next_offset;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let keys: Vec<_> = store
+        .changes_since(offset)?
+        .into_iter()
+        .map(|change| match change.cmd {
+            CommandData::Set { key, .. } | CommandData::Rm { key } => key,
+            cmd => panic!("unexpected change {:?}", cmd),
+        })
+        .collect();
+    assert_eq!(keys, vec!["key2", "key1", "key3"]);
+
+    // the end of the log has no changes, and anything past it is an error
+    let end = store.changes_since(0)?.last().unwrap().next_offset;
+    assert!(store.changes_since(end)?.is_empty());
+    assert!(store.stream_changes_since(end + 1).is_err());
+
+    // sled has no replayable log
+    let store = SledKvsEngine::open(temp_dir.path().join("db"))?;
+    let err = store.changes_since(0).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::Unsupported { .. })
+    ));
+    Ok(())
+}
+
+// Appending / prepending to a missing key creates it, otherwise the value is extended in place
+fn append_prepend<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.append("key1".to_owned(), "bc".to_owned())?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("bc".to_owned()));
+    assert_eq!(store.append("key1".to_owned(), "dé".to_owned())?, 5);
+    assert_eq!(store.prepend("key1".to_owned(), "a".to_owned())?, 6);
+    assert_eq!(store.get("key1".to_owned())?, Some("abcdé".to_owned()));
+    assert_eq!(store.prepend("key2".to_owned(), "value2".to_owned())?, 6);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn append_prepend_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_prepend(KvStore::open(temp_dir.path())?)?;
+    // the appended value is persisted
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("abcdé".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn append_prepend_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_prepend(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn append_prepend_memory() -> Result<()> {
+    append_prepend(KvStore::with_storage(StreamStorage::in_memory())?)
+}
+
+// Renaming moves the value, replacing the destination, and fails if the source is missing
+fn rename<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // the source is missing, nothing changes
+    let err = store
+        .rename("key1".to_owned(), "key2".to_owned())
+        .unwrap_err();
+    assert!(err.is::<ErrKeyNotFound>());
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // an existing destination is overwritten
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.rename("key3".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.len()?, 1);
+
+    // renaming a key to itself leaves it in place
+    store.rename("key2".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn rename_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    rename(KvStore::open(temp_dir.path())?)?;
+    // the rename is persisted
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+#[test]
+fn rename_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    rename(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn rename_tiered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    rename(TieredEngine::new(
+        KvStore::open(temp_dir.path())?,
+        SledKvsEngine::open(temp_dir.path().join("db"))?,
+    ))
+}
+
+// Walking a store in pages returns every key exactly once, in key order, every page but the last
+// is full, and the last has no next key
+fn scan_pages<E: KvsEngine>(store: E) -> Result<()> {
+    const KEYS: usize = 1000;
+    const LIMIT: usize = 64;
+    // keys are written out of order, 7919 is prime so every key is written once
+    for i in 0..KEYS {
+        let i = i * 7919 % KEYS;
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // removed keys are not returned
+    store.remove("key0500".to_owned())?;
+    let expected: Vec<_> = (0..KEYS)
+        .filter(|&i| i != 500)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+
+    let mut seen = Vec::new();
+    let mut start = None;
+    loop {
+        let (entries, next) = store.scan_page(start, LIMIT)?;
+        assert!(entries.len() <= LIMIT);
+        seen.extend(entries);
+        match next {
+            Some(next) => {
+                assert_eq!(seen.len() % LIMIT, 0);
+                start = Some(next);
+            }
+            None => break,
         }
-        return Ok(());
     }
+    assert_eq!(seen, expected);
 
-    panic!("No compaction detected");
+    // a page may start at a key that does not exist
+    let (entries, next) = store.scan_page(Some("key0500".to_owned()), 2)?;
+    assert_eq!(entries, expected[500..502]);
+    assert_eq!(next, Some("key0503".to_owned()));
+    // the last page is exactly full
+    let (entries, next) = store.scan_page(Some("key0998".to_owned()), 2)?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(next, None);
+    // past the last key there is nothing
+    assert_eq!(
+        store.scan_page(Some("z".to_owned()), LIMIT)?,
+        (Vec::new(), None)
+    );
+    Ok(())
 }
 
 #[test]
-fn concurrent_get() -> Result<()> {
+fn scan_pages_kvs() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    scan_pages(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_pages_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_pages(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_pages_tiered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_pages(TieredEngine::new(
+        KvStore::open(temp_dir.path())?,
+        SledKvsEngine::open(temp_dir.path().join("db"))?,
+    ))
+}
+
+// A SledKvsEngine works with a page cache far smaller than the values it holds, and far larger,
+// with compression, and with background flushes disabled
+#[test]
+fn sled_options() -> Result<()> {
+    let options = [
+        SledOptions {
+            cache_capacity: Some(1024),
+            ..SledOptions::default()
+        },
+        SledOptions {
+            cache_capacity: Some(1 << 30),
+            ..SledOptions::default()
+        },
+        SledOptions {
+            flush_every_ms: Some(0),
+            use_compression: true,
+            ..SledOptions::default()
+        },
+    ];
+    for options in options {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SledKvsEngine::open_with_config(temp_dir.path(), options.clone())?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{:01000}", key_id))?;
+        }
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{:01000}", key_id))
+            );
+        }
+        store.sync()?;
+        drop(store);
+
+        let store = SledKvsEngine::open_with_config(temp_dir.path(), options)?;
+        assert_eq!(store.len()?, 1000);
+        assert_eq!(
+            store.get("key999".to_owned())?,
+            Some(format!("{:01000}", 999))
+        );
     }
+    Ok(())
+}
+
+// Appends from concurrent threads are never lost
+fn concurrent_append<E: KvsEngine>(store: E) -> Result<()> {
     let shared_kvs_engine = SharedKvsEngine::from(store);
     let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let mut store = shared_kvs_engine.clone();
+    for thread_id in 0..2 {
+        let store = shared_kvs_engine.clone();
         let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                if let Err(err) = store.get(format!("key{}", key_id)) {
-                    println!("{}", err);
-                }
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+            for _ in 0..100 {
+                store
+                    .append("key1".to_owned(), format!("{}", thread_id))
+                    .unwrap();
             }
         });
         handles.push(handle);
@@ -331,13 +867,190 @@ fn concurrent_get() -> Result<()> {
     for handle in handles {
         handle.join().unwrap();
     }
+    let val = shared_kvs_engine.get("key1".to_owned())?.unwrap();
+    assert_eq!(val.len(), 200);
+    assert_eq!(val.matches('0').count(), 100);
+    Ok(())
+}
 
-    // Open from disk again and check persistent data
-    drop(shared_kvs_engine);
-    let shared_kvs_engine = SharedKvsEngine::from(KvStore::open(temp_dir.path())?);
+#[test]
+fn concurrent_append_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_append(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_append_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_append(SledKvsEngine::open(temp_dir.path())?)
+}
+
+#[test]
+fn concurrent_append_tiered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_append(TieredEngine::new(
+        KvStore::open(temp_dir.path())?,
+        SledKvsEngine::open(temp_dir.path().join("db"))?,
+    ))
+}
+
+// SlowWriter sleeps for SLOW_READ on its first write, so a read into it holds whatever locks the
+// engine takes for a read for at least that long
+struct SlowWriter {
+    written: Vec<u8>,
+}
+
+const SLOW_READ: Duration = Duration::from_millis(100);
+
+impl Write for SlowWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.is_empty() {
+            thread::sleep(SLOW_READ);
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads from 16 threads through a SharedKvsEngine run concurrently, rather than one at a time
+#[test]
+fn concurrent_reads_do_not_serialize() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let engine = SharedKvsEngine::from(store);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..16)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                let mut writer = SlowWriter {
+                    written: Vec::new(),
+                };
+                assert!(engine.get_into("key1".to_owned(), &mut writer).unwrap());
+                assert_eq!(writer.written, b"value1");
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // one read at a time would take 16 * SLOW_READ
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < 4 * SLOW_READ,
+        "16 reads took {:?}, each holding the store for {:?}",
+        elapsed,
+        SLOW_READ
+    );
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered.
+
+        drop(store);
+        // reopen and check content.
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// A store whose records are all live should not be rewritten on every write once it is over the
+// compaction size, the bytes written stay within a constant factor of the bytes set
+#[test]
+fn compaction_write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut set = 0;
+    for key_id in 0..2000 {
+        let key = format!("key{}", key_id);
+        let value = format!("value{}", key_id);
+        set += (key.len() + value.len()) as u64;
+        store.set(key, value)?;
+    }
+    let amplification = store.bytes_written() / set;
+    assert!(amplification < 10, "{}x bytes written", amplification);
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let shared_kvs_engine = SharedKvsEngine::from(store);
     let mut handles = Vec::new();
     for thread_id in 0..100 {
-        let mut store = shared_kvs_engine.clone();
+        let store = shared_kvs_engine.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                if let Err(err) = store.get(format!("key{}", key_id)) {
+                    println!("{}", err);
+                }
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(shared_kvs_engine);
+    let shared_kvs_engine = SharedKvsEngine::from(KvStore::open(temp_dir.path())?);
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = shared_kvs_engine.clone();
         let handle = thread::spawn(move || {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
@@ -359,7 +1072,7 @@ fn concurrent_get() -> Result<()> {
 #[test]
 fn concurrent_get_sled() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = SledKvsEngine::open(temp_dir.path())?;
+    let store = SledKvsEngine::open(temp_dir.path())?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -410,3 +1123,1078 @@ fn concurrent_get_sled() -> Result<()> {
 
     Ok(())
 }
+
+// A value that only lives in the cold tier should be found, and promoted into the hot tier.
+#[test]
+fn tiered_cold_read_promotes() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = SledKvsEngine::open(cold_dir.path())?;
+    cold.set("key1".to_owned(), "value1".to_owned())?;
+
+    let store = TieredEngine::new(KvStore::open(hot_dir.path())?, cold);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // the hot tier now holds the promoted value
+    let hot = KvStore::open(hot_dir.path())?;
+    assert_eq!(hot.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Writes go to the hot tier, and are written back to the cold tier.
+#[test]
+fn tiered_write_back() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = TieredEngine::new(
+        KvStore::open(hot_dir.path())?,
+        SledKvsEngine::open(cold_dir.path())?,
+    );
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let hot = KvStore::open(hot_dir.path())?;
+    assert_eq!(hot.get("key1".to_owned())?, Some("value1".to_owned()));
+    let cold = SledKvsEngine::open(cold_dir.path())?;
+    assert_eq!(cold.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(cold.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Removing a key in neither tier returns ErrKeyNotFound, a key only in the cold tier is removed.
+#[test]
+fn tiered_remove() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = SledKvsEngine::open(cold_dir.path())?;
+    cold.set("key1".to_owned(), "value1".to_owned())?;
+
+    let store = TieredEngine::new(KvStore::open(hot_dir.path())?, cold);
+    let err = store.remove("key2".to_owned()).unwrap_err();
+    assert!(err.is::<ErrKeyNotFound>());
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Reads racing writes that evict keys from the hot tier, and promotions of keys only in the cold
+// tier, should never see a value older than one they have already seen.
+#[test]
+fn tiered_concurrent_set_get() -> Result<()> {
+    let hot_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold = SledKvsEngine::open(cold_dir.path())?;
+    for key_id in 0..20 {
+        cold.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+    let store = Arc::new(TieredEngine::new(KvStore::open(hot_dir.path())?, cold));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        readers.push(thread::spawn(move || {
+            let mut seen = [0; 20];
+            while !done.load(Ordering::SeqCst) {
+                for (key_id, last) in seen.iter_mut().enumerate() {
+                    if let Some(val) = store.get(format!("key{}", key_id)).unwrap() {
+                        let val: usize = val.parse().unwrap();
+                        assert!(
+                            val >= *last,
+                            "key{} went from {} back to {}",
+                            key_id,
+                            last,
+                            val
+                        );
+                        *last = val;
+                    }
+                }
+            }
+        }));
+    }
+
+    // each round overwrites every key, and every other round evicts it from the hot tier again
+    for round in 1..=50 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), round.to_string())?;
+            if round % 2 == 0 {
+                store.remove(key)?;
+            }
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    Ok(())
+}
+
+// A single operation applied to the store by `compaction_preserves_state`
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Get(String),
+    Rm(String),
+}
+
+// characters that exercise escaping in the log records
+const VALUE_CHARS: &[char] = &['a', 'b', 'z', '0', ' ', '"', '\\', '\n', '\t', 'é', '😀'];
+
+// a single operation over a small key space, so keys are overwritten and removed often
+fn op() -> impl Strategy<Value = Op> {
+    let key = (0..8).prop_map(|key_id| format!("key{}", key_id));
+    let value = prop::collection::vec(prop::sample::select(VALUE_CHARS), 0..40)
+        .prop_map(|chars| chars.into_iter().collect());
+    prop_oneof![
+        6 => (key.clone(), value).prop_map(|(key, value)| Op::Set(key, value)),
+        2 => key.clone().prop_map(Op::Get),
+        2 => key.prop_map(Op::Rm),
+    ]
+}
+
+// apply ops to a fresh store and to a model map, force a compaction, reopen the store and compare
+// it to the model, an Err describes the first divergence
+fn check_compaction(ops: &[Op]) -> std::result::Result<(), String> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).map_err(|e| e.to_string())?;
+    let mut model = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::Set(key, value) => {
+                store
+                    .set(key.clone(), value.clone())
+                    .map_err(|e| format!("op {}: set failed: {}", i, e))?;
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Get(key) => {
+                let got = store
+                    .get(key.clone())
+                    .map_err(|e| format!("op {}: get failed: {}", i, e))?;
+                if got.as_ref() != model.get(key) {
+                    return Err(format!(
+                        "op {}: get {:?} returned {:?}, expected {:?}",
+                        i,
+                        key,
+                        got,
+                        model.get(key)
+                    ));
+                }
+            }
+            Op::Rm(key) => match (store.remove(key.clone()), model.remove(key)) {
+                (Ok(()), Some(_)) => (),
+                (Err(e), None) if e.is::<ErrKeyNotFound>() => (),
+                (res, expected) => {
+                    return Err(format!(
+                        "op {}: remove {:?} returned {:?}, expected value {:?}",
+                        i,
+                        key,
+                        res.map_err(|e| e.to_string()),
+                        expected
+                    ))
+                }
+            },
+        }
+    }
+    store
+        .compact()
+        .map_err(|e| format!("compaction failed: {}", e))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path()).map_err(|e| e.to_string())?;
+    for key_id in 0..8 {
+        let key = format!("key{}", key_id);
+        let got = store
+            .get(key.clone())
+            .map_err(|e| format!("get after reopen failed: {}", e))?;
+        if got.as_ref() != model.get(&key) {
+            return Err(format!(
+                "after reopen get {:?} returned {:?}, expected {:?}",
+                key,
+                got,
+                model.get(&key)
+            ));
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    // Compaction must preserve the logical (key, value) mapping for any sequence of operations,
+    // long enough that the log passes the compaction threshold, a failing sequence is shrunk to a
+    // minimal one
+    #[test]
+    fn compaction_preserves_state(ops in prop::collection::vec(op(), 0..400)) {
+        check_compaction(&ops).map_err(TestCaseError::fail)?;
+    }
+}
+
+// number of sealed segments in dir
+fn sealed_segments(dir: &TempDir) -> usize {
+    WalkDir::new(dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("log."))
+        .count()
+}
+
+// assert that store holds exactly the pairs in model
+fn assert_matches_model(store: &KvStore, model: &HashMap<String, String>) -> Result<()> {
+    assert_eq!(store.len()?, model.len());
+    for (key, value) in model {
+        assert_eq!(store.get(key.to_owned())?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// A size-tiered store seals its log into segments, and merges runs of them without losing any
+// write, a store this small never grows past the first tier, so there are always fewer sealed
+// segments than the fanout
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for fanout in [0, 1] {
+        assert!(store
+            .set_compaction_options(CompactionOptions {
+                strategy: CompactionStrategy::SizeTiered { fanout },
+            })
+            .is_err());
+    }
+    store.set_compaction_options(CompactionOptions {
+        strategy: CompactionStrategy::SizeTiered { fanout: 3 },
+    })?;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut model = HashMap::new();
+    for i in 0..5000 {
+        let key = format!("key{}", rng.gen_range(0..50));
+        if rng.gen_bool(0.1) {
+            if model.remove(&key).is_some() {
+                store.remove(key)?;
+            }
+        } else {
+            let value = format!("value{}", i);
+            store.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+    }
+    let segments = sealed_segments(&temp_dir);
+    assert!(segments > 0 && segments < 3, "{} sealed segments", segments);
+    assert_matches_model(&store, &model)?;
+    drop(store);
+
+    // the segments are found again on open
+    let store = KvStore::open(temp_dir.path())?;
+    assert_matches_model(&store, &model)?;
+
+    // a full rewrite merges every segment into the log
+    store.compact()?;
+    assert_eq!(sealed_segments(&temp_dir), 0);
+    assert_matches_model(&store, &model)?;
+    Ok(())
+}
+
+// A growing size-tiered store merges runs of similar-sized segments, rather than rewriting its
+// oldest, largest, segment on every merge, and removals merged into a later segment keep their
+// keys removed
+#[test]
+fn size_tiered_tiers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_options(CompactionOptions {
+        strategy: CompactionStrategy::SizeTiered { fanout: 2 },
+    })?;
+    let mut model = HashMap::new();
+    let mut set = 0;
+    for key_id in 0..10000 {
+        let key = format!("key{}", key_id);
+        let value = format!("value{}", key_id);
+        set += (key.len() + value.len()) as u64;
+        store.set(key.clone(), value.clone())?;
+        model.insert(key, value);
+        if key_id % 10 == 0 && key_id >= 500 {
+            let key = format!("key{}", key_id - 500);
+            store.remove(key.clone())?;
+            model.remove(&key);
+        }
+    }
+    // a record is about 3x the bytes set, and is rewritten once for each of about 5 tiers
+    let amplification = store.bytes_written() / set;
+    assert!(amplification < 30, "{}x bytes written", amplification);
+    assert!(sealed_segments(&temp_dir) > 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_matches_model(&store, &model)?;
+    Ok(())
+}
+
+// The full set / get / remove / compact cycle works against a log held in memory, with either
+// compaction strategy
+#[test]
+fn in_memory_storage() -> Result<()> {
+    for strategy in [
+        CompactionStrategy::FullRewrite,
+        CompactionStrategy::SizeTiered { fanout: 3 },
+    ] {
+        let store = KvStore::with_storage(StreamStorage::in_memory())?;
+        store.set_compaction_options(CompactionOptions { strategy })?;
+        let mut model = HashMap::new();
+        // enough overwrites to compact the log many times
+        for iter in 0..500 {
+            for key_id in 0..10 {
+                let key = format!("key{}", key_id);
+                let value = format!("{}", iter);
+                store.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            let key = format!("key{}", iter % 10);
+            if iter % 7 == 0 {
+                store.remove(key.clone())?;
+                model.remove(&key);
+            }
+        }
+        assert!(store.remove("missing".to_owned()).is_err());
+        assert_matches_model(&store, &model)?;
+        store.compact()?;
+        assert_matches_model(&store, &model)?;
+        store.clear()?;
+        assert!(store.is_empty()?);
+    }
+    Ok(())
+}
+
+// A StreamStorage reads a log already in its stream
+#[test]
+fn stream_storage_reads_existing_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = fs::read(temp_dir.path().join("log"))?;
+    let store = KvStore::with_storage(StreamStorage::new(Cursor::new(log))?)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// length of the log file in dir
+fn log_len(dir: &TempDir) -> u64 {
+    fs::metadata(dir.path().join("log")).unwrap().len()
+}
+
+// Under a GroupCommit, writes are buffered rather than written to the log, but are visible to
+// reads immediately, and are in the log once the store is dropped
+#[test]
+fn group_commit_visible_to_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // nothing is flushed for the length of the test
+    store.set_durability(Durability::GroupCommit {
+        interval: Duration::from_secs(3600),
+        max_bytes: usize::MAX,
+    })?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(log_len(&temp_dir), 0);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // the value is decoded from the buffered record
+    let mut buf = Vec::new();
+    assert!(store.get_into("key99".to_owned(), &mut buf)?);
+    assert_eq!(buf, b"value99");
+    assert_eq!(store.len()?, 99);
+    assert_eq!(store.changes_since(0)?.len(), 101);
+    assert_eq!(log_len(&temp_dir), 0);
+
+    // compaction rewrites the log, buffered records included
+    for iter in 0..1000 {
+        store.set(format!("key{}", iter % 10), format!("{}", iter))?;
+    }
+    assert_eq!(store.get("key9".to_owned())?, Some("999".to_owned()));
+    assert_eq!(store.len()?, 100);
+
+    drop(store);
+    assert!(log_len(&temp_dir) > 0);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("990".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.len()?, 100);
+    Ok(())
+}
+
+// A GroupCommit buffer is flushed by the background thread once its interval is up, or it
+// reaches max_bytes, and when the durability is changed
+#[test]
+fn group_commit_flushes() -> Result<()> {
+    // wait for the log to be flushed
+    fn wait_for_flush(dir: &TempDir) {
+        for _ in 0..500 {
+            if log_len(dir) > 0 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("log was never flushed");
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_durability(Durability::GroupCommit {
+        interval: Duration::from_millis(10),
+        max_bytes: usize::MAX,
+    })?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    wait_for_flush(&temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_durability(Durability::GroupCommit {
+        interval: Duration::from_secs(3600),
+        max_bytes: 1,
+    })?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    wait_for_flush(&temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_durability(Durability::GroupCommit {
+        interval: Duration::from_secs(3600),
+        max_bytes: usize::MAX,
+    })?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(log_len(&temp_dir), 0);
+    store.set_durability(Durability::EveryWrite)?;
+    assert!(log_len(&temp_dir) > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(store
+        .set_durability(Durability::GroupCommit {
+            interval: Duration::ZERO,
+            max_bytes: usize::MAX,
+        })
+        .is_err());
+    Ok(())
+}
+
+// FailingStorage is a FileStorage whose appends and syncs fail while fail is set
+struct FailingStorage {
+    inner: FileStorage,
+    fail: Arc<AtomicBool>,
+}
+
+impl FailingStorage {
+    fn check(&self) -> Result<()> {
+        match self.fail.load(Ordering::SeqCst) {
+            true => Err("disk failed".into()),
+            false => Ok(()),
+        }
+    }
+}
+
+impl LogStorage for FailingStorage {
+    fn segments(&self) -> Result<Vec<u64>> {
+        self.inner.segments()
+    }
+
+    fn len(&self, segment: Option<u64>) -> Result<u64> {
+        self.inner.len(segment)
+    }
+
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>> {
+        self.inner.read(segment)
+    }
+
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        self.inner.reader(segment, offset, len)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.check()?;
+        self.inner.append(buf)
+    }
+
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()> {
+        self.inner.write(segment, buf)
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        self.inner.seal(id)
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        self.inner.remove(id)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.check()?;
+        self.inner.sync()
+    }
+}
+
+// A GroupCommit buffer that fails to flush when the durability is changed stays buffered, the
+// store keeps its storage, and the change succeeds once the storage recovers
+#[test]
+fn group_commit_failed_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fail = Arc::new(AtomicBool::new(false));
+    let store = KvStore::with_storage(FailingStorage {
+        inner: FileStorage::open(temp_dir.path())?,
+        fail: fail.clone(),
+    })?;
+    store.set_durability(Durability::GroupCommit {
+        interval: Duration::from_secs(3600),
+        max_bytes: usize::MAX,
+    })?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fail.store(true, Ordering::SeqCst);
+    assert!(store.set_durability(Durability::EveryWrite).is_err());
+    assert_eq!(log_len(&temp_dir), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    fail.store(false, Ordering::SeqCst);
+    store.set_durability(Durability::EveryWrite)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A value streamed from the log holds no lock on the store, writes and compaction carry on while
+// it is read, and the stream reads the value as it was when it was opened
+#[test]
+fn get_stream_does_not_block_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+    store.set_inline_threshold(0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut stream = store.get_stream("key1".to_owned())?.unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let writer = store.clone();
+    thread::spawn(move || {
+        let res = writer
+            .set("key1".to_owned(), "value2".to_owned())
+            .and_then(|_| writer.compact());
+        tx.send(res.is_ok()).unwrap();
+    });
+    assert!(rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("write blocked by the value stream"));
+
+    let mut value = String::new();
+    stream.reader.read_to_string(&mut value)?;
+    assert_eq!(value, "value1");
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Values held inline, and values only held in the log, survive overwrites, removal, compaction,
+// and reopening the store, whatever the inline threshold
+#[test]
+fn inline_values() -> Result<()> {
+    for threshold in [0, 256, usize::MAX] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set_inline_threshold(threshold);
+        let mut model = HashMap::new();
+        // even keys hold small values, odd keys large ones, enough overwrites to compact the log
+        for iter in 0..200 {
+            for key_id in 0..20 {
+                let key = format!("key{}", key_id);
+                let value = match key_id % 2 {
+                    0 => format!("{}", iter),
+                    _ => format!("{:01000}", iter),
+                };
+                store.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            let key = format!("key{}", iter % 20);
+            if iter % 3 == 0 {
+                store.remove(key.clone())?;
+                model.remove(&key);
+            }
+        }
+        // a small value grown past the threshold, and a large value renamed
+        let len = store.append("key0".to_owned(), format!("{:0300}", 0))?;
+        model.insert(
+            "key0".to_owned(),
+            model.get("key0").cloned().unwrap_or_default() + &format!("{:0300}", 0),
+        );
+        assert_eq!(len, model["key0"].len());
+        store.rename("key1".to_owned(), "renamed".to_owned())?;
+        let value = model.remove("key1").unwrap();
+        model.insert("renamed".to_owned(), value);
+
+        assert_matches_model(&store, &model)?;
+        for (key, value) in model.iter() {
+            let mut buf = Vec::new();
+            assert!(store.get_into(key.clone(), &mut buf)?);
+            assert_eq!(&buf, value.as_bytes());
+        }
+        store.compact()?;
+        assert_matches_model(&store, &model)?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        store.set_inline_threshold(threshold);
+        assert_matches_model(&store, &model)?;
+    }
+    Ok(())
+}
+
+// A dry-run compaction should report the stale bytes overwrites leave behind without touching
+// the log, a compaction should then reclaim exactly those bytes
+#[test]
+fn compact_dry_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key9".to_owned())?;
+
+    let dry_run = CompactOpts { dry_run: true };
+    let len = log_len(&temp_dir);
+    let report = store.compact_with(dry_run.clone())?;
+    assert_eq!(report.total_bytes, len);
+    assert_eq!(report.entries, 9);
+    assert!(report.reclaimable_bytes > 0);
+    assert_eq!(report.live_bytes + report.reclaimable_bytes, len);
+    assert_eq!(log_len(&temp_dir), len);
+
+    // the compaction reclaims what the dry run reported
+    assert_eq!(store.compact_with(CompactOpts::default())?, report);
+    assert_eq!(log_len(&temp_dir), report.live_bytes);
+    let report = store.compact_with(dry_run)?;
+    assert_eq!(report.reclaimable_bytes, 0);
+    for key_id in 0..9 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// `kvs compact --dry-run` should print the space compaction would reclaim, and leave the log as is
+#[test]
+fn cli_compact_dry_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+    let len = log_len(&temp_dir);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("total bytes: {}\n", len)));
+    assert_eq!(log_len(&temp_dir), len);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(log_len(&temp_dir) < len);
+    Ok(())
+}
+
+// CrashingStorage is a FileStorage whose writes crash halfway, as a process killed while
+// compacting would, half the replacement is left in the temporary file it is written to. With
+// after_write, writes complete, and the crash comes as the merged segments are removed
+struct CrashingStorage {
+    inner: FileStorage,
+    dir: PathBuf,
+    after_write: bool,
+}
+
+impl LogStorage for CrashingStorage {
+    fn segments(&self) -> Result<Vec<u64>> {
+        self.inner.segments()
+    }
+
+    fn len(&self, segment: Option<u64>) -> Result<u64> {
+        self.inner.len(segment)
+    }
+
+    fn read(&self, segment: Option<u64>) -> Result<Vec<u8>> {
+        self.inner.read(segment)
+    }
+
+    fn reader(&self, segment: Option<u64>, offset: u64, len: u64) -> Result<Box<dyn Read + Send>> {
+        self.inner.reader(segment, offset, len)
+    }
+
+    fn append(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.append(buf)
+    }
+
+    fn write(&mut self, segment: Option<u64>, buf: &[u8]) -> Result<()> {
+        if self.after_write {
+            return self.inner.write(segment, buf);
+        }
+        let name = match segment {
+            Some(id) => format!("log.{}", id),
+            None => "log".to_owned(),
+        };
+        fs::write(self.dir.join(name + TEMP_SUFFIX), &buf[..buf.len() / 2])?;
+        Err("crashed while writing".into())
+    }
+
+    fn seal(&mut self, id: u64) -> Result<()> {
+        self.inner.seal(id)
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        if self.after_write {
+            return Err("crashed while removing".into());
+        }
+        self.inner.remove(id)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+// A compaction that crashes partway through rewriting the log loses nothing, the log is only
+// replaced once its replacement is fully written, and the partial replacement is removed once the
+// store is reopened
+#[test]
+fn compaction_crash_keeps_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::with_storage(CrashingStorage {
+        inner: FileStorage::open(temp_dir.path())?,
+        dir: temp_dir.path().to_owned(),
+        after_write: false,
+    })?;
+    // stay under the size that compacts automatically
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key9".to_owned())?;
+    store.sync()?;
+    let len = log_len(&temp_dir);
+
+    assert!(store.compact().is_err());
+    drop(store);
+    let temp = temp_dir.path().join(format!("log{}", TEMP_SUFFIX));
+    assert!(temp.exists());
+    assert_eq!(log_len(&temp_dir), len);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp.exists());
+    for key_id in 0..9 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+    // without the crash the log is replaced, and no temporary file is left behind
+    store.compact()?;
+    assert!(log_len(&temp_dir) < len);
+    assert!(!temp.exists());
+    assert_eq!(store.len()?, 9);
+
+    // a crash once the sealed segments are merged into the log, but before they are removed,
+    // leaves them in place, a key removed since it was sealed stays removed
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::with_storage(CrashingStorage {
+        inner: FileStorage::open(temp_dir.path())?,
+        dir: temp_dir.path().to_owned(),
+        after_write: true,
+    })?;
+    // seal the log without merging any segment
+    store.set_compaction_options(CompactionOptions {
+        strategy: CompactionStrategy::SizeTiered { fanout: 100 },
+    })?;
+    store.set("key9".to_owned(), "value9".to_owned())?;
+    for iter in 0..1000 {
+        store.set(format!("key{}", iter % 9), format!("value{}", iter))?;
+    }
+    store.remove("key9".to_owned())?;
+    let segments = sealed_segments(&temp_dir);
+    assert!(segments > 0);
+
+    assert!(store.compact().is_err());
+    drop(store);
+    assert_eq!(sealed_segments(&temp_dir), segments);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key9".to_owned())?, None);
+    assert_eq!(store.len()?, 9);
+    store.compact()?;
+    assert_eq!(sealed_segments(&temp_dir), 0);
+    assert_eq!(store.get("key9".to_owned())?, None);
+    assert_eq!(store.get("key8".to_owned())?, Some("value998".to_owned()));
+    Ok(())
+}
+
+// With access tracking enabled, a set, get, or touch records when the key was accessed, without
+// writing to the log, and compaction persists the access times so they survive reopening the store
+#[test]
+fn access_tracking() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // tracking is opt-in
+    assert!(store.touch("key1".to_owned()).is_err());
+    assert!(store.last_accessed("key1".to_owned()).is_err());
+
+    store.set_access_tracking(true)?;
+    // keys written before tracking began have not been accessed
+    assert_eq!(store.last_accessed("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let set = store.last_accessed("key2".to_owned())?.unwrap();
+    store.sync()?;
+    let len = log_len(&temp_dir);
+
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let get = store.last_accessed("key2".to_owned())?.unwrap();
+    assert!(get > set);
+    thread::sleep(Duration::from_millis(5));
+    store.touch("key1".to_owned())?;
+    let touch = store.last_accessed("key1".to_owned())?.unwrap();
+    assert!(touch > get);
+    // reads and touches add nothing to the log
+    store.sync()?;
+    assert_eq!(log_len(&temp_dir), len);
+
+    let err = store.touch("key3".to_owned()).unwrap_err();
+    assert!(err.is::<ErrKeyNotFound>());
+    store.remove("key2".to_owned())?;
+    let err = store.last_accessed("key2".to_owned()).unwrap_err();
+    assert!(err.is::<ErrKeyNotFound>());
+
+    // the access times are only written to the log by compaction
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_access_tracking(true)?;
+    assert_eq!(store.last_accessed("key1".to_owned())?, Some(touch));
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.last_accessed("key1".to_owned())?.unwrap() > touch);
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// The commit offset is the end of the log, it grows with every write, is left as is by reads, and
+// a consumer resuming from it sees only the writes made after it was taken
+#[test]
+fn commit_offset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut offset = store.commit_offset()?.unwrap();
+    assert_eq!(offset, 0);
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        let next = store.commit_offset()?.unwrap();
+        assert!(next > offset);
+        offset = next;
+        // reads do not move the offset
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        store.get("missing".to_owned())?;
+        assert_eq!(store.commit_offset()?, Some(offset));
+    }
+    assert_eq!(offset, log_len(&temp_dir));
+
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.remove("key0".to_owned())?;
+    assert!(store.commit_offset()?.unwrap() > offset);
+    let changes: Vec<_> = store
+        .stream_changes_since(offset)?
+        .map(|change| change.map(|change| change.cmd.name()))
+        .collect::<Result<_>>()?;
+    assert_eq!(changes, ["set", "rm"]);
+
+    // sled has no log to resume from
+    let sled = SledKvsEngine::open(temp_dir.path().join("db"))?;
+    sled.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(sled.commit_offset()?, None);
+    Ok(())
+}
+
+// An empty value is a value, it is returned as Some(""), survives compaction and reopening the
+// store, and is only gone once the key is removed
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "".to_owned())?;
+    store.set("key3".to_owned(), "".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.len()?, 2);
+    let mut buf = Vec::new();
+    assert!(store.get_into("key1".to_owned(), &mut buf)?);
+    assert!(buf.is_empty());
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    for engine in [
+        SharedKvsEngine::from(SledKvsEngine::open(temp_dir.path().join("db"))?),
+        SharedKvsEngine::from(TieredEngine::new(
+            KvStore::with_storage(StreamStorage::in_memory())?,
+            KvStore::with_storage(StreamStorage::in_memory())?,
+        )),
+    ] {
+        engine.set("key1".to_owned(), "".to_owned())?;
+        engine.sync()?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("".to_owned()));
+        engine.remove("key1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// hashes every key to 0, so every key collides with every other
+#[derive(Default)]
+struct CollidingHasher;
+
+impl Hasher for CollidingHasher {
+    fn finish(&self) -> u64 {
+        0
+    }
+
+    fn write(&mut self, _: &[u8]) {}
+}
+
+// With hashed keys, keys whose hashes collide should be told apart by the keys in their records,
+// through overwrites, removes, replay and compaction
+#[test]
+fn key_hash_collisions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || -> Result<KvStore> {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set_key_hasher(BuildHasherDefault::<CollidingHasher>::default())?;
+        // longer values are only held in the log
+        store.set_inline_threshold(4);
+        Ok(store)
+    };
+    let store = open()?;
+    let mut model = HashMap::new();
+    // keys that are prefixes of one another, and keys escaped in the log
+    for (i, key) in ["key", "key1", "key10", "k\"ey", "k\\ey\n"]
+        .iter()
+        .enumerate()
+    {
+        let value = format!("value{}", i);
+        store.set(key.to_string(), value.clone())?;
+        model.insert(key.to_string(), value);
+    }
+    assert_matches_model(&store, &model)?;
+    assert_eq!(store.get("key100".to_owned())?, None);
+
+    store.set("key1".to_owned(), "v".to_owned())?;
+    model.insert("key1".to_owned(), "v".to_owned());
+    store.remove("key".to_owned())?;
+    model.remove("key");
+    assert!(store
+        .remove("key".to_owned())
+        .unwrap_err()
+        .is::<ErrKeyNotFound>());
+    assert_matches_model(&store, &model)?;
+    let mut value = String::new();
+    let mut stream = store.get_stream("key10".to_owned())?.unwrap();
+    stream.reader.read_to_string(&mut value)?;
+    assert_eq!(value, "value2");
+
+    // the index is rebuilt from the log, then from the compacted log
+    drop(store);
+    let store = open()?;
+    assert_matches_model(&store, &model)?;
+    store.compact()?;
+    assert_matches_model(&store, &model)?;
+    drop(store);
+    assert_matches_model(&open()?, &model)?;
+
+    let store = open()?;
+    let err = store.scan_page(None, 10).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<KvsError>(),
+        Some(&KvsError::Unsupported {
+            operation: "scan of hashed keys".to_owned()
+        })
+    );
+    assert!(store.set_access_tracking(true).is_err());
+    Ok(())
+}
+
+// A store with hashed keys should hold the same pairs as one without, the log is the same either
+// way
+#[test]
+fn key_hashing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_key_hashing(true)?;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut model = HashMap::new();
+    for _ in 0..2000 {
+        let key = format!("{}{}", "long key ".repeat(10), rng.gen_range(0..200));
+        if rng.gen_bool(0.2) {
+            assert_eq!(
+                store.remove(key.clone()).is_ok(),
+                model.remove(&key).is_some()
+            );
+        } else {
+            let value = format!("value{}", rng.gen::<u32>());
+            store.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+    }
+    assert_matches_model(&store, &model)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_matches_model(&store, &model)?;
+    store.set_key_hashing(true)?;
+    assert_matches_model(&store, &model)?;
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use kvs::engines::kvs_engine::Result;
 use kvs::thread_pool::{naive::*, rayon::*, shared_queue::*, ThreadPool};
@@ -66,3 +68,36 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_bounded_queue() -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const CAPACITY: usize = 2;
+
+    // a single slow worker, so spawns outpace the pool
+    let mut pool = SharedQueueThreadPool::new_with_capacity(1, Some(CAPACITY))?;
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut max_depth = 0;
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        });
+        max_depth = max_depth.max(pool.queued());
+    }
+
+    wg.wait();
+    assert!(max_depth <= CAPACITY);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_zero_capacity() {
+    assert!(SharedQueueThreadPool::new_with_capacity(1, Some(0)).is_err());
+}